@@ -24,7 +24,12 @@ fn main() {
         if zlib_ng_compat {
             panic!("LIBSSH2_SYS_USE_PKG_CONFIG set, but cannot use zlib-ng-compat with system libssh2");
         }
-        if let Ok(lib) = pkg_config::find_library("libssh2") {
+        // Older versions lack functions such as libssh2_channel_signal_ex,
+        // and are passed over for the bundled copy.
+        if let Ok(lib) = pkg_config::Config::new()
+            .atleast_version("1.11.0")
+            .probe("libssh2")
+        {
             for path in &lib.include_paths {
                 println!("cargo:include={}", path.display());
             }
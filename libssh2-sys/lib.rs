@@ -50,9 +50,21 @@ pub const LIBSSH2_METHOD_LANG_CS: c_int = 8;
 pub const LIBSSH2_METHOD_LANG_SC: c_int = 9;
 pub const LIBSSH2_METHOD_SIGN_ALGO: c_int = 10;
 
+pub const LIBSSH2_CALLBACK_IGNORE: c_int = 0;
+pub const LIBSSH2_CALLBACK_DEBUG: c_int = 1;
+pub const LIBSSH2_CALLBACK_DISCONNECT: c_int = 2;
+pub const LIBSSH2_CALLBACK_MACERROR: c_int = 3;
+pub const LIBSSH2_CALLBACK_X11: c_int = 4;
+pub const LIBSSH2_CALLBACK_SEND: c_int = 5;
+pub const LIBSSH2_CALLBACK_RECV: c_int = 6;
+pub const LIBSSH2_CALLBACK_AUTHAGENT: c_int = 7;
+pub const LIBSSH2_CALLBACK_AUTHAGENT_IDENTITIES: c_int = 8;
+pub const LIBSSH2_CALLBACK_AUTHAGENT_SIGN: c_int = 9;
+
 pub const LIBSSH2_CHANNEL_PACKET_DEFAULT: c_uint = 32768;
 pub const LIBSSH2_CHANNEL_WINDOW_DEFAULT: c_uint = 2 * 1024 * 1024;
 
+pub const LIBSSH2_ERROR_SOCKET_NONE: c_int = -1;
 pub const LIBSSH2_ERROR_BANNER_RECV: c_int = -2;
 pub const LIBSSH2_ERROR_BANNER_SEND: c_int = -3;
 pub const LIBSSH2_ERROR_INVALID_MAC: c_int = -4;
@@ -141,6 +153,7 @@ pub const LIBSSH2_KNOWNHOST_CHECK_FAILURE: c_int = 3;
 pub const LIBSSH2_KNOWNHOST_TYPE_PLAIN: c_int = 1;
 pub const LIBSSH2_KNOWNHOST_TYPE_SHA1: c_int = 2;
 pub const LIBSSH2_KNOWNHOST_TYPE_CUSTOM: c_int = 3;
+pub const LIBSSH2_KNOWNHOST_TYPE_MASK: c_int = 0xffff;
 pub const LIBSSH2_KNOWNHOST_KEYENC_RAW: c_int = 1 << 16;
 pub const LIBSSH2_KNOWNHOST_KEYENC_BASE64: c_int = 2 << 16;
 pub const LIBSSH2_KNOWNHOST_KEY_RSA1: c_int = 1 << 18;
@@ -151,6 +164,7 @@ pub const LIBSSH2_KNOWNHOST_KEY_ECDSA_384: c_int = 5 << 18;
 pub const LIBSSH2_KNOWNHOST_KEY_ECDSA_521: c_int = 6 << 18;
 pub const LIBSSH2_KNOWNHOST_KEY_ED25519: c_int = 7 << 18;
 pub const LIBSSH2_KNOWNHOST_KEY_UNKNOWN: c_int = 15 << 18;
+pub const LIBSSH2_KNOWNHOST_KEY_MASK: c_int = 15 << 18;
 
 pub const LIBSSH2_FXF_READ: c_ulong = 0x00000001;
 pub const LIBSSH2_FXF_WRITE: c_ulong = 0x00000002;
@@ -292,6 +306,20 @@ pub type LIBSSH2_PASSWD_CHANGEREQ_FUNC = extern "C" fn(
     abstrakt: *mut *mut c_void,
 );
 
+pub type LIBSSH2_X11_OPEN_FUNC = extern "C" fn(
+    session: *mut LIBSSH2_SESSION,
+    channel: *mut LIBSSH2_CHANNEL,
+    shost: *const c_char,
+    sport: c_int,
+    abstrakt: *mut *mut c_void,
+);
+
+pub type LIBSSH2_AUTHAGENT_FUNC = extern "C" fn(
+    session: *mut LIBSSH2_SESSION,
+    channel: *mut LIBSSH2_CHANNEL,
+    abstrakt: *mut *mut c_void,
+);
+
 pub type LIBSSH2_USERAUTH_KBDINT_RESPONSE_FUNC = extern "C" fn(
     username: *const c_char,
     username_len: c_int,
@@ -303,6 +331,15 @@ pub type LIBSSH2_USERAUTH_KBDINT_RESPONSE_FUNC = extern "C" fn(
     abstrakt: *mut *mut c_void,
 );
 
+pub type LIBSSH2_USERAUTH_PUBLICKEY_SIGN_FUNC = extern "C" fn(
+    session: *mut LIBSSH2_SESSION,
+    sig: *mut *mut c_uchar,
+    sig_len: *mut size_t,
+    data: *const c_uchar,
+    data_len: size_t,
+    abstrakt: *mut *mut c_void,
+) -> c_int;
+
 #[repr(C)]
 pub struct LIBSSH2_USERAUTH_KBDINT_PROMPT {
     pub text: *mut c_uchar,
@@ -339,6 +376,11 @@ extern "C" {
         abstrakt: *mut c_void,
     ) -> *mut LIBSSH2_SESSION;
     pub fn libssh2_session_abstract(session: *mut LIBSSH2_SESSION) -> *mut *mut c_void;
+    pub fn libssh2_session_callback_set(
+        session: *mut LIBSSH2_SESSION,
+        cbtype: c_int,
+        callback: *mut c_void,
+    ) -> *mut c_void;
     pub fn libssh2_session_free(sess: *mut LIBSSH2_SESSION) -> c_int;
     pub fn libssh2_session_banner_get(sess: *mut LIBSSH2_SESSION) -> *const c_char;
     pub fn libssh2_session_banner_set(sess: *mut LIBSSH2_SESSION, banner: *const c_char) -> c_int;
@@ -420,6 +462,11 @@ extern "C" {
         msg: *const c_char,
         msg_len: c_uint,
     ) -> c_int;
+    pub fn libssh2_channel_signal_ex(
+        chan: *mut LIBSSH2_CHANNEL,
+        signame: *const c_char,
+        signame_len: size_t,
+    ) -> c_int;
     pub fn libssh2_channel_flush_ex(chan: *mut LIBSSH2_CHANNEL, streamid: c_int) -> c_int;
     pub fn libssh2_channel_write_ex(
         chan: *mut LIBSSH2_CHANNEL,
@@ -520,6 +567,13 @@ extern "C" {
         mode: c_int,
     ) -> c_int;
     pub fn libssh2_channel_request_auth_agent(channel: *mut LIBSSH2_CHANNEL) -> c_int;
+    pub fn libssh2_channel_x11_req_ex(
+        channel: *mut LIBSSH2_CHANNEL,
+        single_connection: c_int,
+        auth_proto: *const c_char,
+        auth_cookie: *const c_char,
+        screen_number: c_int,
+    ) -> c_int;
 
     // userauth
     pub fn libssh2_userauth_banner(sess: *mut LIBSSH2_SESSION, banner: *mut *mut c_char) -> c_int;
@@ -559,6 +613,14 @@ extern "C" {
         privatekeydata_len: size_t,
         passphrase: *const c_char,
     ) -> c_int;
+    pub fn libssh2_userauth_publickey(
+        sess: *mut LIBSSH2_SESSION,
+        username: *const c_char,
+        pubkeydata: *const c_uchar,
+        pubkeydata_len: size_t,
+        sign_callback: Option<LIBSSH2_USERAUTH_PUBLICKEY_SIGN_FUNC>,
+        abstrakt: *mut *mut c_void,
+    ) -> c_int;
     pub fn libssh2_userauth_password_ex(
         session: *mut LIBSSH2_SESSION,
         username: *const c_char,
@@ -1,5 +1,7 @@
 use parking_lot::{Mutex, MutexGuard};
+use sha2::{Digest, Sha256};
 use std::ffi::{CStr, CString};
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::ptr::null_mut;
 use std::slice;
@@ -7,7 +9,8 @@ use std::str;
 use std::sync::Arc;
 
 use util;
-use {raw, Error, ErrorCode, SessionInner};
+use wire::Reader;
+use {raw, AgentClient, Error, ErrorCode, SessionInner};
 
 /// A structure representing a connection to an SSH agent.
 ///
@@ -30,6 +33,31 @@ pub struct PublicKey {
     comment: String,
 }
 
+/// Selects which of an agent's identities may be used, much like OpenSSH's
+/// `IdentitiesOnly` and `IdentityFile` options.
+///
+/// An identity is selected if it matches any of the fingerprints, comments
+/// or public keys added to the filter. An empty filter selects every
+/// identity.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::path::Path;
+/// use ssh2::{IdentityFilter, Session};
+///
+/// # let sess: Session = panic!();
+/// let mut filter = IdentityFilter::new();
+/// filter.public_key_file(Path::new("/home/me/.ssh/id_deploy.pub")).unwrap();
+/// sess.userauth_agent_filtered("git", &filter).unwrap();
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct IdentityFilter {
+    fingerprints: Vec<String>,
+    comments: Vec<String>,
+    blobs: Vec<Vec<u8>>,
+}
+
 impl Agent {
     pub(crate) fn from_raw_opt(
         raw: *mut raw::LIBSSH2_AGENT,
@@ -109,6 +137,16 @@ impl Agent {
         Ok(None)
     }
 
+    /// Get the identities of this agent which are selected by `filter`.
+    ///
+    /// Like `identities`, this returns the identities fetched by the last
+    /// call to `list_identities`.
+    pub fn filtered_identities(&self, filter: &IdentityFilter) -> Result<Vec<PublicKey>, Error> {
+        let mut identities = self.identities()?;
+        identities.retain(|identity| filter.matches(identity));
+        Ok(identities)
+    }
+
     /// Attempt public key authentication with the help of ssh-agent.
     pub fn userauth(&self, username: &str, identity: &PublicKey) -> Result<(), Error> {
         let username = CString::new(username)?;
@@ -137,6 +175,18 @@ impl Agent {
         Ok(())
     }
 
+    /// Open a separate connection to the same agent which speaks the full
+    /// agent protocol.
+    ///
+    /// The agent at `identity_path` is used if one has been set, otherwise
+    /// the one named by `SSH_AUTH_SOCK`.
+    pub fn client(&self) -> Result<AgentClient, Error> {
+        match self.identity_path() {
+            Some(path) => AgentClient::connect(&path),
+            None => AgentClient::connect_env(),
+        }
+    }
+
     /// Get the custom agent socket path, if set.
     pub fn identity_path(&self) -> Option<PathBuf> {
         unsafe {
@@ -157,6 +207,10 @@ impl Drop for Agent {
 }
 
 impl PublicKey {
+    pub(crate) fn new(blob: Vec<u8>, comment: String) -> Self {
+        Self { blob, comment }
+    }
+
     unsafe fn from_raw(raw: *mut raw::libssh2_agent_publickey) -> Self {
         let blob = slice::from_raw_parts_mut((*raw).blob, (*raw).blob_len as usize);
         let comment = (*raw).comment;
@@ -180,4 +234,140 @@ impl PublicKey {
     pub fn comment(&self) -> &str {
         &self.comment
     }
+
+    /// Returns the SSH name of the key algorithm, such as `ssh-ed25519` or
+    /// `ssh-rsa-cert-v01@openssh.com`.
+    ///
+    /// Returns an empty string if the blob is malformed.
+    pub fn algorithm(&self) -> &str {
+        Reader::new(&self.blob).str().unwrap_or("")
+    }
+
+    /// Returns the size of the key in bits, or `None` if the key type is not
+    /// recognized.
+    pub fn bits(&self) -> Option<u32> {
+        let mut r = Reader::new(&self.blob);
+        let algorithm = r.str().ok()?;
+        if self.is_certificate() {
+            let _nonce = r.string().ok()?;
+        }
+        match algorithm.trim_end_matches("-cert-v01@openssh.com") {
+            "ssh-rsa" => {
+                let _e = r.string().ok()?;
+                Some(mpint_bits(r.string().ok()?))
+            }
+            "ssh-dss" => Some(mpint_bits(r.string().ok()?)),
+            "ecdsa-sha2-nistp256"
+            | "sk-ecdsa-sha2-nistp256@openssh.com"
+            | "sk-ecdsa-sha2-nistp256" => Some(256),
+            "ecdsa-sha2-nistp384" => Some(384),
+            "ecdsa-sha2-nistp521" => Some(521),
+            "ssh-ed25519" | "sk-ssh-ed25519@openssh.com" | "sk-ssh-ed25519" => Some(256),
+            _ => None,
+        }
+    }
+
+    /// Returns the SHA256 fingerprint of this key in the format used by
+    /// OpenSSH, such as `SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU`.
+    pub fn fingerprint(&self) -> String {
+        let digest = Sha256::digest(&self.blob);
+        format!("SHA256:{}", util::base64_encode(&digest, false))
+    }
+
+    /// Returns whether this is an OpenSSH certificate rather than a plain
+    /// public key.
+    pub fn is_certificate(&self) -> bool {
+        self.algorithm().ends_with("-cert-v01@openssh.com")
+    }
+}
+
+/// Returns the number of significant bits in an SSH `mpint`.
+fn mpint_bits(mpint: &[u8]) -> u32 {
+    let mut bytes = mpint.iter().skip_while(|&&b| b == 0);
+    match bytes.next() {
+        Some(&first) => (bytes.count() as u32) * 8 + (8 - first.leading_zeros()),
+        None => 0,
+    }
+}
+
+impl IdentityFilter {
+    /// Create an empty filter, which selects every identity.
+    pub fn new() -> IdentityFilter {
+        IdentityFilter::default()
+    }
+
+    /// Select the identity with this SHA256 fingerprint. The `SHA256:`
+    /// prefix is optional.
+    pub fn fingerprint(&mut self, fingerprint: &str) -> &mut IdentityFilter {
+        let fingerprint = fingerprint.trim_start_matches("SHA256:");
+        self.fingerprints
+            .push(fingerprint.trim_end_matches('=').to_string());
+        self
+    }
+
+    /// Select identities with exactly this comment.
+    pub fn comment(&mut self, comment: &str) -> &mut IdentityFilter {
+        self.comments.push(comment.to_string());
+        self
+    }
+
+    /// Select the identities whose public keys are listed in `path`, a file
+    /// in the format of `~/.ssh/id_*.pub` or `authorized_keys`.
+    ///
+    /// The file is read immediately, and must hold at least one key, so that
+    /// an empty file can't leave the filter selecting every identity.
+    pub fn public_key_file(&mut self, path: &Path) -> Result<&mut IdentityFilter, Error> {
+        let contents = fs::read_to_string(path).map_err(|_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_FILE),
+                "unable to read public key file",
+            )
+        })?;
+        let count = self.blobs.len();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // Look for the key type followed by the base64 blob, skipping over
+            // any options in front of them as found in authorized_keys files.
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let blob = fields.windows(2).find_map(|pair| {
+                let blob = util::base64_decode(pair[1])?;
+                let matches = Reader::new(&blob).str().ok()? == pair[0];
+                if matches {
+                    Some(blob)
+                } else {
+                    None
+                }
+            });
+            match blob {
+                Some(blob) => self.blobs.push(blob),
+                None => {
+                    return Err(Error::new(
+                        ErrorCode::Session(raw::LIBSSH2_ERROR_FILE),
+                        "invalid public key file",
+                    ))
+                }
+            }
+        }
+        if self.blobs.len() == count {
+            return Err(Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_FILE),
+                "no public keys in public key file",
+            ));
+        }
+        Ok(self)
+    }
+
+    /// Returns whether `identity` is selected by this filter.
+    pub fn matches(&self, identity: &PublicKey) -> bool {
+        if self.fingerprints.is_empty() && self.comments.is_empty() && self.blobs.is_empty() {
+            return true;
+        }
+        let fingerprint = identity.fingerprint();
+        self.fingerprints.iter().any(|f| fingerprint[7..] == **f)
+            || self.comments.contains(&identity.comment)
+            || self.blobs.contains(&identity.blob)
+    }
 }
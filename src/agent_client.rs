@@ -0,0 +1,356 @@
+//! A client for the ssh-agent protocol.
+//!
+//! libssh2's agent support only covers listing identities and using them for
+//! authentication. `AgentClient` speaks the protocol directly so that the rest
+//! of it (signing arbitrary data, adding and removing keys, locking) is
+//! available as well.
+
+use std::env;
+#[cfg(windows)]
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use wire::{Reader, Writer};
+#[cfg(feature = "keys")]
+use PrivateKey;
+use {raw, Error, ErrorCode, PublicKey, PublicKeySigner, Session};
+
+pub(crate) const SSH_AGENT_FAILURE: u8 = 5;
+pub(crate) const SSH_AGENT_SUCCESS: u8 = 6;
+pub(crate) const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+pub(crate) const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+pub(crate) const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+pub(crate) const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+#[cfg(feature = "keys")]
+pub(crate) const SSH_AGENTC_ADD_IDENTITY: u8 = 17;
+pub(crate) const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
+pub(crate) const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
+pub(crate) const SSH_AGENTC_LOCK: u8 = 22;
+pub(crate) const SSH_AGENTC_UNLOCK: u8 = 23;
+#[cfg(feature = "keys")]
+pub(crate) const SSH_AGENTC_ADD_ID_CONSTRAINED: u8 = 25;
+
+#[cfg(feature = "keys")]
+pub(crate) const SSH_AGENT_CONSTRAIN_LIFETIME: u8 = 1;
+#[cfg(feature = "keys")]
+pub(crate) const SSH_AGENT_CONSTRAIN_CONFIRM: u8 = 2;
+
+/// The largest message either side of the protocol will accept.
+pub(crate) const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+#[cfg(windows)]
+const DEFAULT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+
+bitflags! {
+    /// Flags which can be passed to `AgentClient::sign`.
+    #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+    pub struct SignFlags: u32 {
+        /// Sign with an RSA key using SHA-256 (`rsa-sha2-256`) rather than
+        /// SHA-1.
+        const RSA_SHA2_256 = 2;
+        /// Sign with an RSA key using SHA-512 (`rsa-sha2-512`) rather than
+        /// SHA-1.
+        const RSA_SHA2_512 = 4;
+    }
+}
+
+/// Restrictions which an agent applies to a key added with
+/// `AgentClient::add_identity`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeyConstraints {
+    /// Number of seconds after which the agent forgets the key.
+    pub lifetime: Option<u32>,
+    /// If true, the agent asks the user for confirmation each time the key
+    /// is used.
+    pub confirm: bool,
+}
+
+/// A signature produced by an SSH agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    algorithm: String,
+    blob: Vec<u8>,
+}
+
+impl Signature {
+    /// Returns the signature algorithm, such as `ssh-ed25519` or
+    /// `rsa-sha2-256`.
+    pub fn algorithm(&self) -> &str {
+        &self.algorithm
+    }
+
+    /// Returns the raw signature blob, without the algorithm name.
+    pub fn blob(&self) -> &[u8] {
+        &self.blob
+    }
+
+    /// Returns the signature in the RFC 4253 encoding: the algorithm name
+    /// followed by the blob, each as a length-prefixed string.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.string(self.algorithm.as_bytes());
+        w.string(&self.blob);
+        w.into_vec()
+    }
+}
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+/// A connection to an SSH agent which speaks the agent protocol directly.
+///
+/// This complements `Agent`, whose operations are limited to what libssh2
+/// offers. The keys returned by `identities` are the same `PublicKey` values
+/// as returned by `Agent::identities`, so the two can be used together.
+///
+/// # Example
+///
+/// ```no_run
+/// use ssh2::{AgentClient, SignFlags};
+///
+/// let mut client = AgentClient::connect_env().unwrap();
+/// let identities = client.identities().unwrap();
+/// let signature = client.sign(&identities[0], b"challenge", SignFlags::empty()).unwrap();
+/// println!("signed with {}", signature.algorithm());
+/// ```
+pub struct AgentClient {
+    stream: Box<dyn Stream>,
+}
+
+impl AgentClient {
+    /// Speak the agent protocol over an already connected stream.
+    pub fn new<S: Read + Write + Send + 'static>(stream: S) -> AgentClient {
+        AgentClient {
+            stream: Box::new(stream),
+        }
+    }
+
+    /// Connect to the agent listening at `path`.
+    ///
+    /// On Unix this is the path of a unix domain socket, and on Windows the
+    /// name of a named pipe such as `\\.\pipe\openssh-ssh-agent`.
+    pub fn connect(path: &Path) -> Result<AgentClient, Error> {
+        #[cfg(unix)]
+        let stream = UnixStream::connect(path);
+        #[cfg(windows)]
+        let stream = OpenOptions::new().read(true).write(true).open(path);
+        let stream = stream.map_err(|_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_AGENT_PROTOCOL),
+                "unable to connect to the ssh agent",
+            )
+        })?;
+        Ok(AgentClient::new(stream))
+    }
+
+    /// Connect to the agent named by the `SSH_AUTH_SOCK` environment
+    /// variable.
+    ///
+    /// On Windows the OpenSSH agent's named pipe is used if the variable is
+    /// not set.
+    pub fn connect_env() -> Result<AgentClient, Error> {
+        match env::var_os("SSH_AUTH_SOCK") {
+            Some(path) => AgentClient::connect(Path::new(&path)),
+            #[cfg(windows)]
+            None => AgentClient::connect(Path::new(DEFAULT_PIPE)),
+            #[cfg(unix)]
+            None => Err(Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_AGENT_PROTOCOL),
+                "SSH_AUTH_SOCK is not set",
+            )),
+        }
+    }
+
+    /// Ask the agent for the list of keys it holds.
+    pub fn identities(&mut self) -> Result<Vec<PublicKey>, Error> {
+        let response = self.request(&[SSH_AGENTC_REQUEST_IDENTITIES])?;
+        let mut r = Reader::new(&response);
+        if r.u8()? != SSH_AGENT_IDENTITIES_ANSWER {
+            return Err(unexpected_response());
+        }
+        let count = r.u32()?;
+        let mut res = Vec::new();
+        for _ in 0..count {
+            let blob = r.string()?;
+            let comment = String::from_utf8_lossy(r.string()?).into_owned();
+            res.push(PublicKey::new(blob.to_vec(), comment));
+        }
+        Ok(res)
+    }
+
+    /// Ask the agent to sign `data` with the private half of `identity`.
+    ///
+    /// `flags` selects the hash used for RSA keys and is ignored by the agent
+    /// for other key types.
+    pub fn sign(
+        &mut self,
+        identity: &PublicKey,
+        data: &[u8],
+        flags: SignFlags,
+    ) -> Result<Signature, Error> {
+        let mut w = Writer::new();
+        w.u8(SSH_AGENTC_SIGN_REQUEST);
+        w.string(identity.blob());
+        w.string(data);
+        w.u32(flags.bits());
+        let response = self.request(&w.into_vec())?;
+
+        let mut r = Reader::new(&response);
+        if r.u8()? != SSH_AGENT_SIGN_RESPONSE {
+            return Err(unexpected_response());
+        }
+        let mut sig = Reader::new(r.string()?);
+        let algorithm = sig.str()?.to_string();
+        let blob = sig.string()?.to_vec();
+        Ok(Signature { algorithm, blob })
+    }
+
+    /// Add a private key to the agent, subject to `constraints`.
+    ///
+    /// The key's comment is stored by the agent and reported back by
+    /// `identities`. Only available with the `keys` feature.
+    #[cfg(feature = "keys")]
+    pub fn add_identity(
+        &mut self,
+        key: &PrivateKey,
+        constraints: &KeyConstraints,
+    ) -> Result<(), Error> {
+        let constrained = constraints.lifetime.is_some() || constraints.confirm;
+        let mut w = Writer::new();
+        w.u8(if constrained {
+            SSH_AGENTC_ADD_ID_CONSTRAINED
+        } else {
+            SSH_AGENTC_ADD_IDENTITY
+        });
+        w.raw(&key.agent_key_data()?);
+        w.string(key.comment().as_bytes());
+        if let Some(lifetime) = constraints.lifetime {
+            w.u8(SSH_AGENT_CONSTRAIN_LIFETIME);
+            w.u32(lifetime);
+        }
+        if constraints.confirm {
+            w.u8(SSH_AGENT_CONSTRAIN_CONFIRM);
+        }
+        self.simple_request(&w.into_vec())
+    }
+
+    /// Remove a single key from the agent.
+    pub fn remove_identity(&mut self, identity: &PublicKey) -> Result<(), Error> {
+        let mut w = Writer::new();
+        w.u8(SSH_AGENTC_REMOVE_IDENTITY);
+        w.string(identity.blob());
+        self.simple_request(&w.into_vec())
+    }
+
+    /// Remove all keys from the agent.
+    pub fn remove_all_identities(&mut self) -> Result<(), Error> {
+        self.simple_request(&[SSH_AGENTC_REMOVE_ALL_IDENTITIES])
+    }
+
+    /// Lock the agent with a passphrase.
+    ///
+    /// A locked agent reports no identities and refuses to sign until it is
+    /// unlocked with the same passphrase.
+    pub fn lock(&mut self, passphrase: &str) -> Result<(), Error> {
+        self.simple_request(&passphrase_request(SSH_AGENTC_LOCK, passphrase))
+    }
+
+    /// Unlock an agent previously locked with `lock`.
+    pub fn unlock(&mut self, passphrase: &str) -> Result<(), Error> {
+        self.simple_request(&passphrase_request(SSH_AGENTC_UNLOCK, passphrase))
+    }
+
+    /// Attempt public key authentication of `sess`, with the agent signing
+    /// the request using `identity`.
+    ///
+    /// This works with any agent this client can reach, independently of the
+    /// agents libssh2 knows how to talk to.
+    pub fn userauth(
+        &mut self,
+        sess: &Session,
+        username: &str,
+        identity: &PublicKey,
+    ) -> Result<(), Error> {
+        let mut signer = ClientSigner {
+            client: self,
+            identity,
+        };
+        sess.userauth_pubkey_sign(username, identity.blob(), &mut signer)
+    }
+
+    fn request(&mut self, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        // Requests may carry passphrases and private keys, so the framed copy
+        // is wiped too.
+        let mut w = Writer::with_capacity(4 + msg.len());
+        w.string(msg);
+        let framed = Zeroizing::new(w.into_vec());
+        self.stream.write_all(&framed).map_err(io_error)?;
+        self.stream.flush().map_err(io_error)?;
+
+        let mut len = [0; 4];
+        self.stream.read_exact(&mut len).map_err(io_error)?;
+        let len = Reader::new(&len).u32()? as usize;
+        if len == 0 || len > MAX_MESSAGE_LEN {
+            return Err(unexpected_response());
+        }
+        let mut response = vec![0; len];
+        self.stream.read_exact(&mut response).map_err(io_error)?;
+        if response[0] == SSH_AGENT_FAILURE {
+            return Err(Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_AGENT_PROTOCOL),
+                "the ssh agent refused the request",
+            ));
+        }
+        Ok(response)
+    }
+
+    fn simple_request(&mut self, msg: &[u8]) -> Result<(), Error> {
+        match self.request(msg)?[0] {
+            SSH_AGENT_SUCCESS => Ok(()),
+            _ => Err(unexpected_response()),
+        }
+    }
+}
+
+/// Builds a lock or unlock request, which is wiped when dropped.
+fn passphrase_request(kind: u8, passphrase: &str) -> Zeroizing<Vec<u8>> {
+    let mut w = Writer::with_capacity(1 + 4 + passphrase.len());
+    w.u8(kind);
+    w.string(passphrase.as_bytes());
+    Zeroizing::new(w.into_vec())
+}
+
+struct ClientSigner<'a> {
+    client: &'a mut AgentClient,
+    identity: &'a PublicKey,
+}
+
+impl<'a> PublicKeySigner for ClientSigner<'a> {
+    fn sign(&mut self, algorithm: &str, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let flags = match algorithm {
+            "rsa-sha2-256" => SignFlags::RSA_SHA2_256,
+            "rsa-sha2-512" => SignFlags::RSA_SHA2_512,
+            _ => SignFlags::empty(),
+        };
+        let sig = self.client.sign(self.identity, data, flags)?;
+        Ok(sig.blob)
+    }
+}
+
+fn io_error(_: ::std::io::Error) -> Error {
+    Error::new(
+        ErrorCode::Session(raw::LIBSSH2_ERROR_AGENT_PROTOCOL),
+        "unable to communicate with the ssh agent",
+    )
+}
+
+fn unexpected_response() -> Error {
+    Error::new(
+        ErrorCode::Session(raw::LIBSSH2_ERROR_AGENT_PROTOCOL),
+        "unexpected response from the ssh agent",
+    )
+}
@@ -0,0 +1,488 @@
+//! An ssh-agent which runs inside the current process.
+
+#[cfg(unix)]
+use libc;
+use parking_lot::Mutex;
+#[cfg(unix)]
+use std::cmp;
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+#[cfg(unix)]
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+use agent_client::{SignFlags, MAX_MESSAGE_LEN};
+use agent_client::{SSH_AGENTC_ADD_IDENTITY, SSH_AGENTC_ADD_ID_CONSTRAINED, SSH_AGENTC_LOCK};
+use agent_client::{SSH_AGENTC_REMOVE_ALL_IDENTITIES, SSH_AGENTC_REMOVE_IDENTITY};
+use agent_client::{SSH_AGENTC_REQUEST_IDENTITIES, SSH_AGENTC_SIGN_REQUEST, SSH_AGENTC_UNLOCK};
+use agent_client::{SSH_AGENT_CONSTRAIN_CONFIRM, SSH_AGENT_CONSTRAIN_LIFETIME, SSH_AGENT_FAILURE};
+use agent_client::{SSH_AGENT_IDENTITIES_ANSWER, SSH_AGENT_SIGN_RESPONSE, SSH_AGENT_SUCCESS};
+use pump;
+use wire::{Reader, Writer};
+use {raw, Channel, Error, ErrorCode, PrivateKey, PublicKey, Secret, Session};
+
+/// An SSH agent which holds keys in memory and serves them over the
+/// ssh-agent protocol.
+///
+/// A server created with `bind` listens on a unix domain socket, so it can be
+/// used by the crate's own `Agent` (through `Agent::set_identity_path`), by
+/// `AgentClient`, or by external tools such as `ssh` and `ssh-add` through
+/// `SSH_AUTH_SOCK`. Each connection is served on its own thread. Dropping the
+/// server stops listening and removes the socket.
+///
+/// Keys are added with `add_key` or by clients through the protocol. Clients
+/// may also remove keys and lock the agent, just like with `ssh-agent`. Keys
+/// added with the confirm constraint are held but refused for signing, as
+/// there is nobody to ask for confirmation. Signing with RSA keys needs the
+/// `rsa-signing` feature, as described at `PrivateKey::sign`.
+///
+/// The keys can also be served to remote hosts, through the agent channels
+/// they open after `Channel::request_auth_agent_forwarding`; see `forward`.
+/// `serve` can be used with any other bidirectional stream.
+///
+/// Only available with the `keys` feature.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::path::Path;
+/// use ssh2::{AgentServer, KeyType, PrivateKey, Session};
+///
+/// let server = AgentServer::bind(Path::new("/tmp/test-agent.sock")).unwrap();
+/// server.add_key(PrivateKey::generate(KeyType::Ed25519, "ci").unwrap());
+///
+/// let sess = Session::new().unwrap();
+/// let mut agent = sess.agent().unwrap();
+/// agent.set_identity_path(server.path().unwrap()).unwrap();
+/// agent.connect().unwrap();
+/// ```
+pub struct AgentServer {
+    state: Arc<Mutex<AgentState>>,
+    #[cfg(unix)]
+    listening: Option<Listening>,
+}
+
+#[derive(Default)]
+struct AgentState {
+    keys: Vec<AgentKey>,
+    lock: Option<Secret>,
+}
+
+struct AgentKey {
+    key: PrivateKey,
+    blob: Vec<u8>,
+    expires: Option<Instant>,
+    confirm: bool,
+}
+
+/// How long the accept loop waits for a connection before checking whether
+/// the server was dropped.
+#[cfg(unix)]
+const ACCEPT_WAIT: Duration = Duration::from_millis(100);
+
+/// The longest the accept loop pauses after accepting failed.
+#[cfg(unix)]
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+#[cfg(unix)]
+struct Listening {
+    path: PathBuf,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AgentServer {
+    /// Create an agent which isn't listening anywhere, to be used with
+    /// `serve`.
+    pub fn new() -> AgentServer {
+        AgentServer {
+            state: Arc::new(Mutex::new(AgentState::default())),
+            #[cfg(unix)]
+            listening: None,
+        }
+    }
+
+    /// Create an agent listening on a unix domain socket at `path`.
+    ///
+    /// The socket must not already exist.
+    #[cfg(unix)]
+    pub fn bind(path: &Path) -> Result<AgentServer, Error> {
+        let listener = UnixListener::bind(path).map_err(|_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_AGENT_PROTOCOL),
+                "unable to bind the agent socket",
+            )
+        })?;
+        listener.set_nonblocking(true).map_err(|_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_AGENT_PROTOCOL),
+                "unable to set up the agent socket",
+            )
+        })?;
+        let mut server = AgentServer::new();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let state = Arc::clone(&server.state);
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || accept_loop(&listener, &state, &shutdown))
+        };
+        server.listening = Some(Listening {
+            path: path.to_path_buf(),
+            shutdown,
+            thread: Some(thread),
+        });
+        Ok(server)
+    }
+
+    /// Returns the path of the socket this agent listens on, if it was
+    /// created with `bind`.
+    #[cfg(unix)]
+    pub fn path(&self) -> Option<&Path> {
+        self.listening.as_ref().map(|l| l.path.as_path())
+    }
+
+    /// Add a key to the agent, replacing any existing copy of it.
+    pub fn add_key(&self, key: PrivateKey) {
+        self.state.lock().add(key, None, false);
+    }
+
+    /// Remove the key with the given public key blob, returning whether it
+    /// was held by the agent.
+    pub fn remove_key(&self, blob: &[u8]) -> bool {
+        let mut state = self.state.lock();
+        let before = state.keys.len();
+        state.keys.retain(|k| k.blob != blob);
+        state.keys.len() != before
+    }
+
+    /// Returns the public halves of the keys currently held by the agent,
+    /// regardless of whether it is locked.
+    pub fn identities(&self) -> Vec<PublicKey> {
+        let mut state = self.state.lock();
+        state.expire();
+        state.identities()
+    }
+
+    /// Speak the agent protocol over `stream` until the client closes it.
+    ///
+    /// Reads which fail with `WouldBlock` or `TimedOut` are retried, so a
+    /// non-blocking `Channel` or a stream with a read timeout can be used.
+    pub fn serve<S: Read + Write>(&self, stream: S) -> Result<(), Error> {
+        serve(&self.state, stream)
+    }
+
+    /// Serve an agent connection forwarded over SSH, as returned by
+    /// `Session::accept_auth_agent`, on a thread of its own until the remote
+    /// client closes it.
+    ///
+    /// `sess` must be in non-blocking mode, so that waiting for requests
+    /// doesn't hold up the rest of the session.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ssh2::{AgentServer, KeyType, PrivateKey, Session};
+    ///
+    /// # let sess: Session = panic!();
+    /// let server = AgentServer::new();
+    /// server.add_key(PrivateKey::generate(KeyType::Ed25519, "ci").unwrap());
+    ///
+    /// let mut channel = sess.channel_session().unwrap();
+    /// channel.request_auth_agent_forwarding().unwrap();
+    /// channel.exec("ssh-add -l").unwrap();
+    ///
+    /// sess.set_blocking(false);
+    /// loop {
+    ///     // Reading from `channel` lets agent channels arrive.
+    ///     while let Some(agent) = sess.accept_auth_agent() {
+    ///         server.forward(&sess, agent).unwrap();
+    ///     }
+    ///     # break
+    /// }
+    /// ```
+    pub fn forward(&self, sess: &Session, channel: Channel) -> Result<(), Error> {
+        pump::check_nonblocking(sess)?;
+        let state = Arc::clone(&self.state);
+        let _ = thread::spawn(move || serve(&state, channel));
+        Ok(())
+    }
+}
+
+impl Default for AgentServer {
+    fn default() -> AgentServer {
+        AgentServer::new()
+    }
+}
+
+#[cfg(unix)]
+impl Drop for AgentServer {
+    fn drop(&mut self) {
+        if let Some(mut listening) = self.listening.take() {
+            // The accept loop checks the flag at least every ACCEPT_WAIT,
+            // or MAX_BACKOFF after accepting failed.
+            listening.shutdown.store(true, Ordering::SeqCst);
+            if let Some(thread) = listening.thread.take() {
+                let _ = thread.join();
+            }
+            let _ = fs::remove_file(&listening.path);
+        }
+    }
+}
+
+/// Accepts connections to the agent socket, serving each on its own thread,
+/// until `shutdown` is set.
+#[cfg(unix)]
+fn accept_loop(listener: &UnixListener, state: &Arc<Mutex<AgentState>>, shutdown: &AtomicBool) {
+    let mut backoff = ACCEPT_WAIT;
+    while !shutdown.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                backoff = ACCEPT_WAIT;
+                // Some systems pass the listener's non-blocking mode on.
+                if stream.set_nonblocking(false).is_err() {
+                    continue;
+                }
+                let state = Arc::clone(state);
+                let _ = thread::spawn(move || serve(&state, stream));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => wait_accept(listener),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            // Errors such as running out of descriptors may clear up, so
+            // rather than spinning on them, wait longer each time.
+            Err(_) => {
+                thread::sleep(backoff);
+                backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Waits a little for a connection to `listener`.
+#[cfg(unix)]
+fn wait_accept(listener: &UnixListener) {
+    let mut fd = libc::pollfd {
+        fd: listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let _ = unsafe { libc::poll(&mut fd, 1, ACCEPT_WAIT.as_millis() as libc::c_int) };
+}
+
+impl AgentState {
+    fn add(&mut self, key: PrivateKey, lifetime: Option<u32>, confirm: bool) {
+        let blob = key.public_key_blob();
+        self.keys.retain(|k| k.blob != blob);
+        self.keys.push(AgentKey {
+            key,
+            blob,
+            expires: lifetime.map(|secs| Instant::now() + Duration::from_secs(secs as u64)),
+            confirm,
+        });
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.keys.retain(|k| match k.expires {
+            Some(expires) => expires > now,
+            None => true,
+        });
+    }
+
+    fn identities(&self) -> Vec<PublicKey> {
+        self.keys
+            .iter()
+            .map(|k| PublicKey::new(k.blob.clone(), k.key.comment().to_string()))
+            .collect()
+    }
+
+    fn handle(&mut self, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        self.expire();
+        let mut r = Reader::new(msg);
+        let mut w = Writer::new();
+        match r.u8()? {
+            SSH_AGENTC_REQUEST_IDENTITIES => {
+                let identities = match self.lock {
+                    Some(_) => Vec::new(),
+                    None => self.identities(),
+                };
+                w.u8(SSH_AGENT_IDENTITIES_ANSWER);
+                w.u32(identities.len() as u32);
+                for identity in identities {
+                    w.string(identity.blob());
+                    w.string(identity.comment().as_bytes());
+                }
+            }
+            SSH_AGENTC_SIGN_REQUEST => {
+                let blob = r.string()?;
+                let data = r.string()?;
+                let flags = SignFlags::from_bits_truncate(r.u32()?);
+                if self.lock.is_some() {
+                    return Err(refused());
+                }
+                let key = match self.keys.iter().find(|k| k.blob == blob) {
+                    Some(key) if !key.confirm => &key.key,
+                    _ => return Err(refused()),
+                };
+                let algorithm = match &*key.algorithm() {
+                    "ssh-rsa" if flags.contains(SignFlags::RSA_SHA2_512) => {
+                        "rsa-sha2-512".to_string()
+                    }
+                    "ssh-rsa" if flags.contains(SignFlags::RSA_SHA2_256) => {
+                        "rsa-sha2-256".to_string()
+                    }
+                    other => other.to_string(),
+                };
+                let mut sig = Writer::new();
+                sig.string(algorithm.as_bytes());
+                sig.string(&key.sign(&algorithm, data)?);
+                w.u8(SSH_AGENT_SIGN_RESPONSE);
+                w.string(&sig.into_vec());
+            }
+            msg @ SSH_AGENTC_ADD_IDENTITY | msg @ SSH_AGENTC_ADD_ID_CONSTRAINED => {
+                if self.lock.is_some() {
+                    return Err(refused());
+                }
+                let mut rest = r.rest();
+                let mut key = PrivateKey::from_agent_key_data(&mut rest)?;
+                let mut r = Reader::new(rest);
+                key.set_comment(&String::from_utf8_lossy(r.string()?));
+                let mut lifetime = None;
+                let mut confirm = false;
+                while msg == SSH_AGENTC_ADD_ID_CONSTRAINED && !r.is_empty() {
+                    match r.u8()? {
+                        SSH_AGENT_CONSTRAIN_LIFETIME => lifetime = Some(r.u32()?),
+                        SSH_AGENT_CONSTRAIN_CONFIRM => confirm = true,
+                        _ => return Err(refused()),
+                    }
+                }
+                self.add(key, lifetime, confirm);
+                w.u8(SSH_AGENT_SUCCESS);
+            }
+            SSH_AGENTC_REMOVE_IDENTITY => {
+                let blob = r.string()?;
+                if self.lock.is_some() || !self.keys.iter().any(|k| k.blob == blob) {
+                    return Err(refused());
+                }
+                self.keys.retain(|k| k.blob != blob);
+                w.u8(SSH_AGENT_SUCCESS);
+            }
+            SSH_AGENTC_REMOVE_ALL_IDENTITIES => {
+                if self.lock.is_some() {
+                    return Err(refused());
+                }
+                self.keys.clear();
+                w.u8(SSH_AGENT_SUCCESS);
+            }
+            SSH_AGENTC_LOCK => {
+                let passphrase = r.str()?;
+                if self.lock.is_some() {
+                    return Err(refused());
+                }
+                self.lock = Some(Secret::from(passphrase));
+                w.u8(SSH_AGENT_SUCCESS);
+            }
+            SSH_AGENTC_UNLOCK => {
+                let passphrase = r.str()?;
+                if self.lock.as_deref() != Some(passphrase) {
+                    return Err(refused());
+                }
+                self.lock = None;
+                w.u8(SSH_AGENT_SUCCESS);
+            }
+            _ => return Err(refused()),
+        }
+        Ok(w.into_vec())
+    }
+}
+
+fn serve<S: Read + Write>(state: &Mutex<AgentState>, mut stream: S) -> Result<(), Error> {
+    loop {
+        let mut len = [0; 4];
+        if !read_full(&mut stream, &mut len, true)? {
+            return Ok(());
+        }
+        let len = Reader::new(&len).u32()? as usize;
+        if len == 0 || len > MAX_MESSAGE_LEN {
+            return Err(Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_AGENT_PROTOCOL),
+                "invalid agent message length",
+            ));
+        }
+        // Messages may carry passphrases and private keys.
+        let mut msg = Zeroizing::new(vec![0; len]);
+        let _ = read_full(&mut stream, &mut msg, false)?;
+
+        let response = state
+            .lock()
+            .handle(&msg)
+            .unwrap_or_else(|_| vec![SSH_AGENT_FAILURE]);
+        let mut w = Writer::new();
+        w.string(&response);
+        write_full(&mut stream, &w.into_vec())?;
+    }
+}
+
+fn retry(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+    )
+}
+
+/// Fills `buf`, returning `false` if the stream ended cleanly before any data
+/// was read and `eof_ok` is set.
+fn read_full<S: Read>(stream: &mut S, buf: &mut [u8], eof_ok: bool) -> Result<bool, Error> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match stream.read(&mut buf[pos..]) {
+            Ok(0) if pos == 0 && eof_ok => return Ok(false),
+            Ok(0) => return Err(Error::eof()),
+            Ok(n) => pos += n,
+            Err(ref e) if retry(e) => backoff(),
+            Err(_) => return Err(io_failed()),
+        }
+    }
+    Ok(true)
+}
+
+fn write_full<S: Write>(stream: &mut S, mut buf: &[u8]) -> Result<(), Error> {
+    while !buf.is_empty() {
+        match stream.write(buf) {
+            Ok(0) => return Err(Error::eof()),
+            Ok(n) => buf = &buf[n..],
+            Err(ref e) if retry(e) => backoff(),
+            Err(_) => return Err(io_failed()),
+        }
+    }
+    stream.flush().map_err(|_| io_failed())
+}
+
+fn backoff() {
+    ::std::thread::sleep(Duration::from_millis(10));
+}
+
+fn io_failed() -> Error {
+    Error::new(
+        ErrorCode::Session(raw::LIBSSH2_ERROR_AGENT_PROTOCOL),
+        "unable to communicate with the agent client",
+    )
+}
+
+fn refused() -> Error {
+    Error::new(
+        ErrorCode::Session(raw::LIBSSH2_ERROR_AGENT_PROTOCOL),
+        "agent request refused",
+    )
+}
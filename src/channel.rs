@@ -2,18 +2,23 @@ use libc::{c_char, c_int, c_uchar, c_uint, c_ulong, c_void, size_t};
 use parking_lot::{Mutex, MutexGuard};
 use std::cmp;
 use std::ffi::CString;
+use std::fmt;
 use std::ptr::{null, null_mut};
 use std::io;
 use std::io::prelude::*;
 use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-use {raw, Error, ExtendedData, PtyModes, SessionInner};
+use pump::{Plain, Pump};
+use {raw, Error, ErrorCode, ExtendedData, PtyModes, Session, SessionInner};
 
 struct ChannelInner {
     unsafe_raw: *mut raw::LIBSSH2_CHANNEL,
     sess: Arc<Mutex<SessionInner>>,
     read_limit: Mutex<Option<u64>>,
+    // Set once `wait_close` has seen the remote end close the channel.
+    closed: AtomicBool,
 }
 
 // ChannelInner is both Send and Sync; the compiler can't see it because it
@@ -60,6 +65,7 @@ impl Channel {
                     unsafe_raw: raw,
                     sess: Arc::clone(sess),
                     read_limit: Mutex::new(None),
+                    closed: AtomicBool::new(false),
                 }),
             })
         }
@@ -112,6 +118,49 @@ pub struct ExitSignal {
     pub lang_tag: Option<String>,
 }
 
+/// How the process at the other end of a channel exited, as returned by
+/// `Channel::process_status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The process exited with this status code.
+    Code(i32),
+    /// The process was killed by a signal.
+    Signal {
+        /// The signal which killed the process.
+        signal: Signal,
+        /// Error message provided by the remote server (if any)
+        message: Option<String>,
+        /// Language tag provided by the remote server (if any)
+        lang: Option<String>,
+    },
+    /// The channel hasn't been closed yet, so the process may not have
+    /// exited.
+    Unknown,
+}
+
+/// The signals named in RFC 4254, which can be reported in an `exit-signal`
+/// message and sent with a `signal` request.
+#[allow(missing_docs)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Signal {
+    ABRT,
+    ALRM,
+    FPE,
+    HUP,
+    ILL,
+    INT,
+    KILL,
+    PIPE,
+    QUIT,
+    SEGV,
+    TERM,
+    USR1,
+    USR2,
+    /// A signal not named in RFC 4254, such as `WINCH`, or a
+    /// `name@domain` extension.
+    Other(String),
+}
+
 /// Description of the read window as returned by `Channel::read_window`
 #[derive(Copy, Clone)]
 pub struct ReadWindow {
@@ -233,13 +282,54 @@ impl Channel {
     /// Note that some hosts are configured to disallow agent forwarding,
     /// and that even if enabled, there is a possibility that starting
     /// the agent on the remote system can fail.
+    ///
+    /// The server opens a channel for each connection to the forwarded
+    /// agent, which can be picked up with `Session::accept_auth_agent` and
+    /// served with `AgentServer::forward`.
     pub fn request_auth_agent_forwarding(&mut self) -> Result<(), Error> {
-        let locked = self.lock();
+        let mut locked = self.lock();
+        locked.sess.enable_auth_agent();
         locked
             .sess
             .rc(unsafe { raw::libssh2_channel_request_auth_agent(locked.raw) })
     }
 
+    /// Requests X11 forwarding on this channel, before starting a command or
+    /// shell on it.
+    ///
+    /// The server sets up a display for programs on the remote side, and
+    /// opens an X11 channel for each connection to it, which can be picked up
+    /// with `Session::accept_x11`. If `single_connection` is set, only the
+    /// first connection is forwarded.
+    ///
+    /// `auth_protocol` defaults to `MIT-MAGIC-COOKIE-1`, and `auth_cookie`,
+    /// as hex digits, to one libssh2 makes up. The cookie the server gets
+    /// should be a fake one, such as from `X11Cookie::generate`; see
+    /// `X11Forwarding`, which swaps in the real cookie on the way to the
+    /// local display.
+    pub fn request_x11(
+        &mut self,
+        single_connection: bool,
+        auth_protocol: Option<&str>,
+        auth_cookie: Option<&str>,
+        screen_number: i32,
+    ) -> Result<(), Error> {
+        let auth_protocol = auth_protocol.map(CString::new).transpose()?;
+        let auth_cookie = auth_cookie.map(CString::new).transpose()?;
+        let mut locked = self.lock();
+        locked.sess.enable_x11();
+        unsafe {
+            let rc = raw::libssh2_channel_x11_req_ex(
+                locked.raw,
+                single_connection as c_int,
+                auth_protocol.as_ref().map_or(null(), |s| s.as_ptr()),
+                auth_cookie.as_ref().map_or(null(), |s| s.as_ptr()),
+                screen_number as c_int,
+            );
+            locked.sess.rc(rc)
+        }
+    }
+
     /// Execute a command
     ///
     /// An execution is one of the standard process services defined by the SSH2
@@ -300,6 +390,35 @@ impl Channel {
         }
     }
 
+    /// Send a signal to the process at the other end of the channel, as with
+    /// `kill`, for example to interrupt a long running `exec`.
+    ///
+    /// The server gives no answer, and may ignore the signal.
+    pub fn signal(&mut self, signal: Signal) -> Result<(), Error> {
+        let name = signal.name();
+        let locked = self.lock();
+        unsafe {
+            let rc = raw::libssh2_channel_signal_ex(
+                locked.raw,
+                name.as_ptr() as *const _,
+                name.len() as size_t,
+            );
+            locked.sess.rc(rc)
+        }
+    }
+
+    /// Send a break, as defined in RFC 4335, for example to a serial console
+    /// server.
+    ///
+    /// libssh2 has no break request of its own, and the generic request it
+    /// sends can only carry a break length of 0, so no length can be given:
+    /// servers take 0 as their default length.
+    pub fn send_break(&mut self) -> Result<(), Error> {
+        // The empty message is encoded as a single uint32 of 0, which is
+        // exactly the body of a break request.
+        self.process_startup("break", Some(""))
+    }
+
     /// Get a handle to the stderr stream of this channel.
     ///
     /// The returned handle implements the `Read` and `Write` traits.
@@ -346,6 +465,29 @@ impl Channel {
         Ok(unsafe { raw::libssh2_channel_get_exit_status(locked.raw) })
     }
 
+    /// Returns how the process at the other end of the channel exited.
+    ///
+    /// This is `ExitStatus::Unknown` until `wait_close` has returned, as
+    /// servers may send the exit status after EOF, up to closing the channel.
+    ///
+    /// libssh2 doesn't record whether the server sent an exit status at all,
+    /// so `ExitStatus::Code(0)` may also mean that the server didn't say how
+    /// the process exited.
+    pub fn process_status(&self) -> Result<ExitStatus, Error> {
+        if !self.channel_inner.closed.load(Ordering::SeqCst) {
+            return Ok(ExitStatus::Unknown);
+        }
+        let signal = self.exit_signal()?;
+        match signal.exit_signal {
+            Some(name) => Ok(ExitStatus::Signal {
+                signal: Signal::from_name(&name),
+                message: signal.error_message,
+                lang: signal.lang_tag,
+            }),
+            None => self.exit_status().map(ExitStatus::Code),
+        }
+    }
+
     /// Get the remote exit signal.
     pub fn exit_signal(&self) -> Result<ExitSignal, Error> {
         let locked = self.lock();
@@ -460,6 +602,15 @@ impl Channel {
         unsafe { locked.sess.rc(raw::libssh2_channel_send_eof(locked.raw)) }
     }
 
+    /// Same as `send_eof`, but fails with `LIBSSH2_ERROR_EAGAIN` instead of
+    /// blocking, whatever the session's mode.
+    pub(crate) fn try_send_eof(&mut self) -> Result<(), Error> {
+        let locked = self.lock();
+        locked
+            .sess
+            .nonblocking(|| unsafe { locked.sess.rc(raw::libssh2_channel_send_eof(locked.raw)) })
+    }
+
     /// Wait for the remote end to send EOF.
     /// Note that unread buffered stdout and stderr will cause this function
     /// to return `Ok(())` without waiting.
@@ -490,7 +641,150 @@ impl Channel {
     /// Typically sent after `close` in order to examine the exit status.
     pub fn wait_close(&mut self) -> Result<(), Error> {
         let locked = self.lock();
-        unsafe { locked.sess.rc(raw::libssh2_channel_wait_closed(locked.raw)) }
+        unsafe {
+            locked
+                .sess
+                .rc(raw::libssh2_channel_wait_closed(locked.raw))?
+        };
+        self.channel_inner.closed.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Copy data both ways between this channel and `local` until each
+    /// direction has reached end of file, returning the number of bytes
+    /// written to the channel and read from it.
+    ///
+    /// Data read from `local` is written to the channel, and `send_eof` is
+    /// called once `local` reports end of file. The channel's stdout and
+    /// stderr are both written to `local` until the remote end sends EOF or
+    /// closes the channel, after which `local` is flushed and the copy ends,
+    /// even if `local` has more to send. The channel is left open, so the
+    /// exit status can be collected with `wait_close` afterwards.
+    ///
+    /// This works whether or not the session is blocking. The channel is
+    /// only ever called without blocking, so that a full window in one
+    /// direction can't hold up the other, and the mode is switched back
+    /// before the session is unlocked, so other users of the session don't
+    /// see the change. `local` is read from this thread, so a `local` which
+    /// blocks on reading keeps data from the channel waiting until it
+    /// returns; sockets can be made non-blocking beforehand.
+    ///
+    /// If reading from `local` fails, end of file is still sent to the
+    /// channel and the rest of its data is passed on, then that error is
+    /// returned.
+    ///
+    /// ```no_run
+    /// use std::net::TcpStream;
+    /// # let sess: ssh2::Session = panic!();
+    ///
+    /// let mut channel = sess.channel_direct_tcpip("localhost", 80, None).unwrap();
+    /// let mut local = TcpStream::connect("127.0.0.1:8080").unwrap();
+    /// local.set_nonblocking(true).unwrap();
+    /// let (sent, received) = channel.copy_bidirectional(&mut local).unwrap();
+    /// println!("sent {} bytes, received {}", sent, received);
+    /// ```
+    pub fn copy_bidirectional<L: Read + Write>(
+        &mut self,
+        local: &mut L,
+    ) -> Result<(u64, u64), Error> {
+        let sess = Session::from_inner(Arc::clone(&self.channel_inner.sess));
+        let mut pump = Pump::new(self.clone(), Plain(local))
+            .with_stderr()
+            .until_remote_eof();
+        pump.copy(&sess, None)?;
+        if let Some(e) = pump.take_local_error() {
+            return Err(Error::from_io(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_SOCKET_RECV),
+                "failed to read the local side of a copy",
+                &e,
+            ));
+        }
+        Ok(pump.counts())
+    }
+}
+
+impl ExitStatus {
+    /// Returns whether the process exited with status 0, like
+    /// `std::process::ExitStatus::success`.
+    pub fn success(&self) -> bool {
+        *self == ExitStatus::Code(0)
+    }
+
+    /// Returns the exit status code, or `None` if the process was killed by
+    /// a signal or hasn't exited, like `std::process::ExitStatus::code`.
+    pub fn code(&self) -> Option<i32> {
+        match *self {
+            ExitStatus::Code(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    /// Returns the signal which killed the process, if any.
+    pub fn signal(&self) -> Option<&Signal> {
+        match *self {
+            ExitStatus::Signal { ref signal, .. } => Some(signal),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExitStatus::Code(code) => write!(f, "exit status: {}", code),
+            ExitStatus::Signal { ref signal, .. } => write!(f, "signal: SIG{}", signal),
+            ExitStatus::Unknown => f.write_str("unknown exit status"),
+        }
+    }
+}
+
+impl Signal {
+    /// Returns the signal with the given name, without the `SIG` prefix, as
+    /// used in the SSH protocol.
+    pub fn from_name(name: &str) -> Signal {
+        match name {
+            "ABRT" => Signal::ABRT,
+            "ALRM" => Signal::ALRM,
+            "FPE" => Signal::FPE,
+            "HUP" => Signal::HUP,
+            "ILL" => Signal::ILL,
+            "INT" => Signal::INT,
+            "KILL" => Signal::KILL,
+            "PIPE" => Signal::PIPE,
+            "QUIT" => Signal::QUIT,
+            "SEGV" => Signal::SEGV,
+            "TERM" => Signal::TERM,
+            "USR1" => Signal::USR1,
+            "USR2" => Signal::USR2,
+            other => Signal::Other(other.to_string()),
+        }
+    }
+
+    /// Returns the name of the signal, without the `SIG` prefix, as used in
+    /// the SSH protocol.
+    pub fn name(&self) -> &str {
+        match *self {
+            Signal::ABRT => "ABRT",
+            Signal::ALRM => "ALRM",
+            Signal::FPE => "FPE",
+            Signal::HUP => "HUP",
+            Signal::ILL => "ILL",
+            Signal::INT => "INT",
+            Signal::KILL => "KILL",
+            Signal::PIPE => "PIPE",
+            Signal::QUIT => "QUIT",
+            Signal::SEGV => "SEGV",
+            Signal::TERM => "TERM",
+            Signal::USR1 => "USR1",
+            Signal::USR2 => "USR2",
+            Signal::Other(ref name) => name,
+        }
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
     }
 }
 
@@ -528,11 +822,24 @@ impl Stream {
             read_limit: self.channel_inner.read_limit.lock(),
         }
     }
-}
 
-impl Read for Stream {
-    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+    /// Same as `Read::read`, keeping the libssh2 error.
+    pub(crate) fn read_(&mut self, data: &mut [u8]) -> Result<usize, Error> {
+        Stream::read_locked(&mut self.lock(), data)
+    }
+
+    /// Same as `read_`, but fails with `LIBSSH2_ERROR_EAGAIN` instead of
+    /// blocking, whatever the session's mode.
+    pub(crate) fn try_read(&mut self, data: &mut [u8]) -> Result<usize, Error> {
         let mut locked = self.lock();
+        let blocking = locked.sess.is_blocking();
+        locked.sess.set_blocking(false);
+        let ret = Stream::read_locked(&mut locked, data);
+        locked.sess.set_blocking(blocking);
+        ret
+    }
+
+    fn read_locked(locked: &mut LockedStream, data: &mut [u8]) -> Result<usize, Error> {
         if locked.eof() {
             return Ok(0);
         }
@@ -553,21 +860,29 @@ impl Read for Stream {
             );
             locked.sess.rc(rc as c_int).map(|()| rc as usize)
         };
-        match ret {
-            Ok(n) => {
-                if let Some(ref mut amt) = locked.read_limit.as_mut() {
-                    **amt -= n as u64;
-                }
-                Ok(n)
+        if let Ok(n) = ret {
+            if let Some(ref mut amt) = locked.read_limit.as_mut() {
+                **amt -= n as u64;
             }
-            Err(e) => Err(e.into()),
         }
+        ret
     }
-}
 
-impl Write for Stream {
-    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+    /// Same as `Write::write`, keeping the libssh2 error.
+    pub(crate) fn write_(&mut self, data: &[u8]) -> Result<usize, Error> {
+        Stream::write_locked(&self.lock(), data)
+    }
+
+    /// Same as `write_`, but fails with `LIBSSH2_ERROR_EAGAIN` instead of
+    /// blocking, whatever the session's mode.
+    pub(crate) fn try_write(&mut self, data: &[u8]) -> Result<usize, Error> {
         let locked = self.lock();
+        locked
+            .sess
+            .nonblocking(|| Stream::write_locked(&locked, data))
+    }
+
+    fn write_locked(locked: &LockedStream, data: &[u8]) -> Result<usize, Error> {
         unsafe {
             let rc = raw::libssh2_channel_write_ex(
                 locked.raw,
@@ -577,7 +892,18 @@ impl Write for Stream {
             );
             locked.sess.rc(rc as c_int).map(|()| rc as usize)
         }
-        .map_err(Into::into)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        self.read_(data).map_err(Into::into)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.write_(data).map_err(Into::into)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -0,0 +1,458 @@
+use std::borrow::Cow;
+use std::io::{self, Read};
+use std::mem;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pump;
+use util::{self, nonblocking};
+use {raw, Channel, Error, ErrorCode, ExitStatus, PtyModes, Session, Stream};
+
+/// A builder for running a command on the remote host, modeled on
+/// `std::process::Command`.
+///
+/// It takes care of the details of running a command over a session channel:
+/// stdout and stderr are read together, so that neither can fill up its
+/// window and stall the other, stdin is closed once all of its data has been
+/// sent, and the exit status is collected once the channel is closed.
+///
+/// The program is passed to the remote user's shell as it is, so it may be a
+/// whole command line. Arguments added with `arg` are quoted for the shell.
+///
+/// The session must be in non-blocking mode, as the command's streams are
+/// polled, and is left in that mode.
+///
+/// # Example
+///
+/// ```no_run
+/// use ssh2::{RemoteCommand, Session};
+///
+/// # let sess: Session = panic!();
+/// sess.set_blocking(false);
+/// let output = RemoteCommand::new("ls")
+///     .arg("-l")
+///     .arg("/tmp/some dir")
+///     .env("LC_ALL", "C")
+///     .output(&sess)
+///     .unwrap();
+/// assert!(output.status.success());
+/// println!("{}", String::from_utf8_lossy(&output.stdout));
+/// ```
+pub struct RemoteCommand {
+    program: String,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    pty: Option<Pty>,
+    stdin: Input,
+    timeout: Option<Duration>,
+    output_limit: Option<usize>,
+}
+
+struct Pty {
+    term: String,
+    mode: Option<PtyModes>,
+    dim: Option<(u32, u32, u32, u32)>,
+}
+
+enum Input {
+    Null,
+    Data(Vec<u8>),
+    Reader(Box<dyn Read + Send>),
+    // Chunks read from a reader by a thread of its own, an empty one at EOF.
+    Pipe(Receiver<io::Result<Vec<u8>>>),
+}
+
+/// A command started with `RemoteCommand::spawn`.
+///
+/// Its output can be read as it arrives from the `stdout` and `stderr`
+/// streams, or collected with `wait_with_output`.
+pub struct RemoteChild {
+    sess: Session,
+    channel: Channel,
+    stdin: Input,
+    deadline: Option<Instant>,
+    output_limit: Option<usize>,
+}
+
+/// The output of a finished remote command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    /// How the command exited.
+    pub status: ExitStatus,
+    /// The data the command wrote to stdout.
+    pub stdout: Vec<u8>,
+    /// The data the command wrote to stderr.
+    pub stderr: Vec<u8>,
+    /// Whether some of the output was dropped because of the output limit.
+    pub truncated: bool,
+}
+
+impl RemoteCommand {
+    /// Create a builder for running `program`.
+    ///
+    /// By default the command gets no input, no pseudo terminal, no time
+    /// limit and no limit on the size of its output.
+    pub fn new(program: &str) -> RemoteCommand {
+        RemoteCommand {
+            program: program.to_string(),
+            args: Vec::new(),
+            env: Vec::new(),
+            pty: None,
+            stdin: Input::Null,
+            timeout: None,
+            output_limit: None,
+        }
+    }
+
+    /// Add an argument, which is quoted for the remote shell.
+    pub fn arg(&mut self, arg: &str) -> &mut RemoteCommand {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    /// Add several arguments, which are quoted for the remote shell.
+    pub fn args<I, S>(&mut self, args: I) -> &mut RemoteCommand
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.args
+            .extend(args.into_iter().map(|arg| arg.as_ref().to_string()));
+        self
+    }
+
+    /// Set an environment variable for the command, with
+    /// `Channel::setenv`.
+    ///
+    /// Servers usually only accept a few variables, such as `LANG`, and
+    /// running the command fails if one is refused.
+    pub fn env(&mut self, key: &str, val: &str) -> &mut RemoteCommand {
+        self.env.push((key.to_string(), val.to_string()));
+        self
+    }
+
+    /// Run the command on a pseudo terminal, requested as with
+    /// `Channel::request_pty`.
+    ///
+    /// The terminal merges stderr into stdout.
+    pub fn pty(
+        &mut self,
+        term: &str,
+        mode: Option<PtyModes>,
+        dim: Option<(u32, u32, u32, u32)>,
+    ) -> &mut RemoteCommand {
+        self.pty = Some(Pty {
+            term: term.to_string(),
+            mode,
+            dim,
+        });
+        self
+    }
+
+    /// Send `data` to the command's stdin, then close it.
+    pub fn stdin_data<T: Into<Vec<u8>>>(&mut self, data: T) -> &mut RemoteCommand {
+        self.stdin = Input::Data(data.into());
+        self
+    }
+
+    /// Send everything read from `reader` to the command's stdin, then close
+    /// it.
+    ///
+    /// The reader is read on a thread of its own, so that a reader which
+    /// blocks doesn't hold up the command's output. If the command finishes
+    /// first, the thread exits once the read it is in returns. The reader is
+    /// used up by the first run of the command, later runs get no input.
+    pub fn stdin_reader<R: Read + Send + 'static>(&mut self, reader: R) -> &mut RemoteCommand {
+        self.stdin = Input::Reader(Box::new(reader));
+        self
+    }
+
+    /// Fail with a `LIBSSH2_ERROR_TIMEOUT` error if the command hasn't
+    /// finished within `timeout` of being started.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut RemoteCommand {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Keep at most `limit` bytes of each of stdout and stderr. Anything more
+    /// is read and dropped, and `Output::truncated` is set.
+    pub fn output_limit(&mut self, limit: usize) -> &mut RemoteCommand {
+        self.output_limit = Some(limit);
+        self
+    }
+
+    /// Returns the command line sent to the server.
+    pub fn command_line(&self) -> String {
+        let mut line = self.program.clone();
+        for arg in &self.args {
+            line.push(' ');
+            line.push_str(&quote(arg));
+        }
+        line
+    }
+
+    /// Start the command, returning a handle to it.
+    ///
+    /// The input set with `stdin_data` or `stdin_reader` is sent by
+    /// `RemoteChild::wait` or `wait_with_output`. Fails with
+    /// `LIBSSH2_ERROR_BAD_USE` if the session is in blocking mode.
+    pub fn spawn(&mut self, sess: &Session) -> Result<RemoteChild, Error> {
+        pump::check_nonblocking(sess)?;
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let mut channel = drive(sess, deadline, || sess.channel_session())?;
+        for (key, val) in &self.env {
+            drive(sess, deadline, || channel.setenv(key, val))?;
+        }
+        if let Some(ref pty) = self.pty {
+            drive(sess, deadline, || {
+                channel.request_pty(&pty.term, pty.mode.clone(), pty.dim)
+            })?;
+        }
+        let command_line = self.command_line();
+        drive(sess, deadline, || channel.exec(&command_line))?;
+        let stdin = match mem::replace(&mut self.stdin, Input::Null) {
+            Input::Data(data) => {
+                self.stdin = Input::Data(data.clone());
+                Input::Data(data)
+            }
+            Input::Reader(reader) => Input::pipe(reader),
+            other => other,
+        };
+        Ok(RemoteChild {
+            sess: sess.clone(),
+            channel,
+            stdin,
+            deadline,
+            output_limit: self.output_limit,
+        })
+    }
+
+    /// Run the command, collecting its output.
+    pub fn output(&mut self, sess: &Session) -> Result<Output, Error> {
+        self.spawn(sess)?.wait_with_output()
+    }
+
+    /// Run the command, dropping its output, and return how it exited.
+    pub fn status(&mut self, sess: &Session) -> Result<ExitStatus, Error> {
+        self.spawn(sess)?.wait()
+    }
+}
+
+impl RemoteChild {
+    /// Returns the channel the command runs on.
+    pub fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    /// Returns a handle for writing to the command's stdin.
+    pub fn stdin(&self) -> Stream {
+        self.channel.stream(0)
+    }
+
+    /// Returns a handle for reading the command's stdout.
+    pub fn stdout(&self) -> Stream {
+        self.channel.stream(0)
+    }
+
+    /// Returns a handle for reading the command's stderr.
+    pub fn stderr(&self) -> Stream {
+        self.channel.stderr()
+    }
+
+    /// Send the remaining input, wait for the command to finish, dropping
+    /// any output which wasn't read yet, and return how it exited.
+    pub fn wait(self) -> Result<ExitStatus, Error> {
+        self.run(false).map(|output| output.status)
+    }
+
+    /// Send the remaining input, wait for the command to finish and return
+    /// the output which wasn't read yet.
+    pub fn wait_with_output(self) -> Result<Output, Error> {
+        self.run(true)
+    }
+
+    fn run(mut self, keep: bool) -> Result<Output, Error> {
+        // Everything is driven from this thread, so the streams are polled
+        // rather than read in turn, which could block forever on one stream
+        // while the other's window is full.
+        let mut stdout = Capture::new(keep, self.output_limit);
+        let mut stderr = Capture::new(keep, self.output_limit);
+        let mut buf = vec![0; 32 * 1024];
+        let mut input = Vec::new();
+        let mut written = 0;
+        let mut input_done = false;
+        let mut eof_sent = false;
+
+        while !self.channel.eof() {
+            let mut progress = false;
+
+            if !eof_sent {
+                if written == input.len() && !input_done {
+                    input_done = self.stdin.fill(&mut input)?;
+                    written = 0;
+                }
+                if written < input.len() {
+                    if let Some(n) = nonblocking(self.channel.stream(0).write_(&input[written..]))?
+                    {
+                        written += n;
+                        progress = true;
+                    }
+                } else if input_done && nonblocking(self.channel.send_eof())?.is_some() {
+                    eof_sent = true;
+                    progress = true;
+                }
+            }
+
+            progress |= stdout.read_from(&mut self.channel.stream(0), &mut buf)?;
+            progress |= stderr.read_from(&mut self.channel.stderr(), &mut buf)?;
+
+            if !progress {
+                check_deadline(self.deadline)?;
+                util::wait_session(&self.sess, self.deadline);
+            }
+        }
+
+        let (sess, deadline) = (self.sess.clone(), self.deadline);
+        drive(&sess, deadline, || self.channel.close())?;
+        drive(&sess, deadline, || self.channel.wait_close())?;
+
+        Ok(Output {
+            status: self.channel.process_status()?,
+            truncated: stdout.truncated || stderr.truncated,
+            stdout: stdout.data,
+            stderr: stderr.data,
+        })
+    }
+}
+
+/// Calls `f` until it no longer fails with `LIBSSH2_ERROR_EAGAIN`, waiting
+/// for the session in between.
+fn drive<T, F>(sess: &Session, deadline: Option<Instant>, mut f: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    loop {
+        if let Some(value) = nonblocking(f())? {
+            return Ok(value);
+        }
+        check_deadline(deadline)?;
+        util::wait_session(sess, deadline);
+    }
+}
+
+fn check_deadline(deadline: Option<Instant>) -> Result<(), Error> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(Error::new(
+            ErrorCode::Session(raw::LIBSSH2_ERROR_TIMEOUT),
+            "remote command timed out",
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl Input {
+    /// Starts reading `reader` on a thread of its own.
+    fn pipe(mut reader: Box<dyn Read + Send>) -> Input {
+        let (tx, rx) = mpsc::sync_channel(4);
+        let _ = thread::spawn(move || {
+            let mut buf = vec![0; 32 * 1024];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(n) => {
+                        if tx.send(Ok(buf[..n].to_vec())).is_err() || n == 0 {
+                            break;
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        break;
+                    }
+                }
+            }
+        });
+        Input::Pipe(rx)
+    }
+
+    /// Replaces `buf` with the next chunk of input, which is empty if none
+    /// is available yet, returning whether the input is exhausted.
+    fn fill(&mut self, buf: &mut Vec<u8>) -> Result<bool, Error> {
+        buf.clear();
+        match *self {
+            Input::Null => Ok(true),
+            Input::Data(ref mut data) => {
+                mem::swap(buf, data);
+                Ok(true)
+            }
+            Input::Reader(_) => unreachable!("readers are piped when spawning"),
+            Input::Pipe(ref rx) => match rx.try_recv() {
+                Ok(Ok(data)) => {
+                    *buf = data;
+                    Ok(buf.is_empty())
+                }
+                Ok(Err(e)) => Err(Error::from_io(
+                    ErrorCode::Session(raw::LIBSSH2_ERROR_FILE),
+                    "failed to read the input of a remote command",
+                    &e,
+                )),
+                Err(TryRecvError::Empty) => Ok(false),
+                Err(TryRecvError::Disconnected) => Ok(true),
+            },
+        }
+    }
+}
+
+/// The data read from one of the output streams.
+struct Capture {
+    data: Vec<u8>,
+    keep: bool,
+    limit: Option<usize>,
+    truncated: bool,
+}
+
+impl Capture {
+    fn new(keep: bool, limit: Option<usize>) -> Capture {
+        Capture {
+            data: Vec::new(),
+            keep,
+            limit,
+            truncated: false,
+        }
+    }
+
+    /// Reads what is available from `stream`, returning whether anything
+    /// was read.
+    fn read_from(&mut self, stream: &mut Stream, buf: &mut [u8]) -> Result<bool, Error> {
+        let mut progress = false;
+        while let Some(n) = nonblocking(stream.read_(buf))? {
+            if n == 0 {
+                break;
+            }
+            progress = true;
+            if !self.keep {
+                continue;
+            }
+            let room = match self.limit {
+                Some(limit) => limit.saturating_sub(self.data.len()),
+                None => n,
+            };
+            if n > room {
+                self.truncated = true;
+            }
+            self.data.extend_from_slice(&buf[..n.min(room)]);
+        }
+        Ok(progress)
+    }
+}
+
+/// Quotes `arg` for a POSIX shell, unless it only contains characters which
+/// need no quoting.
+fn quote(arg: &str) -> Cow<'_, str> {
+    let plain = |b: u8| b.is_ascii_alphanumeric() || b"-_./=:,+@%".contains(&b);
+    if !arg.is_empty() && arg.bytes().all(plain) {
+        Cow::Borrowed(arg)
+    } else {
+        Cow::Owned(format!("'{}'", arg.replace('\'', "'\\''")))
+    }
+}
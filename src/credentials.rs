@@ -0,0 +1,471 @@
+//! Pluggable sources of passwords, passphrases and private keys.
+
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fs;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use {raw, AuthMethod, Error, ErrorCode, KeyboardInteractivePrompt, Prompt, Secret};
+
+/// The kind of credential a `CredentialProvider` is asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CredentialKind {
+    /// A login password, used for password and keyboard-interactive
+    /// authentication.
+    Password,
+    /// The passphrase protecting a private key.
+    Passphrase,
+    /// A PEM encoded private key, used for public key authentication.
+    PrivateKey,
+}
+
+/// Describes the credential a `CredentialProvider` is asked for.
+#[derive(Debug, Clone)]
+pub struct CredentialRequest<'a> {
+    /// The user logging in.
+    pub username: &'a str,
+    /// The host being logged in to, as given to `Session::userauth_credentials`.
+    pub host: &'a str,
+    /// The authentication method the credential will be used for.
+    pub method: AuthMethod,
+    /// The kind of credential wanted.
+    pub kind: CredentialKind,
+}
+
+/// A source of credentials for `Session::userauth_credentials`.
+pub trait CredentialProvider {
+    /// Returns the credential described by `request`, or `None` if this
+    /// provider doesn't have one.
+    fn credential(&mut self, request: &CredentialRequest) -> Option<Secret>;
+
+    /// Called when the server rejected `credential`, which was returned for
+    /// `request`.
+    ///
+    /// The built-in providers remember the rejection and don't offer the same
+    /// credential for that user and host again, though they may still offer
+    /// a different one. The default implementation
+    /// does nothing.
+    fn reject(&mut self, request: &CredentialRequest, credential: &Secret) {
+        let _ = (request, credential);
+    }
+}
+
+impl<P: CredentialProvider + ?Sized> CredentialProvider for &mut P {
+    fn credential(&mut self, request: &CredentialRequest) -> Option<Secret> {
+        (**self).credential(request)
+    }
+
+    fn reject(&mut self, request: &CredentialRequest, credential: &Secret) {
+        (**self).reject(request, credential)
+    }
+}
+
+impl<P: CredentialProvider + ?Sized> CredentialProvider for Box<P> {
+    fn credential(&mut self, request: &CredentialRequest) -> Option<Secret> {
+        (**self).credential(request)
+    }
+
+    fn reject(&mut self, request: &CredentialRequest, credential: &Secret) {
+        (**self).reject(request, credential)
+    }
+}
+
+/// Asks each provider in turn, returning the first credential found.
+/// Rejections are passed on to every provider, so none of them offers the
+/// rejected credential again.
+impl<P: CredentialProvider> CredentialProvider for Vec<P> {
+    fn credential(&mut self, request: &CredentialRequest) -> Option<Secret> {
+        self.iter_mut().filter_map(|p| p.credential(request)).next()
+    }
+
+    fn reject(&mut self, request: &CredentialRequest, credential: &Secret) {
+        for provider in self.iter_mut() {
+            provider.reject(request, credential);
+        }
+    }
+}
+
+/// The credentials a provider must no longer offer, by username, host, kind
+/// and a hash of the secret so the secret itself isn't kept around.
+///
+/// The hash is keyed with random keys, so that it can't be checked against
+/// guessed secrets without them.
+#[derive(Debug, Default, Clone)]
+struct Rejected {
+    entries: Vec<(String, String, CredentialKind, u64)>,
+    keys: RandomState,
+}
+
+impl Rejected {
+    fn contains(&self, request: &CredentialRequest, credential: &Secret) -> bool {
+        let hash = self.hash(credential);
+        self.entries.iter().any(|&(ref user, ref host, kind, h)| {
+            user == request.username && host == request.host && kind == request.kind && h == hash
+        })
+    }
+
+    fn insert(&mut self, request: &CredentialRequest, credential: &Secret) {
+        if !self.contains(request, credential) {
+            self.entries.push((
+                request.username.to_string(),
+                request.host.to_string(),
+                request.kind,
+                self.hash(credential),
+            ));
+        }
+    }
+
+    fn hash(&self, credential: &Secret) -> u64 {
+        let mut hasher = self.keys.build_hasher();
+        credential.expose().hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Reads credentials from environment variables.
+///
+/// # Example
+///
+/// ```no_run
+/// use ssh2::{EnvCredentials, Session};
+///
+/// # let sess: Session = panic!();
+/// let mut creds = EnvCredentials::new();
+/// creds.password("DEPLOY_PASSWORD");
+/// sess.userauth_credentials("deploy", "example.com", &mut creds).unwrap();
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct EnvCredentials {
+    vars: Vec<(CredentialKind, String)>,
+    rejected: Rejected,
+}
+
+impl EnvCredentials {
+    /// Create a provider which reads no variables.
+    pub fn new() -> EnvCredentials {
+        EnvCredentials::default()
+    }
+
+    /// Read passwords from the variable `name`.
+    pub fn password(&mut self, name: &str) -> &mut EnvCredentials {
+        self.var(CredentialKind::Password, name)
+    }
+
+    /// Read key passphrases from the variable `name`.
+    pub fn passphrase(&mut self, name: &str) -> &mut EnvCredentials {
+        self.var(CredentialKind::Passphrase, name)
+    }
+
+    /// Read PEM encoded private keys from the variable `name`.
+    pub fn private_key(&mut self, name: &str) -> &mut EnvCredentials {
+        self.var(CredentialKind::PrivateKey, name)
+    }
+
+    fn var(&mut self, kind: CredentialKind, name: &str) -> &mut EnvCredentials {
+        self.vars.retain(|&(k, _)| k != kind);
+        self.vars.push((kind, name.to_string()));
+        self
+    }
+}
+
+impl CredentialProvider for EnvCredentials {
+    fn credential(&mut self, request: &CredentialRequest) -> Option<Secret> {
+        let name = self
+            .vars
+            .iter()
+            .find(|&&(kind, _)| kind == request.kind)
+            .map(|(_, name)| name)?;
+        env::var(name)
+            .ok()
+            .map(Secret::new)
+            .filter(|secret| !self.rejected.contains(request, secret))
+    }
+
+    fn reject(&mut self, request: &CredentialRequest, credential: &Secret) {
+        self.rejected.insert(request, credential);
+    }
+}
+
+/// Reads credentials from files, such as the secrets mounted into a
+/// container.
+///
+/// A single trailing newline is removed from passwords and passphrases.
+#[derive(Debug, Default, Clone)]
+pub struct FileCredentials {
+    files: Vec<(CredentialKind, PathBuf)>,
+    rejected: Rejected,
+}
+
+impl FileCredentials {
+    /// Create a provider which reads no files.
+    pub fn new() -> FileCredentials {
+        FileCredentials::default()
+    }
+
+    /// Read passwords from the file at `path`.
+    pub fn password<P: AsRef<Path>>(&mut self, path: P) -> &mut FileCredentials {
+        self.file(CredentialKind::Password, path.as_ref())
+    }
+
+    /// Read key passphrases from the file at `path`.
+    pub fn passphrase<P: AsRef<Path>>(&mut self, path: P) -> &mut FileCredentials {
+        self.file(CredentialKind::Passphrase, path.as_ref())
+    }
+
+    /// Read PEM encoded private keys from the file at `path`.
+    pub fn private_key<P: AsRef<Path>>(&mut self, path: P) -> &mut FileCredentials {
+        self.file(CredentialKind::PrivateKey, path.as_ref())
+    }
+
+    fn file(&mut self, kind: CredentialKind, path: &Path) -> &mut FileCredentials {
+        self.files.retain(|&(k, _)| k != kind);
+        self.files.push((kind, path.to_path_buf()));
+        self
+    }
+}
+
+impl CredentialProvider for FileCredentials {
+    fn credential(&mut self, request: &CredentialRequest) -> Option<Secret> {
+        let path = self
+            .files
+            .iter()
+            .find(|&&(kind, _)| kind == request.kind)
+            .map(|(_, path)| path)?;
+        let mut data = fs::read_to_string(path).ok()?;
+        if request.kind != CredentialKind::PrivateKey {
+            if data.ends_with('\n') {
+                let _ = data.pop();
+            }
+            if data.ends_with('\r') {
+                let _ = data.pop();
+            }
+        }
+        Some(Secret::new(data)).filter(|secret| !self.rejected.contains(request, secret))
+    }
+
+    fn reject(&mut self, request: &CredentialRequest, credential: &Secret) {
+        self.rejected.insert(request, credential);
+    }
+}
+
+/// Reads passwords from a file in the `.netrc` format used by ftp, curl and
+/// git.
+///
+/// The entry for the requested host is used, or the `default` entry if there
+/// is none. Entries with a `login` only match that user.
+#[derive(Debug, Default, Clone)]
+pub struct NetrcCredentials {
+    entries: Vec<NetrcEntry>,
+    rejected: Rejected,
+}
+
+#[derive(Debug, Default, Clone)]
+struct NetrcEntry {
+    // `None` for the `default` entry.
+    machine: Option<String>,
+    login: Option<String>,
+    password: Option<Secret>,
+}
+
+impl NetrcCredentials {
+    /// Parse the contents of a netrc file.
+    pub fn parse(data: &str) -> NetrcCredentials {
+        let mut entries = Vec::new();
+        let mut entry: Option<NetrcEntry> = None;
+        let mut in_macdef = false;
+        for line in data.lines() {
+            // Macro definitions run until the next blank line.
+            if in_macdef {
+                in_macdef = !line.trim().is_empty();
+                continue;
+            }
+            let mut tokens = line.split_whitespace();
+            while let Some(token) = tokens.next() {
+                match token {
+                    "machine" | "default" => {
+                        entries.extend(entry.take());
+                        let machine = if token == "machine" {
+                            tokens.next().map(|s| s.to_string())
+                        } else {
+                            None
+                        };
+                        entry = Some(NetrcEntry {
+                            machine,
+                            ..NetrcEntry::default()
+                        });
+                    }
+                    "login" => {
+                        let value = tokens.next().map(|s| s.to_string());
+                        if let Some(ref mut entry) = entry {
+                            entry.login = value;
+                        }
+                    }
+                    "password" => {
+                        let value = tokens.next().map(Secret::from);
+                        if let Some(ref mut entry) = entry {
+                            entry.password = value;
+                        }
+                    }
+                    "account" => {
+                        let _ = tokens.next();
+                    }
+                    "macdef" => {
+                        in_macdef = true;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        entries.extend(entry);
+        NetrcCredentials {
+            entries,
+            rejected: Rejected::default(),
+        }
+    }
+
+    /// Read the netrc file at `path`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<NetrcCredentials, Error> {
+        let data = Secret::new(fs::read_to_string(path).map_err(|_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_FILE),
+                "unable to read netrc file",
+            )
+        })?);
+        Ok(NetrcCredentials::parse(&data))
+    }
+}
+
+impl CredentialProvider for NetrcCredentials {
+    fn credential(&mut self, request: &CredentialRequest) -> Option<Secret> {
+        if request.kind != CredentialKind::Password {
+            return None;
+        }
+        let user_matches = |entry: &&NetrcEntry| match entry.login {
+            Some(ref login) => login == request.username,
+            None => true,
+        };
+        let entry = self
+            .entries
+            .iter()
+            .filter(|e| e.machine.as_deref() == Some(request.host))
+            .find(user_matches)
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .filter(|e| e.machine.is_none())
+                    .find(user_matches)
+            })?;
+        entry
+            .password
+            .clone()
+            .filter(|secret| !self.rejected.contains(request, secret))
+    }
+
+    fn reject(&mut self, request: &CredentialRequest, credential: &Secret) {
+        self.rejected.insert(request, credential);
+    }
+}
+
+/// Hands out credentials given to it up front, for example from a secret
+/// store queried at startup.
+///
+/// # Example
+///
+/// ```no_run
+/// use ssh2::{CredentialKind, Session, StaticCredentials};
+///
+/// # let sess: Session = panic!();
+/// let mut creds = StaticCredentials::new();
+/// creds
+///     .insert(CredentialKind::Password, "hunter2".into())
+///     .insert_for("root", "db.example.com", CredentialKind::Password, "letmein".into());
+/// sess.userauth_credentials("root", "db.example.com", &mut creds).unwrap();
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct StaticCredentials {
+    entries: Vec<(Option<String>, Option<String>, CredentialKind, Secret)>,
+    rejected: Rejected,
+}
+
+impl StaticCredentials {
+    /// Create a provider with no credentials.
+    pub fn new() -> StaticCredentials {
+        StaticCredentials::default()
+    }
+
+    /// Offer `secret` for any user and host.
+    pub fn insert(&mut self, kind: CredentialKind, secret: Secret) -> &mut StaticCredentials {
+        self.entries.push((None, None, kind, secret));
+        self
+    }
+
+    /// Offer `secret` only for `username` on `host`.
+    ///
+    /// Credentials added this way take precedence over those added with
+    /// `insert`.
+    pub fn insert_for(
+        &mut self,
+        username: &str,
+        host: &str,
+        kind: CredentialKind,
+        secret: Secret,
+    ) -> &mut StaticCredentials {
+        self.entries.insert(
+            0,
+            (
+                Some(username.to_string()),
+                Some(host.to_string()),
+                kind,
+                secret,
+            ),
+        );
+        self
+    }
+}
+
+impl CredentialProvider for StaticCredentials {
+    fn credential(&mut self, request: &CredentialRequest) -> Option<Secret> {
+        let rejected = &self.rejected;
+        self.entries
+            .iter()
+            .find(|&&(ref user, ref host, kind, ref secret)| {
+                kind == request.kind
+                    && user.iter().all(|u| u == request.username)
+                    && host.iter().all(|h| h == request.host)
+                    && !rejected.contains(request, secret)
+            })
+            .map(|(_, _, _, secret)| secret.clone())
+    }
+
+    fn reject(&mut self, request: &CredentialRequest, credential: &Secret) {
+        self.rejected.insert(request, credential);
+    }
+}
+
+/// Answers every hidden keyboard-interactive prompt with a password, which is
+/// how servers backed by PAM ask for one.
+pub(crate) struct PasswordPrompter<'a> {
+    pub password: &'a str,
+}
+
+impl<'a> KeyboardInteractivePrompt for PasswordPrompter<'a> {
+    fn prompt<'b>(
+        &mut self,
+        _username: &str,
+        _instructions: &str,
+        prompts: &[Prompt<'b>],
+    ) -> Vec<String> {
+        prompts
+            .iter()
+            .map(|p| {
+                if p.echo {
+                    String::new()
+                } else {
+                    self.password.to_string()
+                }
+            })
+            .collect()
+    }
+}
@@ -102,6 +102,15 @@ impl Error {
         }
     }
 
+    /// Create an error for a failed local I/O operation, keeping the kind
+    /// and message of `err` after `context`.
+    pub(crate) fn from_io(code: ErrorCode, context: &str, err: &io::Error) -> Error {
+        Error {
+            code,
+            msg: Cow::Owned(format!("{}: {} ({:?})", context, err, err.kind())),
+        }
+    }
+
     /// Generate an error that represents EOF
     pub fn eof() -> Error {
         Error::new(
@@ -0,0 +1,663 @@
+use parking_lot::Mutex;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use pump::{self, LocalStream, Pump};
+use socks;
+use util::{self, nonblocking};
+use {raw, Channel, Error, ErrorCode, Listener, Session};
+
+/// How often the accept loop checks whether it should stop.
+const ACCEPT_WAIT: Duration = Duration::from_millis(50);
+
+/// How long a SOCKS client may take to send its request.
+const SOCKS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the server may take to answer a channel open, unless the
+/// session has a timeout of its own.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What happened to one forwarded connection.
+#[derive(Debug)]
+pub struct ForwardReport {
+    /// Where the connection came from: the peer's address for connections
+    /// to a local TCP socket, otherwise a description of the socket.
+    pub peer: String,
+    /// Bytes sent through the channel.
+    pub sent: u64,
+    /// Bytes received from the channel.
+    pub received: u64,
+    /// Why the connection failed, if it did.
+    pub error: Option<Error>,
+}
+
+/// A running forwarding service, as returned by `LocalForward::start` and
+/// `RemoteForward::start`.
+///
+/// Dropping the handle stops the service, like `stop` does.
+pub struct ForwardHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<(), Error>>>,
+    reports: Receiver<ForwardReport>,
+    local_addr: Option<SocketAddr>,
+    remote_port: Option<u16>,
+}
+
+impl ForwardHandle {
+    /// Returns the address a `LocalForward` listens on, for TCP listeners.
+    /// This tells the port picked when binding to port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Returns the port the server listens on for a `RemoteForward`. This
+    /// tells the port picked when asking for port 0.
+    pub fn remote_port(&self) -> Option<u16> {
+        self.remote_port
+    }
+
+    /// Returns the reports of the connections which have finished since the
+    /// last call.
+    pub fn reports(&self) -> Vec<ForwardReport> {
+        self.reports.try_iter().collect()
+    }
+
+    /// Stops accepting connections, closes the channels of the ones still
+    /// open, and waits for the service to finish.
+    ///
+    /// Returns the reports not yet returned by `reports`, or the error which
+    /// ended the service early.
+    pub fn stop(mut self) -> Result<Vec<ForwardReport>, Error> {
+        self.shutdown()?;
+        Ok(self.reports())
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        self.stop.store(true, Ordering::SeqCst);
+        match self.thread.take().map(|t| t.join()) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_BAD_USE),
+                "forwarding thread panicked",
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ForwardHandle {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+/// Where forwarded connections go on the remote side.
+#[derive(Clone)]
+enum Target {
+    Tcp(String, u16),
+    StreamLocal(String),
+    /// Wherever each SOCKS client asks for.
+    Socks,
+}
+
+enum Listening {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// Forwards connections made to a local socket through the session, like
+/// `ssh -L`, or like `ssh -D` as a SOCKS proxy.
+///
+/// Each connection accepted gets a `direct-tcpip` or `direct-streamlocal`
+/// channel of its own, with data copied both ways and end of file passed
+/// on in each direction. The service runs on threads of its own, sharing
+/// the session with the rest of the program, so the session must be in
+/// non-blocking mode.
+///
+/// The server has the session's timeout, or 30 seconds if it has none, to
+/// answer each channel open. Otherwise the connection fails with
+/// `LIBSSH2_ERROR_TIMEOUT` and the service stops: libssh2 would hand the
+/// late answer to the next channel opened on the session.
+///
+/// # Example
+///
+/// ```no_run
+/// use ssh2::{LocalForward, Session};
+///
+/// # let sess: Session = panic!();
+/// sess.set_blocking(false);
+/// // ssh -L 8080:db:5432
+/// let handle = LocalForward::tcp("db", 5432)
+///     .listen("127.0.0.1:8080")
+///     .unwrap()
+///     .start(&sess)
+///     .unwrap();
+/// // ...
+/// for report in handle.stop().unwrap() {
+///     println!("{}: {} bytes out, {} in", report.peer, report.sent, report.received);
+/// }
+/// ```
+pub struct LocalForward {
+    target: Target,
+    listening: Option<Listening>,
+}
+
+impl LocalForward {
+    /// Forwards connections to `host` and `port`, as seen from the server.
+    pub fn tcp(host: &str, port: u16) -> LocalForward {
+        LocalForward {
+            target: Target::Tcp(host.to_string(), port),
+            listening: None,
+        }
+    }
+
+    /// Forwards connections to the Unix socket at `socket_path` on the
+    /// server.
+    pub fn streamlocal(socket_path: &str) -> LocalForward {
+        LocalForward {
+            target: Target::StreamLocal(socket_path.to_string()),
+            listening: None,
+        }
+    }
+
+    /// Serves SOCKS4, SOCKS4a and SOCKS5 clients, like `ssh -D`, forwarding
+    /// each to the host and port it asks for.
+    ///
+    /// Host names are resolved by the server. Only `CONNECT` requests
+    /// without authentication are supported. When the channel can't be
+    /// opened, the SOCKS5 reply says whether the server refused to forward
+    /// or failed to connect.
+    pub fn dynamic() -> LocalForward {
+        LocalForward {
+            target: Target::Socks,
+            listening: None,
+        }
+    }
+
+    /// Listens for connections on a TCP address, such as `127.0.0.1:8080`.
+    pub fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> Result<&mut LocalForward, Error> {
+        let listener = TcpListener::bind(addr).map_err(|e| bind_err(&e))?;
+        self.listening = Some(Listening::Tcp(listener));
+        Ok(self)
+    }
+
+    /// Listens for connections on a Unix socket at `path`, which is removed
+    /// when the service stops.
+    #[cfg(unix)]
+    pub fn listen_unix(&mut self, path: &Path) -> Result<&mut LocalForward, Error> {
+        let listener = UnixListener::bind(path).map_err(|e| bind_err(&e))?;
+        self.listening = Some(Listening::Unix(listener, path.to_path_buf()));
+        Ok(self)
+    }
+
+    /// Starts forwarding connections, on a thread of its own.
+    pub fn start(&mut self, sess: &Session) -> Result<ForwardHandle, Error> {
+        pump::check_nonblocking(sess)?;
+        let listening = self.listening.take().ok_or_else(|| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_BAD_USE),
+                "no address to listen on was given",
+            )
+        })?;
+        let local_addr = match listening {
+            Listening::Tcp(ref listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listening::Unix(..) => None,
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, reports) = mpsc::channel();
+        let service = LocalService {
+            sess: sess.clone(),
+            target: self.target.clone(),
+            stop: stop.clone(),
+            reports: tx,
+            opening: Arc::new(Mutex::new(())),
+        };
+        let thread = thread::spawn(move || match listening {
+            Listening::Tcp(listener) => service.serve(&listener),
+            #[cfg(unix)]
+            Listening::Unix(listener, path) => {
+                let result = service.serve(&listener);
+                let _ = ::std::fs::remove_file(path);
+                result
+            }
+        });
+        Ok(ForwardHandle {
+            stop,
+            thread: Some(thread),
+            reports,
+            local_addr,
+            remote_port: None,
+        })
+    }
+}
+
+/// Where connections forwarded from the server go on this side.
+#[derive(Clone)]
+enum Connect {
+    Tcp(Vec<SocketAddr>),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Forwards connections made to a port on the server to a local socket,
+/// like `ssh -R`.
+///
+/// The server is asked to listen on the port, and each `forwarded-tcpip`
+/// channel it opens for a connection is connected to the local socket, with
+/// data copied both ways and end of file passed on in each direction.
+/// Stopping the service, or dropping its handle, cancels the forward on the
+/// server. As with `LocalForward`, the session must be in non-blocking mode.
+///
+/// # Example
+///
+/// ```no_run
+/// use ssh2::{RemoteForward, Session};
+///
+/// # let sess: Session = panic!();
+/// sess.set_blocking(false);
+/// // ssh -R 8080:localhost:3000
+/// let handle = RemoteForward::tcp("localhost:3000")
+///     .unwrap()
+///     .listen(None, 8080)
+///     .start(&sess)
+///     .unwrap();
+/// ```
+pub struct RemoteForward {
+    connect: Connect,
+    host: Option<String>,
+    port: u16,
+    queue_maxsize: Option<u32>,
+}
+
+impl RemoteForward {
+    /// Connects forwarded connections to a local TCP address, such as
+    /// `localhost:3000`.
+    pub fn tcp<A: ToSocketAddrs>(addr: A) -> Result<RemoteForward, Error> {
+        let addrs = addr
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<_>>())
+            .ok()
+            .filter(|addrs| !addrs.is_empty())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorCode::Session(raw::LIBSSH2_ERROR_INVAL),
+                    "unable to resolve the local address",
+                )
+            })?;
+        Ok(RemoteForward::new(Connect::Tcp(addrs)))
+    }
+
+    /// Connects forwarded connections to a local Unix socket.
+    #[cfg(unix)]
+    pub fn unix(path: &Path) -> RemoteForward {
+        RemoteForward::new(Connect::Unix(path.to_path_buf()))
+    }
+
+    fn new(connect: Connect) -> RemoteForward {
+        RemoteForward {
+            connect,
+            host: None,
+            port: 0,
+            queue_maxsize: None,
+        }
+    }
+
+    /// Sets the address and port the server should listen on, as for
+    /// `Session::channel_forward_listen`. By default the server picks a port
+    /// on all of its addresses.
+    pub fn listen(&mut self, host: Option<&str>, port: u16) -> &mut RemoteForward {
+        self.host = host.map(|h| h.to_string());
+        self.port = port;
+        self
+    }
+
+    /// Sets how many connections the server may queue before they are
+    /// accepted.
+    pub fn queue_maxsize(&mut self, size: u32) -> &mut RemoteForward {
+        self.queue_maxsize = Some(size);
+        self
+    }
+
+    /// Asks the server to listen, and starts forwarding connections on a
+    /// thread of its own.
+    pub fn start(&mut self, sess: &Session) -> Result<ForwardHandle, Error> {
+        pump::check_nonblocking(sess)?;
+        let (listener, port) = loop {
+            let listen =
+                sess.channel_forward_listen(self.port, self.host.as_deref(), self.queue_maxsize);
+            match nonblocking(listen)? {
+                Some(listening) => break listening,
+                None => util::wait_session(sess, None),
+            }
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, reports) = mpsc::channel();
+        let service = RemoteService {
+            sess: sess.clone(),
+            connect: self.connect.clone(),
+            peer: format!("{}:{}", self.host.as_deref().unwrap_or("*"), port),
+            stop: stop.clone(),
+            reports: tx,
+        };
+        let thread = thread::spawn(move || service.serve(listener));
+        Ok(ForwardHandle {
+            stop,
+            thread: Some(thread),
+            reports,
+            local_addr: None,
+            remote_port: Some(port),
+        })
+    }
+}
+
+fn bind_err(err: &io::Error) -> Error {
+    Error::from_io(
+        ErrorCode::Session(raw::LIBSSH2_ERROR_SOCKET_NONE),
+        "unable to listen on the local address",
+        err,
+    )
+}
+
+/// A listening socket whose connections can be forwarded.
+trait Accept {
+    type Stream: LocalStream + 'static;
+
+    fn set_nonblocking(&self) -> io::Result<()>;
+    fn accept(&self) -> io::Result<(Self::Stream, String)>;
+    #[cfg(unix)]
+    fn poll_fd(&self) -> RawFd;
+}
+
+impl Accept for TcpListener {
+    type Stream = TcpStream;
+
+    fn set_nonblocking(&self) -> io::Result<()> {
+        TcpListener::set_nonblocking(self, true)
+    }
+
+    fn accept(&self) -> io::Result<(TcpStream, String)> {
+        TcpListener::accept(self).map(|(s, addr)| (s, addr.to_string()))
+    }
+
+    #[cfg(unix)]
+    fn poll_fd(&self) -> RawFd {
+        self.as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl Accept for UnixListener {
+    type Stream = UnixStream;
+
+    fn set_nonblocking(&self) -> io::Result<()> {
+        UnixListener::set_nonblocking(self, true)
+    }
+
+    fn accept(&self) -> io::Result<(UnixStream, String)> {
+        UnixListener::accept(self).map(|(s, addr)| {
+            let peer = match addr.as_pathname() {
+                Some(path) => path.display().to_string(),
+                None => "unix socket".to_string(),
+            };
+            (s, peer)
+        })
+    }
+
+    fn poll_fd(&self) -> RawFd {
+        self.as_raw_fd()
+    }
+}
+
+#[derive(Clone)]
+struct LocalService {
+    sess: Session,
+    target: Target,
+    stop: Arc<AtomicBool>,
+    reports: Sender<ForwardReport>,
+    /// libssh2 keeps the state of a channel being opened in the session,
+    /// so only one connection at a time may be opening its channel.
+    opening: Arc<Mutex<()>>,
+}
+
+impl LocalService {
+    fn serve<L: Accept>(&self, listener: &L) -> Result<(), Error> {
+        listener.set_nonblocking().map_err(|e| bind_err(&e))?;
+        let mut connections: Vec<JoinHandle<()>> = Vec::new();
+        let result = loop {
+            if self.stop.load(Ordering::SeqCst) {
+                break Ok(());
+            }
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    let service = self.clone();
+                    connections.retain(|c| !c.is_finished());
+                    connections.push(thread::spawn(move || service.connection(stream, peer)));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => wait_accept(listener),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    break Err(Error::from_io(
+                        ErrorCode::Session(raw::LIBSSH2_ERROR_SOCKET_NONE),
+                        "unable to accept a connection",
+                        &e,
+                    ))
+                }
+            }
+        };
+        // Make the connections wind down too if accepting failed.
+        self.stop.store(true, Ordering::SeqCst);
+        for connection in connections {
+            let _ = connection.join();
+        }
+        result
+    }
+
+    fn connection<S: LocalStream>(&self, mut stream: S, peer: String) {
+        let mut report = ForwardReport {
+            peer,
+            sent: 0,
+            received: 0,
+            error: None,
+        };
+        let channel = match self.target {
+            Target::Socks => self.socks(&mut stream, &report.peer),
+            ref target => self.open(&report.peer, target),
+        };
+        let result =
+            channel.and_then(|channel| relay(&self.sess, channel, stream, &self.stop, &mut report));
+        report.error = result.err();
+        let _ = self.reports.send(report);
+    }
+
+    /// Reads a SOCKS request from `stream`, and opens a channel to where it
+    /// asks, telling the client how that went.
+    fn socks<S: LocalStream>(&self, stream: &mut S, peer: &str) -> Result<Channel, Error> {
+        // Sockets accepted from a non-blocking listener can inherit its mode.
+        let _ = stream.set_nonblocking(false);
+        let _ = stream.set_read_timeout(Some(SOCKS_TIMEOUT));
+        let request = socks::read_request(stream)?;
+        let _ = stream.set_read_timeout(None);
+        let mut channel = match self.open(peer, &Target::Tcp(request.host.clone(), request.port)) {
+            Ok(channel) => channel,
+            Err(e) => {
+                let _ = request.fail_open(stream, &e);
+                return Err(e);
+            }
+        };
+        if request.succeed(stream).is_err() {
+            let _ = pump::close(&mut channel, &self.sess);
+            return Err(Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_SOCKET_SEND),
+                "SOCKS client connection failed",
+            ));
+        }
+        Ok(channel)
+    }
+
+    /// Opens a channel to `target`.
+    fn open(&self, peer: &str, target: &Target) -> Result<Channel, Error> {
+        let src = peer.parse::<SocketAddr>().ok();
+        let src = src.as_ref().map(|a| (a.ip().to_string(), a.port()));
+        let src = src.as_ref().map(|&(ref ip, port)| (&**ip, port));
+        let _opening = self.opening.lock();
+        // Stopping is only safe before the open starts.
+        if self.stop.load(Ordering::SeqCst) {
+            return Err(Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_CHANNEL_FAILURE),
+                "forwarding stopped before the channel was opened",
+            ));
+        }
+        let timeout = match self.sess.timeout() {
+            0 => OPEN_TIMEOUT,
+            ms => Duration::from_millis(u64::from(ms)),
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            let channel = match *target {
+                Target::Tcp(ref host, port) => self.sess.channel_direct_tcpip(host, port, src),
+                Target::StreamLocal(ref path) => self.sess.channel_direct_streamlocal(path, src),
+                Target::Socks => unreachable!("SOCKS targets come from each request"),
+            };
+            // Even when stopping, an open once started has to be finished,
+            // or the next one would pick up its state. Only a server which
+            // doesn't answer at all is given up on, along with the service.
+            match nonblocking(channel)? {
+                Some(channel) => return Ok(channel),
+                None if Instant::now() >= deadline => {
+                    self.stop.store(true, Ordering::SeqCst);
+                    return Err(Error::new(
+                        ErrorCode::Session(raw::LIBSSH2_ERROR_TIMEOUT),
+                        "timed out waiting for the channel to open",
+                    ));
+                }
+                None => util::wait_session(&self.sess, Some(deadline)),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct RemoteService {
+    sess: Session,
+    connect: Connect,
+    /// The remote address listened on, which stands in for the peers the
+    /// server doesn't tell about.
+    peer: String,
+    stop: Arc<AtomicBool>,
+    reports: Sender<ForwardReport>,
+}
+
+impl RemoteService {
+    fn serve(&self, mut listener: Listener) -> Result<(), Error> {
+        let mut connections: Vec<JoinHandle<()>> = Vec::new();
+        let mut result = Ok(());
+        while !self.stop.load(Ordering::SeqCst) {
+            match nonblocking(listener.accept()) {
+                Ok(Some(channel)) => {
+                    let service = self.clone();
+                    connections.retain(|c| !c.is_finished());
+                    connections.push(thread::spawn(move || service.connection(channel)));
+                }
+                Ok(None) => util::wait_session(&self.sess, None),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.stop.store(true, Ordering::SeqCst);
+        for connection in connections {
+            let _ = connection.join();
+        }
+        while nonblocking(listener.cancel())?.is_none() {
+            util::wait_session(&self.sess, None);
+        }
+        result
+    }
+
+    fn connection(&self, mut channel: Channel) {
+        let mut report = ForwardReport {
+            peer: self.peer.clone(),
+            sent: 0,
+            received: 0,
+            error: None,
+        };
+        let result = match self.connect {
+            Connect::Tcp(ref addrs) => TcpStream::connect(&addrs[..])
+                .map(|stream| relay(&self.sess, channel.clone(), stream, &self.stop, &mut report)),
+            #[cfg(unix)]
+            Connect::Unix(ref path) => UnixStream::connect(path)
+                .map(|stream| relay(&self.sess, channel.clone(), stream, &self.stop, &mut report)),
+        };
+        report.error = match result {
+            Ok(relayed) => relayed.err(),
+            Err(e) => {
+                let _ = pump::close(&mut channel, &self.sess);
+                Some(Error::from_io(
+                    ErrorCode::Session(raw::LIBSSH2_ERROR_SOCKET_NONE),
+                    "unable to connect to the local address",
+                    &e,
+                ))
+            }
+        };
+        let _ = self.reports.send(report);
+    }
+}
+
+/// Copies data between `channel` and `stream` until both are done or the
+/// service stops, counting it in `report`.
+fn relay<S: LocalStream>(
+    sess: &Session,
+    mut channel: Channel,
+    stream: S,
+    stop: &AtomicBool,
+    report: &mut ForwardReport,
+) -> Result<(), Error> {
+    if let Err(e) = stream.set_nonblocking(true) {
+        let _ = pump::close(&mut channel, sess);
+        return Err(Error::from_io(
+            ErrorCode::Session(raw::LIBSSH2_ERROR_SOCKET_NONE),
+            "unable to set up the local connection",
+            &e,
+        ));
+    }
+    let mut pump = Pump::new(channel, stream);
+    let result = pump.run(sess, Some(stop));
+    let (sent, received) = pump.counts();
+    report.sent = sent;
+    report.received = received;
+    result
+}
+
+/// Waits a little for a connection to `listener`.
+fn wait_accept<L: Accept>(listener: &L) {
+    #[cfg(unix)]
+    {
+        use libc;
+
+        let mut fd = libc::pollfd {
+            fd: listener.poll_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let _ = unsafe { libc::poll(&mut fd, 1, ACCEPT_WAIT.as_millis() as libc::c_int) };
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = listener;
+        thread::sleep(ACCEPT_WAIT);
+    }
+}
@@ -1,13 +1,30 @@
+use getrandom;
+use hmac::{Hmac, Mac};
 use libc::{c_int, size_t};
 use parking_lot::{Mutex, MutexGuard};
+use sha1::Sha1;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
 use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::mem;
 use std::path::Path;
 use std::ptr::null_mut;
 use std::str;
 use std::sync::Arc;
 
+#[cfg(feature = "keys")]
+use ssh_encoding::{Decode, Encode};
+#[cfg(feature = "keys")]
+use ssh_key::{certificate::CertType, public::KeyData, Certificate, HashAlg};
+#[cfg(feature = "keys")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use util;
-use {raw, CheckResult, Error, ErrorCode, KnownHostFileKind, SessionInner};
+use wire::Reader;
+use {raw, CheckResult, Error, ErrorCode, KnownHostFileKind, KnownHostKeyFormat, SessionInner};
 
 /// A set of known hosts which can be used to verify the identity of a remote
 /// server.
@@ -36,7 +53,8 @@ use {raw, CheckResult, Error, ErrorCode, KnownHostFileKind, SessionInner};
 ///         CheckResult::Mismatch => {
 ///             panic!("host mismatch, man in the middle attack?!")
 ///         }
-///         CheckResult::Failure => panic!("failed to check the known hosts"),
+///         CheckResult::Revoked => panic!("the host key has been revoked"),
+///         _ => panic!("failed to check the known hosts"),
 ///     }
 ///
 ///     println!("adding {} to the known hosts", host);
@@ -45,9 +63,47 @@ use {raw, CheckResult, Error, ErrorCode, KnownHostFileKind, SessionInner};
 ///     known_hosts.write_file(&file, KnownHostFileKind::OpenSSH).unwrap();
 /// }
 /// ```
+///
+/// # Markers
+///
+/// libssh2 doesn't understand the `@cert-authority` and `@revoked` markers
+/// of OpenSSH known hosts files, so lines carrying them are kept separately
+/// and honored by `check` and `check_port`:
+///
+/// * A key listed as `@revoked` for a host is reported as
+///   `CheckResult::Revoked`, even if it is also listed as a regular entry.
+/// * A host certificate signed by a key listed as `@cert-authority` for the
+///   host is accepted if it is a host certificate, currently valid, and lists
+///   the host among its principals (or lists none). This requires the `keys`
+///   feature; without it certificates are only ever compared to revoked and
+///   regular entries.
+///
+/// Marker lines are written back by `write_file`, after the other entries.
 pub struct KnownHosts {
     raw: *mut raw::LIBSSH2_KNOWNHOSTS,
     sess: Arc<Mutex<SessionInner>>,
+    markers: RefCell<Vec<Marker>>,
+    // Where the entries came from, keyed by the line libssh2 writes for them:
+    // the nth entry writing a line has the nth origin listed for the line,
+    // which is `None` unless the entry was read by `read_file`.
+    origins: RefCell<HashMap<String, Vec<Option<Origin>>>>,
+}
+
+/// A known hosts line with a marker, which libssh2 would reject.
+struct Marker {
+    marker: KnownHostMarker,
+    patterns: String,
+    key_type: String,
+    key: Vec<u8>,
+    comment: Option<String>,
+    origin: Option<Origin>,
+}
+
+/// The file and line number an entry was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Origin {
+    file: Arc<Path>,
+    line: usize,
 }
 
 /// Structure representing a known host as part of a `KnownHosts` structure.
@@ -55,6 +111,30 @@ pub struct KnownHosts {
 pub struct Host {
     name: Option<String>,
     key: String,
+    hashed: bool,
+    patterns: Vec<HostPattern>,
+    format: KnownHostKeyFormat,
+    comment: Option<String>,
+    marker: Option<KnownHostMarker>,
+    origin: Option<Origin>,
+}
+
+/// One of the comma separated host name patterns of a known hosts entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostPattern {
+    host: String,
+    port: Option<u16>,
+    negated: bool,
+}
+
+/// The marker at the start of a known hosts line.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KnownHostMarker {
+    /// `@cert-authority`: the key is a certificate authority trusted to sign
+    /// the host keys of the matching hosts.
+    CertAuthority,
+    /// `@revoked`: the key must never be accepted for the matching hosts.
+    Revoked,
 }
 
 impl KnownHosts {
@@ -69,27 +149,67 @@ impl KnownHosts {
             Ok(Self {
                 raw,
                 sess: Arc::clone(sess),
+                markers: RefCell::new(Vec::new()),
+                origins: RefCell::new(HashMap::new()),
             })
         }
     }
 
     /// Reads a collection of known hosts from a specified file and adds them to
     /// the collection of known hosts.
+    ///
+    /// The file and line each entry was read from are available from
+    /// `Host::source` and `Host::line`.
     pub fn read_file(&mut self, file: &Path, kind: KnownHostFileKind) -> Result<u32, Error> {
-        let file = CString::new(util::path2bytes(file)?)?;
+        // Read the file line by line rather than with libssh2_knownhost_readfile,
+        // which gives up at the first marker line.
+        let contents = fs::read(file).map_err(|_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_FILE),
+                "Failed to open file",
+            )
+        })?;
+        let contents = String::from_utf8_lossy(&contents);
+        let file: Arc<Path> = Arc::from(file);
         let sess = self.sess.lock();
-        let n = unsafe { raw::libssh2_knownhost_readfile(self.raw, file.as_ptr(), kind as c_int) };
-        if n < 0 {
-            sess.rc(n)?
+        let mut tail = self.raw_entries(&sess)?.pop().unwrap_or(null_mut());
+        let mut n = 0;
+        for (i, line) in contents.lines().enumerate() {
+            let origin = Origin {
+                file: Arc::clone(&file),
+                line: i + 1,
+            };
+            self.read_line(&sess, line, kind, Some(origin), &mut tail)?;
+            n += 1;
         }
-        Ok(n as u32)
+        Ok(n)
     }
 
     /// Read a line as if it were from a known hosts file.
     pub fn read_str(&mut self, s: &str, kind: KnownHostFileKind) -> Result<(), Error> {
+        let sess = self.sess.lock();
+        let mut tail = self.raw_entries(&sess)?.pop().unwrap_or(null_mut());
+        self.read_line(&sess, s, kind, None, &mut tail)
+    }
+
+    /// Reads one line, recording `origin` as the origin of the entries it
+    /// adds after `tail`, the last entry of the list, which is updated.
+    fn read_line(
+        &self,
+        sess: &MutexGuard<SessionInner>,
+        s: &str,
+        kind: KnownHostFileKind,
+        origin: Option<Origin>,
+        tail: &mut *mut raw::libssh2_knownhost,
+    ) -> Result<(), Error> {
+        if s.trim_start().starts_with('@') {
+            let mut marker = Marker::parse(s)?;
+            marker.origin = origin;
+            self.markers.borrow_mut().push(marker);
+            return Ok(());
+        }
         let s = CString::new(s)?;
         let s = s.as_bytes();
-        let sess = self.sess.lock();
         sess.rc(unsafe {
             raw::libssh2_knownhost_readline(
                 self.raw,
@@ -97,29 +217,68 @@ impl KnownHosts {
                 s.len() as size_t,
                 kind as c_int,
             )
-        })
+        })?;
+        let mut next = null_mut();
+        while unsafe { raw::libssh2_knownhost_get(self.raw, &mut next, *tail) } == 0 {
+            let line = self.write_line(sess, next, KnownHostFileKind::OpenSSH)?;
+            self.insert_origin(line, usize::MAX, origin.clone());
+            *tail = next;
+        }
+        Ok(())
     }
 
     /// Writes all the known hosts to the specified file using the specified
     /// file format.
     pub fn write_file(&self, file: &Path, kind: KnownHostFileKind) -> Result<(), Error> {
+        let path = file;
         let file = CString::new(util::path2bytes(file)?)?;
         let sess = self.sess.lock();
         let n = unsafe { raw::libssh2_knownhost_writefile(self.raw, file.as_ptr(), kind as c_int) };
-        sess.rc(n)
+        sess.rc(n)?;
+        let markers = self.markers.borrow();
+        if markers.is_empty() {
+            return Ok(());
+        }
+        let mut lines = String::new();
+        for marker in markers.iter() {
+            lines.push_str(&marker.to_line());
+        }
+        OpenOptions::new()
+            .append(true)
+            .open(path)
+            .and_then(|mut f| f.write_all(lines.as_bytes()))
+            .map_err(|_| {
+                Error::new(
+                    ErrorCode::Session(raw::LIBSSH2_ERROR_FILE),
+                    "Failed to write marker lines to file",
+                )
+            })
     }
 
     /// Converts a single known host to a single line of output for storage,
     /// using the 'type' output format.
     pub fn write_string(&self, host: &Host, kind: KnownHostFileKind) -> Result<String, Error> {
-        let mut v = Vec::with_capacity(128);
+        if host.marker.is_some() {
+            let markers = self.markers.borrow();
+            return match markers.iter().find(|m| host.is_marker(m)) {
+                Some(marker) => Ok(marker.to_line()),
+                None => Err(not_found()),
+            };
+        }
         let sess = self.sess.lock();
-        let raw_host = self.resolve_to_raw_host(&sess, host)?.ok_or_else(|| {
-            Error::new(
-                ErrorCode::Session(raw::LIBSSH2_ERROR_BAD_USE),
-                "Host is not in the set of known hosts",
-            )
-        })?;
+        let raw_host = self
+            .resolve_to_raw_host(&sess, host)?
+            .ok_or_else(not_found)?;
+        self.write_line(&sess, raw_host, kind)
+    }
+
+    fn write_line(
+        &self,
+        sess: &MutexGuard<SessionInner>,
+        raw_host: *mut raw::libssh2_knownhost,
+        kind: KnownHostFileKind,
+    ) -> Result<String, Error> {
+        let mut v = Vec::with_capacity(128);
         loop {
             let mut outlen = 0;
             unsafe {
@@ -150,24 +309,62 @@ impl KnownHosts {
     }
 
     /// Retrieves the list of known hosts
+    ///
+    /// Entries with a marker come after all the others.
     pub fn hosts(&self) -> Result<Vec<Host>, Error> {
-        let mut next = null_mut();
-        let mut prev = null_mut();
         let sess = self.sess.lock();
+        let entries = self.raw_entries(&sess)?;
         let mut hosts = vec![];
+        for (&raw_host, (line, origin)) in entries.iter().zip(self.origins_of(&sess, &entries)?) {
+            let mut host = unsafe { Host::from_raw(raw_host) };
+            host.comment = line_comment(&line, 3);
+            host.origin = origin;
+            hosts.push(host);
+        }
+        hosts.extend(self.markers.borrow().iter().map(Marker::to_host));
+        Ok(hosts)
+    }
+
+    /// Returns the line written for each entry, with the number of the line
+    /// it was read from. Entries with a marker come after all the others.
+    pub(crate) fn lines(&self) -> Result<Vec<(Option<usize>, String)>, Error> {
+        let sess = self.sess.lock();
+        let entries = self.raw_entries(&sess)?;
+        let mut lines = self
+            .origins_of(&sess, &entries)?
+            .into_iter()
+            .map(|(line, origin)| (origin.map(|o| o.line), line))
+            .collect::<Vec<_>>();
+        lines.extend(
+            self.markers
+                .borrow()
+                .iter()
+                .map(|m| (m.origin.as_ref().map(|o| o.line), m.to_line())),
+        );
+        Ok(lines)
+    }
+
+    /// Returns all the entries libssh2 holds, in order.
+    /// The returned values are only valid while the session is locked.
+    fn raw_entries(
+        &self,
+        sess: &MutexGuard<SessionInner>,
+    ) -> Result<Vec<*mut raw::libssh2_knownhost>, Error> {
+        let mut next = null_mut();
+        let mut prev = null_mut();
+        let mut entries = vec![];
 
         loop {
             match unsafe { raw::libssh2_knownhost_get(self.raw, &mut next, prev) } {
                 0 => {
                     prev = next;
-                    hosts.push(unsafe { Host::from_raw(next) });
+                    entries.push(next);
                 }
                 1 => break,
                 rc => return Err(Error::from_session_error_raw(sess.raw, rc)),
             }
         }
-
-        Ok(hosts)
+        Ok(entries)
     }
 
     /// Given a Host object, find the matching raw node in the internal list.
@@ -177,34 +374,255 @@ impl KnownHosts {
         sess: &MutexGuard<SessionInner>,
         host: &Host,
     ) -> Result<Option<*mut raw::libssh2_knownhost>, Error> {
-        let mut next = null_mut();
-        let mut prev = null_mut();
-
-        loop {
-            match unsafe { raw::libssh2_knownhost_get(self.raw, &mut next, prev) } {
-                0 => {
-                    prev = next;
-                    let current = unsafe { Host::from_raw(next) };
-                    if current == *host {
-                        return Ok(Some(next));
-                    }
-                }
-                1 => break,
-                rc => return Err(Error::from_session_error_raw(sess.raw, rc)),
-            }
+        if host.marker.is_some() {
+            return Ok(None);
         }
-        Ok(None)
+        Ok(self
+            .raw_entries(sess)?
+            .into_iter()
+            .find(|&raw_host| unsafe { Host::from_raw(raw_host) }.is_entry(host)))
     }
 
     /// Delete a known host entry from the collection of known hosts.
     pub fn remove(&self, host: &Host) -> Result<(), Error> {
+        if host.marker.is_some() {
+            self.markers.borrow_mut().retain(|m| !host.is_marker(m));
+            return Ok(());
+        }
+
         let sess = self.sess.lock();
+        let entries = self.raw_entries(&sess)?;
+        let found = entries
+            .iter()
+            .position(|&raw_host| unsafe { Host::from_raw(raw_host) }.is_entry(host));
+        if let Some(index) = found {
+            let (line, n) = self.occurrence(&sess, &entries, index)?;
+            sess.rc(unsafe { raw::libssh2_knownhost_del(self.raw, entries[index]) })?;
+            let _ = self.remove_origin(&line, n);
+        }
+        Ok(())
+    }
+
+    /// Returns the line written for each of `entries`, which must be all the
+    /// entries in order, and where it came from.
+    fn origins_of(
+        &self,
+        sess: &MutexGuard<SessionInner>,
+        entries: &[*mut raw::libssh2_knownhost],
+    ) -> Result<Vec<(String, Option<Origin>)>, Error> {
+        let origins = self.origins.borrow();
+        let mut seen = HashMap::new();
+        let mut out = Vec::with_capacity(entries.len());
+        for &raw_host in entries {
+            let line = self.write_line(sess, raw_host, KnownHostFileKind::OpenSSH)?;
+            let n = seen.entry(line.clone()).or_insert(0);
+            let origin = origins.get(&line).and_then(|o| o.get(*n)).cloned();
+            *n += 1;
+            out.push((line, origin.unwrap_or(None)));
+        }
+        Ok(out)
+    }
+
+    /// Returns the line written for `entries[index]` and how many of the
+    /// entries before it write the same line.
+    fn occurrence(
+        &self,
+        sess: &MutexGuard<SessionInner>,
+        entries: &[*mut raw::libssh2_knownhost],
+        index: usize,
+    ) -> Result<(String, usize), Error> {
+        let line = self.write_line(sess, entries[index], KnownHostFileKind::OpenSSH)?;
+        let mut n = 0;
+        for &raw_host in &entries[..index] {
+            if self.write_line(sess, raw_host, KnownHostFileKind::OpenSSH)? == line {
+                n += 1;
+            }
+        }
+        Ok((line, n))
+    }
+
+    /// Records `origin` for the `n`th entry writing `line`, or the last one
+    /// if there are fewer.
+    fn insert_origin(&self, line: String, n: usize, origin: Option<Origin>) {
+        let mut origins = self.origins.borrow_mut();
+        let list = origins.entry(line).or_default();
+        let n = cmp::min(n, list.len());
+        list.insert(n, origin);
+    }
 
-        if let Some(raw_host) = self.resolve_to_raw_host(&sess, host)? {
-            return sess.rc(unsafe { raw::libssh2_knownhost_del(self.raw, raw_host) });
+    /// Forgets the origin of the `n`th entry writing `line`, returning it.
+    fn remove_origin(&self, line: &str, n: usize) -> Option<Origin> {
+        let mut origins = self.origins.borrow_mut();
+        let (origin, empty) = match origins.get_mut(line) {
+            Some(list) if n < list.len() => (list.remove(n), list.is_empty()),
+            _ => return None,
+        };
+        if empty {
+            let _ = origins.remove(line);
+        }
+        origin
+    }
+
+    /// Replaces the key of an entry, keeping its host names, comment and
+    /// position, for when a host's key has been rotated.
+    ///
+    /// The type of the key is taken from the key itself, which must be the
+    /// raw key for the host.
+    pub fn update_key(&mut self, host: &Host, key: &[u8]) -> Result<(), Error> {
+        self.rewrite(host, |line| {
+            let mut fields = line.split_whitespace();
+            let marker = if host.marker.is_some() {
+                fields.next()
+            } else {
+                None
+            };
+            let name = fields.next().unwrap_or("");
+            format_line(
+                marker,
+                name,
+                key,
+                line_comment(line, 3 + marker.is_some() as usize),
+            )
+        })
+    }
+
+    /// Replaces an entry with one for `host`, `key` and `comment`, keeping
+    /// its position and marker. If the entry's name was hashed, `host` is
+    /// hashed too.
+    ///
+    /// The host is given as for `add`, and the type of the key is taken from
+    /// the key itself, which must be the raw key for the host.
+    pub fn replace(
+        &mut self,
+        old: &Host,
+        host: &str,
+        key: &[u8],
+        comment: &str,
+    ) -> Result<(), Error> {
+        let name = if old.hashed && old.marker.is_none() {
+            let (salt, hash) = hash_host(host)?;
+            format!("|1|{}|{}", salt, hash)
         } else {
-            Ok(())
+            host.to_string()
+        };
+        let comment = if comment.is_empty() {
+            None
+        } else {
+            Some(comment.to_string())
+        };
+        let marker = old.marker.map(KnownHostMarker::as_str);
+        self.rewrite(old, |_| format_line(marker, &name, key, comment.clone()))
+    }
+
+    /// Replaces the line of `host` with the one `build` makes from it.
+    ///
+    /// libssh2 can only append entries, so the whole list is read back in
+    /// order to keep the entry in place.
+    fn rewrite<F>(&mut self, host: &Host, build: F) -> Result<(), Error>
+    where
+        F: FnOnce(&str) -> Result<String, Error>,
+    {
+        if host.marker.is_some() {
+            let mut markers = self.markers.borrow_mut();
+            let marker = markers
+                .iter_mut()
+                .find(|m| host.is_marker(m))
+                .ok_or_else(not_found)?;
+            let mut new = Marker::parse(&build(&marker.to_line())?)?;
+            new.origin = marker.origin.clone();
+            *marker = new;
+            return Ok(());
+        }
+
+        let sess = self.sess.lock();
+        let entries = self.raw_entries(&sess)?;
+        let mut lines = Vec::with_capacity(entries.len());
+        let mut target = None;
+        for &raw_host in &entries {
+            if target.is_none() && unsafe { Host::from_raw(raw_host) }.is_entry(host) {
+                target = Some(lines.len());
+            }
+            lines.push(self.write_line(&sess, raw_host, KnownHostFileKind::OpenSSH)?);
+        }
+        let target = target.ok_or_else(not_found)?;
+        let new = build(&lines[target])?;
+
+        // Make sure libssh2 accepts the new line before dropping anything.
+        unsafe {
+            let scratch = raw::libssh2_knownhost_init(sess.raw);
+            if scratch.is_null() {
+                return Err(Error::unknown());
+            }
+            let rc = raw::libssh2_knownhost_readline(
+                scratch,
+                new.as_ptr() as *const _,
+                new.len() as size_t,
+                KnownHostFileKind::OpenSSH as c_int,
+            );
+            raw::libssh2_knownhost_free(scratch);
+            sess.rc(rc)?;
+        }
+        let old = mem::replace(&mut lines[target], new);
+        let n = lines[..target].iter().filter(|&line| *line == old).count();
+
+        for &raw_host in &entries {
+            sess.rc(unsafe { raw::libssh2_knownhost_del(self.raw, raw_host) })?;
+        }
+        for line in &lines {
+            sess.rc(unsafe {
+                raw::libssh2_knownhost_readline(
+                    self.raw,
+                    line.as_ptr() as *const _,
+                    line.len() as size_t,
+                    KnownHostFileKind::OpenSSH as c_int,
+                )
+            })?;
         }
+
+        let origin = self.remove_origin(&old, n);
+        let (line, n) = self.occurrence(&sess, &self.raw_entries(&sess)?, target)?;
+        self.insert_origin(line, n, origin);
+        Ok(())
+    }
+
+    /// Returns the host key algorithms for the keys known for `host`, so that
+    /// they can be preferred during the handshake, see
+    /// `Session::prefer_known_host_keys`.
+    ///
+    /// Certificate algorithms come first if a `@cert-authority` entry covers
+    /// the host, followed by the algorithms for the host's own keys, in the
+    /// order of the entries.
+    pub fn host_key_algorithms(&self, host: &str, port: u16) -> Result<Vec<&'static str>, Error> {
+        let name = if port != 22 {
+            format!("[{}]:{}", host, port)
+        } else {
+            host.to_string()
+        };
+        let mut algorithms = Vec::new();
+        let mut add = |new: &[&'static str]| {
+            for alg in new {
+                if !algorithms.contains(alg) {
+                    algorithms.push(*alg);
+                }
+            }
+        };
+        for marker in self.markers.borrow().iter() {
+            if marker.marker == KnownHostMarker::CertAuthority
+                && host_matches(&marker.patterns, &name)
+            {
+                add(cert_algorithms(key_format_from_name(&marker.key_type)));
+            }
+        }
+        let sess = self.sess.lock();
+        for raw_host in self.raw_entries(&sess)? {
+            // Hashed names are only available from the entry's line.
+            let line = self.write_line(&sess, raw_host, KnownHostFileKind::OpenSSH)?;
+            let patterns = line.split_whitespace().next().unwrap_or("");
+            if host_matches(patterns, &name) {
+                add(key_algorithms(unsafe { Host::from_raw(raw_host) }.format));
+            }
+        }
+        Ok(algorithms)
     }
 
     /// Checks a host and its associated key against the collection of known
@@ -222,6 +640,29 @@ impl KnownHosts {
     }
 
     fn check_port_(&self, host: &str, port: i32, key: &[u8]) -> CheckResult {
+        // Names of hosts on non-standard ports are written as `[host]:port`.
+        let name = if port >= 0 && port != 22 {
+            format!("[{}]:{}", host, port)
+        } else {
+            host.to_string()
+        };
+        if self.is_revoked(&name, key) {
+            return CheckResult::Revoked;
+        }
+        #[cfg(feature = "keys")]
+        {
+            let is_cert = Reader::new(key)
+                .str()
+                .map(|alg| alg.ends_with("-cert-v01@openssh.com"))
+                .unwrap_or(false);
+            if is_cert {
+                return self.check_certificate(host, port, &name, key);
+            }
+        }
+        self.check_raw(host, port, key)
+    }
+
+    fn check_raw(&self, host: &str, port: i32, key: &[u8]) -> CheckResult {
         let host = CString::new(host).unwrap();
         let flags = raw::LIBSSH2_KNOWNHOST_TYPE_PLAIN | raw::LIBSSH2_KNOWNHOST_KEYENC_RAW;
         unsafe {
@@ -243,6 +684,61 @@ impl KnownHosts {
         }
     }
 
+    fn is_revoked(&self, name: &str, key: &[u8]) -> bool {
+        self.markers.borrow().iter().any(|m| {
+            m.marker == KnownHostMarker::Revoked && m.key == key && host_matches(&m.patterns, name)
+        })
+    }
+
+    /// Checks a host certificate against the `@cert-authority` entries for
+    /// the host. If there are none, the certified key is checked as a plain
+    /// key instead, as OpenSSH does.
+    #[cfg(feature = "keys")]
+    fn check_certificate(&self, host: &str, port: i32, name: &str, key: &[u8]) -> CheckResult {
+        let cert = match Certificate::from_bytes(key) {
+            Ok(cert) => cert,
+            Err(_) => return CheckResult::Failure,
+        };
+        let mut certified = Vec::new();
+        let mut authority = Vec::new();
+        if cert.public_key().encode(&mut certified).is_err()
+            || cert.signature_key().encode(&mut authority).is_err()
+        {
+            return CheckResult::Failure;
+        }
+        if self.is_revoked(name, &certified) || self.is_revoked(name, &authority) {
+            return CheckResult::Revoked;
+        }
+
+        let authorities = self
+            .markers
+            .borrow()
+            .iter()
+            .filter(|m| m.marker == KnownHostMarker::CertAuthority)
+            .filter(|m| host_matches(&m.patterns, name))
+            .filter_map(|m| KeyData::decode(&mut &m.key[..]).ok())
+            .map(|k| k.fingerprint(HashAlg::Sha256))
+            .collect::<Vec<_>>();
+        if authorities.is_empty() {
+            return self.check_raw(host, port, &certified);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let principals = cert.valid_principals();
+        let valid = cert.validate_at(now, &authorities).is_ok()
+            && cert.cert_type() == CertType::Host
+            && cert.critical_options().is_empty()
+            && (principals.is_empty() || principals.iter().any(|p| p.eq_ignore_ascii_case(host)));
+        if valid {
+            CheckResult::Match
+        } else {
+            CheckResult::Mismatch
+        }
+    }
+
     /// Adds a known host to the collection of known hosts.
     ///
     /// The host is the host name in plain text. The host name can be the IP
@@ -261,27 +757,340 @@ impl KnownHosts {
         fmt: ::KnownHostKeyFormat,
     ) -> Result<(), Error> {
         let host = CString::new(host)?;
+        let flags = raw::LIBSSH2_KNOWNHOST_TYPE_PLAIN;
+        self.add_(&host, None, key, comment, flags | (fmt as c_int))
+    }
+
+    /// Same as `add`, but the host name is stored hashed, as an
+    /// `|1|salt|hash` entry like the ones written by OpenSSH when
+    /// `HashKnownHosts` is enabled.
+    ///
+    /// A fresh random salt is used for each entry, so the host name can't be
+    /// recovered from the file, yet `check` still finds the entry.
+    pub fn add_hashed(
+        &mut self,
+        host: &str,
+        key: &[u8],
+        comment: &str,
+        fmt: ::KnownHostKeyFormat,
+    ) -> Result<(), Error> {
+        let (salt, hash) = hash_host(host)?;
+        let salt = CString::new(salt)?;
+        let hash = CString::new(hash)?;
+        let flags = raw::LIBSSH2_KNOWNHOST_TYPE_SHA1;
+        self.add_(&hash, Some(&salt), key, comment, flags | (fmt as c_int))
+    }
+
+    fn add_(
+        &mut self,
+        host: &CString,
+        salt: Option<&CString>,
+        key: &[u8],
+        comment: &str,
+        flags: c_int,
+    ) -> Result<(), Error> {
         let comment = CString::new(comment)?;
-        let flags =
-            raw::LIBSSH2_KNOWNHOST_TYPE_PLAIN | raw::LIBSSH2_KNOWNHOST_KEYENC_RAW | (fmt as c_int);
+        let flags = flags | raw::LIBSSH2_KNOWNHOST_KEYENC_RAW;
         let sess = self.sess.lock();
-        unsafe {
-            let rc = raw::libssh2_knownhost_addc(
+        let mut entry = null_mut();
+        sess.rc(unsafe {
+            raw::libssh2_knownhost_addc(
                 self.raw,
                 host.as_ptr() as *mut _,
-                null_mut(),
+                salt.map(|s| s.as_ptr() as *mut _).unwrap_or(null_mut()),
                 key.as_ptr() as *mut _,
                 key.len() as size_t,
                 comment.as_ptr() as *const _,
                 comment.as_bytes().len() as size_t,
                 flags,
-                null_mut(),
-            );
-            sess.rc(rc)
+                &mut entry,
+            )
+        })?;
+        let line = self.write_line(&sess, entry, KnownHostFileKind::OpenSSH)?;
+        self.insert_origin(line, usize::MAX, None);
+        Ok(())
+    }
+
+    /// Hashes the host names of the OpenSSH known hosts file at `file` in
+    /// place, the way `ssh-keygen -H` does, returning the number of names
+    /// hashed.
+    ///
+    /// An entry listing several names is split into one hashed entry per
+    /// name. Comments, entries which are already hashed, entries with a
+    /// marker such as `@cert-authority`, and entries using wildcards or
+    /// negated patterns are left as they are. The original file is kept
+    /// alongside with a `.old` suffix.
+    pub fn hash_file(file: &Path) -> Result<u32, Error> {
+        let contents = fs::read_to_string(file).map_err(|_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_FILE),
+                "unable to read the known hosts file",
+            )
+        })?;
+        let mut out = String::with_capacity(contents.len() * 2);
+        let mut hashed = 0;
+        for line in contents.lines() {
+            let entry = line.trim_start();
+            let names = entry.split_whitespace().next().unwrap_or("");
+            let unhashable =
+                |name: &str| name.starts_with('!') || name.contains('*') || name.contains('?');
+            if entry.is_empty()
+                || entry.starts_with('#')
+                || entry.starts_with('@')
+                || names.starts_with("|1|")
+                || names.split(',').any(unhashable)
+            {
+                out.push_str(line);
+                out.push('\n');
+                continue;
+            }
+            let rest = &entry[names.len()..];
+            for name in names.split(',').filter(|name| !name.is_empty()) {
+                let (salt, hash) = hash_host(name)?;
+                out.push_str(&format!("|1|{}|{}{}\n", salt, hash, rest));
+                hashed += 1;
+            }
+        }
+
+        let write_err = |_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_FILE),
+                "unable to write the known hosts file",
+            )
+        };
+        let tmp = util::with_suffix(file, ".tmp");
+        fs::write(&tmp, out).map_err(write_err)?;
+        fs::rename(file, util::with_suffix(file, ".old")).map_err(write_err)?;
+        fs::rename(&tmp, file).map_err(write_err)?;
+        Ok(hashed)
+    }
+}
+
+/// Returns the base64 encoded salt and HMAC-SHA1 of `host` for an `|1|`
+/// hashed known hosts entry.
+fn hash_host(host: &str) -> Result<(String, String), Error> {
+    let mut salt = [0u8; 20];
+    getrandom::getrandom(&mut salt).map_err(|_| {
+        Error::new(
+            ErrorCode::Session(raw::LIBSSH2_ERROR_RANDGEN),
+            "unable to generate a salt for a hashed host name",
+        )
+    })?;
+    let mut mac =
+        <Hmac<Sha1> as Mac>::new_from_slice(&salt).expect("HMAC accepts keys of any length");
+    mac.update(host.as_bytes());
+    let hash = mac.finalize().into_bytes();
+    Ok((
+        util::base64_encode(&salt, true),
+        util::base64_encode(&hash, true),
+    ))
+}
+
+/// Matches a host name against the comma separated patterns of a known hosts
+/// line, which may use `*` and `?` wildcards, `!` negation and hashing.
+fn host_matches(patterns: &str, name: &str) -> bool {
+    let name = name.to_lowercase();
+    let mut matched = false;
+    for pattern in patterns.split(',') {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let is_match = if pattern.starts_with("|1|") {
+            hashed_host_matches(pattern, &name)
+        } else {
+            wildcard_match(pattern.to_lowercase().as_bytes(), name.as_bytes())
+        };
+        if is_match && negated {
+            return false;
+        }
+        matched |= is_match;
+    }
+    matched
+}
+
+fn hashed_host_matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern[3..].splitn(2, '|');
+    let salt = parts.next().and_then(util::base64_decode);
+    let hash = parts.next().and_then(util::base64_decode);
+    match (salt, hash) {
+        (Some(salt), Some(hash)) => {
+            let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(&salt)
+                .expect("HMAC accepts keys of any length");
+            mac.update(name.as_bytes());
+            mac.verify_slice(&hash).is_ok()
+        }
+        _ => false,
+    }
+}
+
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((&b'*', rest)) => (0..=name.len()).any(|i| wildcard_match(rest, &name[i..])),
+        Some((&b'?', rest)) => !name.is_empty() && wildcard_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && wildcard_match(rest, &name[1..]),
+    }
+}
+
+impl Marker {
+    fn parse(line: &str) -> Result<Marker, Error> {
+        let err = |msg| Error::new(ErrorCode::Session(raw::LIBSSH2_ERROR_KNOWN_HOSTS), msg);
+        let mut fields = line.split_whitespace();
+        let marker = match fields.next() {
+            Some("@revoked") => KnownHostMarker::Revoked,
+            Some("@cert-authority") => KnownHostMarker::CertAuthority,
+            _ => return Err(err("Unknown marker in known hosts line")),
+        };
+        let patterns = fields.next();
+        let key_type = fields.next();
+        let key = fields.next().and_then(util::base64_decode);
+        match (patterns, key_type, key) {
+            (Some(patterns), Some(key_type), Some(key)) => Ok(Marker {
+                marker,
+                patterns: patterns.to_string(),
+                key_type: key_type.to_string(),
+                key,
+                comment: line_comment(line, 4),
+                origin: None,
+            }),
+            _ => Err(err("Failed to parse known hosts line")),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        let mut line = format!(
+            "{} {} {} {}",
+            self.marker.as_str(),
+            self.patterns,
+            self.key_type,
+            util::base64_encode(&self.key, true)
+        );
+        if let Some(ref comment) = self.comment {
+            line.push(' ');
+            line.push_str(comment);
         }
+        line.push('\n');
+        line
+    }
+
+    fn to_host(&self) -> Host {
+        let hashed = self.patterns.starts_with("|1|");
+        Host {
+            name: Some(self.patterns.clone()),
+            key: util::base64_encode(&self.key, true),
+            hashed,
+            patterns: if hashed {
+                Vec::new()
+            } else {
+                HostPattern::parse_list(&self.patterns)
+            },
+            format: key_format_from_name(&self.key_type),
+            comment: self.comment.clone(),
+            marker: Some(self.marker),
+            origin: self.origin.clone(),
+        }
+    }
+}
+
+/// Returns what follows the first `fields` fields of a known hosts line.
+fn line_comment(line: &str, fields: usize) -> Option<String> {
+    let mut rest = line.trim();
+    for _ in 0..fields {
+        rest = rest.trim_start();
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = &rest[end..];
+    }
+    let rest = rest.trim();
+    if rest.is_empty() {
+        None
+    } else {
+        Some(rest.to_string())
+    }
+}
+
+/// Builds a known hosts line, taking the key type from the key itself.
+fn format_line(
+    marker: Option<&str>,
+    name: &str,
+    key: &[u8],
+    comment: Option<String>,
+) -> Result<String, Error> {
+    let key_type = Reader::new(key).str().map_err(|_| {
+        Error::new(
+            ErrorCode::Session(raw::LIBSSH2_ERROR_INVAL),
+            "key is not a valid SSH public key",
+        )
+    })?;
+    let mut line = String::new();
+    if let Some(marker) = marker {
+        line.push_str(marker);
+        line.push(' ');
+    }
+    line.push_str(&format!(
+        "{} {} {}",
+        name,
+        key_type,
+        util::base64_encode(key, true)
+    ));
+    if let Some(comment) = comment {
+        line.push(' ');
+        line.push_str(&comment);
+    }
+    line.push('\n');
+    Ok(line)
+}
+
+fn key_format_from_name(name: &str) -> KnownHostKeyFormat {
+    match name {
+        "ssh-rsa" => KnownHostKeyFormat::SshRsa,
+        "ssh-dss" => KnownHostKeyFormat::SshDss,
+        "ecdsa-sha2-nistp256" => KnownHostKeyFormat::Ecdsa256,
+        "ecdsa-sha2-nistp384" => KnownHostKeyFormat::Ecdsa384,
+        "ecdsa-sha2-nistp521" => KnownHostKeyFormat::Ecdsa521,
+        "ssh-ed25519" => KnownHostKeyFormat::Ed25519,
+        _ => KnownHostKeyFormat::Unknown,
+    }
+}
+
+/// The host key algorithms for keys of type `format`, most preferred first.
+fn key_algorithms(format: KnownHostKeyFormat) -> &'static [&'static str] {
+    match format {
+        KnownHostKeyFormat::SshRsa => &["rsa-sha2-512", "rsa-sha2-256", "ssh-rsa"],
+        KnownHostKeyFormat::SshDss => &["ssh-dss"],
+        KnownHostKeyFormat::Ecdsa256 => &["ecdsa-sha2-nistp256"],
+        KnownHostKeyFormat::Ecdsa384 => &["ecdsa-sha2-nistp384"],
+        KnownHostKeyFormat::Ecdsa521 => &["ecdsa-sha2-nistp521"],
+        KnownHostKeyFormat::Ed25519 => &["ssh-ed25519"],
+        KnownHostKeyFormat::Rsa1 | KnownHostKeyFormat::Unknown => &[],
     }
 }
 
+/// The host certificate algorithms for certificate authorities with keys of
+/// type `format`, most preferred first.
+fn cert_algorithms(format: KnownHostKeyFormat) -> &'static [&'static str] {
+    match format {
+        KnownHostKeyFormat::SshRsa => &[
+            "rsa-sha2-512-cert-v01@openssh.com",
+            "rsa-sha2-256-cert-v01@openssh.com",
+            "ssh-rsa-cert-v01@openssh.com",
+        ],
+        KnownHostKeyFormat::SshDss => &["ssh-dss-cert-v01@openssh.com"],
+        KnownHostKeyFormat::Ecdsa256 => &["ecdsa-sha2-nistp256-cert-v01@openssh.com"],
+        KnownHostKeyFormat::Ecdsa384 => &["ecdsa-sha2-nistp384-cert-v01@openssh.com"],
+        KnownHostKeyFormat::Ecdsa521 => &["ecdsa-sha2-nistp521-cert-v01@openssh.com"],
+        KnownHostKeyFormat::Ed25519 => &["ssh-ed25519-cert-v01@openssh.com"],
+        KnownHostKeyFormat::Rsa1 | KnownHostKeyFormat::Unknown => &[],
+    }
+}
+
+fn not_found() -> Error {
+    Error::new(
+        ErrorCode::Session(raw::LIBSSH2_ERROR_BAD_USE),
+        "Host is not in the set of known hosts",
+    )
+}
+
 impl Drop for KnownHosts {
     fn drop(&mut self) {
         let _sess = self.sess.lock();
@@ -291,6 +1100,9 @@ impl Drop for KnownHosts {
 
 impl Host {
     /// This is `None` if no plain text host name exists.
+    ///
+    /// For entries with several names, such as those with a marker, this is
+    /// the comma separated list of names.
     pub fn name(&self) -> Option<&str> {
         self.name.as_ref().map(String::as_str)
     }
@@ -300,10 +1112,155 @@ impl Host {
         &self.key
     }
 
+    /// Returns the raw key.
+    pub fn key_bytes(&self) -> Vec<u8> {
+        util::base64_decode(&self.key).unwrap_or_default()
+    }
+
+    /// Returns the type of the key.
+    pub fn key_format(&self) -> KnownHostKeyFormat {
+        self.format
+    }
+
+    /// Returns whether the host name is stored hashed, in which case `name`
+    /// returns `None`.
+    pub fn is_hashed(&self) -> bool {
+        self.hashed
+    }
+
+    /// Returns the parsed host name patterns, which is empty if the name is
+    /// hashed.
+    ///
+    /// libssh2 splits lines listing several names into one entry per name,
+    /// so only entries with a marker have more than one pattern.
+    pub fn patterns(&self) -> &[HostPattern] {
+        &self.patterns
+    }
+
+    /// Returns the comment at the end of the entry, if any.
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    /// Returns the marker of the entry, if any.
+    pub fn marker(&self) -> Option<KnownHostMarker> {
+        self.marker
+    }
+
+    /// Returns the line number, starting at 1, of the entry in the file it
+    /// was read from with `KnownHosts::read_file`.
+    pub fn line(&self) -> Option<usize> {
+        self.origin.as_ref().map(|o| o.line)
+    }
+
+    /// Returns the file the entry was read from with `KnownHosts::read_file`.
+    pub fn source(&self) -> Option<&Path> {
+        self.origin.as_ref().map(|o| &*o.file)
+    }
+
     unsafe fn from_raw(raw: *mut raw::libssh2_knownhost) -> Self {
         let name = ::opt_bytes(&raw, (*raw).name).and_then(|s| String::from_utf8(s.to_vec()).ok());
         let key = ::opt_bytes(&raw, (*raw).key).unwrap();
         let key = String::from_utf8(key.to_vec()).unwrap();
-        Self { name, key }
+        let hashed =
+            (*raw).typemask & raw::LIBSSH2_KNOWNHOST_TYPE_MASK == raw::LIBSSH2_KNOWNHOST_TYPE_SHA1;
+        let format = match (*raw).typemask & raw::LIBSSH2_KNOWNHOST_KEY_MASK {
+            raw::LIBSSH2_KNOWNHOST_KEY_RSA1 => KnownHostKeyFormat::Rsa1,
+            raw::LIBSSH2_KNOWNHOST_KEY_SSHRSA => KnownHostKeyFormat::SshRsa,
+            raw::LIBSSH2_KNOWNHOST_KEY_SSHDSS => KnownHostKeyFormat::SshDss,
+            raw::LIBSSH2_KNOWNHOST_KEY_ECDSA_256 => KnownHostKeyFormat::Ecdsa256,
+            raw::LIBSSH2_KNOWNHOST_KEY_ECDSA_384 => KnownHostKeyFormat::Ecdsa384,
+            raw::LIBSSH2_KNOWNHOST_KEY_ECDSA_521 => KnownHostKeyFormat::Ecdsa521,
+            raw::LIBSSH2_KNOWNHOST_KEY_ED25519 => KnownHostKeyFormat::Ed25519,
+            _ => KnownHostKeyFormat::Unknown,
+        };
+        let patterns = match name {
+            Some(ref name) => HostPattern::parse_list(name),
+            None => Vec::new(),
+        };
+        Self {
+            name,
+            key,
+            hashed,
+            patterns,
+            format,
+            comment: None,
+            marker: None,
+            origin: None,
+        }
+    }
+
+    /// Whether `other` describes the same libssh2 entry as this one.
+    fn is_entry(&self, other: &Host) -> bool {
+        self.name == other.name && self.key == other.key && self.format == other.format
+    }
+
+    fn is_marker(&self, marker: &Marker) -> bool {
+        self.marker == Some(marker.marker)
+            && self.name.as_ref() == Some(&marker.patterns)
+            && self.key_bytes() == marker.key
+    }
+}
+
+impl HostPattern {
+    fn parse_list(patterns: &str) -> Vec<HostPattern> {
+        patterns
+            .split(',')
+            .filter(|p| !p.is_empty())
+            .map(HostPattern::parse)
+            .collect()
+    }
+
+    fn parse(pattern: &str) -> HostPattern {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        // `[host]:port`
+        if pattern.starts_with('[') {
+            if let Some(end) = pattern.find("]:") {
+                if let Ok(port) = pattern[end + 2..].parse() {
+                    return HostPattern {
+                        host: pattern[1..end].to_string(),
+                        port: Some(port),
+                        negated,
+                    };
+                }
+            }
+        }
+        HostPattern {
+            host: pattern.to_string(),
+            port: None,
+            negated,
+        }
+    }
+
+    /// Returns the host name or address, which may contain wildcards.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Returns the port, for patterns of the form `[host]:port`.
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Returns whether this is a `!pattern`, excluding the hosts it matches.
+    pub fn is_negated(&self) -> bool {
+        self.negated
+    }
+
+    /// Returns whether the host contains `*` or `?` wildcards.
+    pub fn is_wildcard(&self) -> bool {
+        self.host.contains('*') || self.host.contains('?')
+    }
+}
+
+impl KnownHostMarker {
+    fn as_str(self) -> &'static str {
+        match self {
+            KnownHostMarker::CertAuthority => "@cert-authority",
+            KnownHostMarker::Revoked => "@revoked",
+        }
     }
 }
@@ -0,0 +1,461 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+#[cfg(unix)]
+use libc;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+
+use util;
+use {raw, CheckResult, Error, ErrorCode, Host, KnownHostFileKind, KnownHostKeyFormat};
+use {KnownHosts, Session};
+
+/// A set of known hosts files which are checked together, the way OpenSSH
+/// checks `/etc/ssh/ssh_known_hosts` as well as `~/.ssh/known_hosts`.
+///
+/// System files are only ever read. New and updated entries are written to
+/// the user file, as soon as they are made: the user file is locked, read
+/// again so that entries added by other processes in the meantime aren't
+/// lost, and atomically replaced with the new contents. Only the lines of
+/// changed entries are rewritten and new entries are appended, so comments
+/// and the order of the other lines are kept.
+///
+/// The lock is taken with `flock` on a `.lock` file next to the user file,
+/// which is removed again once the change is written. On platforms other
+/// than Unix the file isn't locked, so stores in different processes writing
+/// at the same time can lose each other's changes.
+///
+/// # Example
+///
+/// ```no_run
+/// use ssh2::{CheckResult, KnownHostsStore, Session};
+///
+/// # let sess: Session = panic!();
+/// let mut store = KnownHostsStore::openssh(&sess).unwrap();
+/// let (key, key_type) = sess.host_key().unwrap();
+/// match store.check_port("example.com", 22, key) {
+///     CheckResult::Match => {}
+///     CheckResult::NotFound => {
+///         store
+///             .add("example.com", key, "", key_type.into())
+///             .unwrap();
+///     }
+///     _ => panic!("host key verification failed"),
+/// }
+/// ```
+pub struct KnownHostsStore {
+    sess: Session,
+    files: Vec<KnownHostsFile>,
+    user: Option<usize>,
+}
+
+struct KnownHostsFile {
+    path: PathBuf,
+    hosts: KnownHosts,
+}
+
+impl KnownHostsStore {
+    /// Create a store with no files.
+    pub fn new(sess: &Session) -> Result<KnownHostsStore, Error> {
+        // Make sure a collection can be created before there's a file.
+        let _ = sess.known_hosts()?;
+        Ok(KnownHostsStore {
+            sess: sess.clone(),
+            files: Vec::new(),
+            user: None,
+        })
+    }
+
+    /// Create a store with the files OpenSSH uses by default:
+    /// `~/.ssh/known_hosts` as the user file, and `~/.ssh/known_hosts2`,
+    /// `/etc/ssh/ssh_known_hosts` and `/etc/ssh/ssh_known_hosts2` as
+    /// read-only files.
+    ///
+    /// Files which don't exist are treated as empty. If `HOME` isn't set,
+    /// the store has no user file.
+    pub fn openssh(sess: &Session) -> Result<KnownHostsStore, Error> {
+        let mut store = KnownHostsStore::new(sess)?;
+        if let Some(home) = env::var_os("HOME") {
+            let ssh = Path::new(&home).join(".ssh");
+            let _ = store
+                .user_file(&ssh.join("known_hosts"))?
+                .system_file(&ssh.join("known_hosts2"))?;
+        }
+        let _ = store
+            .system_file(Path::new("/etc/ssh/ssh_known_hosts"))?
+            .system_file(Path::new("/etc/ssh/ssh_known_hosts2"))?;
+        Ok(store)
+    }
+
+    /// Load a read-only file, such as `/etc/ssh/ssh_known_hosts`.
+    ///
+    /// A file which doesn't exist is treated as empty.
+    pub fn system_file(&mut self, path: &Path) -> Result<&mut KnownHostsStore, Error> {
+        let hosts = self.load(path)?;
+        self.files.push(KnownHostsFile {
+            path: path.to_path_buf(),
+            hosts,
+        });
+        Ok(self)
+    }
+
+    /// Load the file which new and updated entries are written to, creating
+    /// it with the first change if it doesn't exist.
+    ///
+    /// A store has a single user file. Setting another one makes the
+    /// previous one read-only.
+    pub fn user_file(&mut self, path: &Path) -> Result<&mut KnownHostsStore, Error> {
+        let _ = self.system_file(path)?;
+        self.user = Some(self.files.len() - 1);
+        Ok(self)
+    }
+
+    /// Returns the path of the user file, if there is one.
+    pub fn user_path(&self) -> Option<&Path> {
+        self.user.map(|i| &*self.files[i].path)
+    }
+
+    /// Returns the paths of all the files, in the order they were added.
+    pub fn paths(&self) -> Vec<&Path> {
+        self.files.iter().map(|f| &*f.path).collect()
+    }
+
+    /// Returns whether `host` comes from one of the read-only files, and so
+    /// can't be changed or removed through this store.
+    pub fn is_read_only(&self, host: &Host) -> bool {
+        host.source().is_none() || host.source() != self.user_path()
+    }
+
+    /// Returns the entries of all the files. `Host::source` tells which file
+    /// each came from.
+    pub fn hosts(&self) -> Result<Vec<Host>, Error> {
+        let mut hosts = Vec::new();
+        for file in &self.files {
+            hosts.extend(file.hosts.hosts()?);
+        }
+        Ok(hosts)
+    }
+
+    /// Checks a host and its key against all of the files.
+    ///
+    /// A key revoked in any file is `Revoked`. Otherwise a key matching in
+    /// any file is a `Match`, even if another file has a different key for
+    /// the host.
+    pub fn check(&self, host: &str, key: &[u8]) -> CheckResult {
+        self.check_with(|hosts| hosts.check(host, key))
+    }
+
+    /// Same as `check`, but takes a port as well.
+    pub fn check_port(&self, host: &str, port: u16, key: &[u8]) -> CheckResult {
+        self.check_with(|hosts| hosts.check_port(host, port, key))
+    }
+
+    fn check_with<F>(&self, check: F) -> CheckResult
+    where
+        F: Fn(&KnownHosts) -> CheckResult,
+    {
+        let mut result = CheckResult::NotFound;
+        for file in &self.files {
+            match check(&file.hosts) {
+                CheckResult::Revoked => return CheckResult::Revoked,
+                CheckResult::Match => result = CheckResult::Match,
+                CheckResult::Mismatch => {
+                    if let CheckResult::NotFound | CheckResult::Failure = result {
+                        result = CheckResult::Mismatch;
+                    }
+                }
+                CheckResult::Failure => {
+                    if let CheckResult::NotFound = result {
+                        result = CheckResult::Failure;
+                    }
+                }
+                CheckResult::NotFound => {}
+            }
+        }
+        result
+    }
+
+    /// Returns the host key algorithms for the keys known for `host` in any
+    /// of the files, as `KnownHosts::host_key_algorithms` does.
+    ///
+    /// Pass them to `Session::prefer_host_key_algorithms` before the
+    /// handshake.
+    pub fn host_key_algorithms(&self, host: &str, port: u16) -> Result<Vec<&'static str>, Error> {
+        let mut algorithms = Vec::new();
+        for file in &self.files {
+            for alg in file.hosts.host_key_algorithms(host, port)? {
+                if !algorithms.contains(&alg) {
+                    algorithms.push(alg);
+                }
+            }
+        }
+        Ok(algorithms)
+    }
+
+    /// Adds an entry to the user file, as `KnownHosts::add` does.
+    pub fn add(
+        &mut self,
+        host: &str,
+        key: &[u8],
+        comment: &str,
+        fmt: KnownHostKeyFormat,
+    ) -> Result<(), Error> {
+        self.modify(|hosts| hosts.add(host, key, comment, fmt))
+    }
+
+    /// Adds an entry with a hashed host name to the user file, as
+    /// `KnownHosts::add_hashed` does.
+    pub fn add_hashed(
+        &mut self,
+        host: &str,
+        key: &[u8],
+        comment: &str,
+        fmt: KnownHostKeyFormat,
+    ) -> Result<(), Error> {
+        self.modify(|hosts| hosts.add_hashed(host, key, comment, fmt))
+    }
+
+    /// Replaces the key of an entry of the user file, as
+    /// `KnownHosts::update_key` does.
+    pub fn update_key(&mut self, host: &Host, key: &[u8]) -> Result<(), Error> {
+        self.check_writable(host)?;
+        self.modify(|hosts| hosts.update_key(host, key))
+    }
+
+    /// Replaces an entry of the user file, as `KnownHosts::replace` does.
+    pub fn replace(
+        &mut self,
+        old: &Host,
+        host: &str,
+        key: &[u8],
+        comment: &str,
+    ) -> Result<(), Error> {
+        self.check_writable(old)?;
+        self.modify(|hosts| hosts.replace(old, host, key, comment))
+    }
+
+    /// Removes an entry from the user file.
+    pub fn remove(&mut self, host: &Host) -> Result<(), Error> {
+        self.check_writable(host)?;
+        self.modify(|hosts| hosts.remove(host))
+    }
+
+    fn check_writable(&self, host: &Host) -> Result<(), Error> {
+        if self.is_read_only(host) {
+            return Err(Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_BAD_USE),
+                "entry is not from the user known hosts file",
+            ));
+        }
+        Ok(())
+    }
+
+    fn load(&self, path: &Path) -> Result<KnownHosts, Error> {
+        let mut hosts = self.sess.known_hosts()?;
+        if path.exists() {
+            let _ = hosts.read_file(path, KnownHostFileKind::OpenSSH)?;
+        }
+        Ok(hosts)
+    }
+
+    /// Applies `change` to the latest contents of the user file, under the
+    /// lock, and writes them back.
+    fn modify<F>(&mut self, change: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut KnownHosts) -> Result<(), Error>,
+    {
+        let index = self.user.ok_or_else(|| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_BAD_USE),
+                "no user known hosts file to write to",
+            )
+        })?;
+        let path = self.files[index].path.clone();
+        let file_err = |msg| Error::new(ErrorCode::Session(raw::LIBSSH2_ERROR_FILE), msg);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|_| file_err("unable to create the known hosts directory"))?;
+        }
+
+        let _lock = Lock::acquire(&path)?;
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(_) => return Err(file_err("unable to read the known hosts file")),
+        };
+        let mut hosts = self.load(&path)?;
+        let before = hosts.lines()?;
+        change(&mut hosts)?;
+        let contents = patch(&contents, &before, &hosts.lines()?);
+
+        let tmp = util::with_suffix(&path, &format!(".{}.tmp", process::id()));
+        let written = fs::write(&tmp, contents)
+            .and_then(|()| {
+                if let Ok(metadata) = fs::metadata(&path) {
+                    let _ = fs::set_permissions(&tmp, metadata.permissions());
+                }
+                File::open(&tmp)
+            })
+            .and_then(|f| f.sync_all())
+            .and_then(|()| fs::rename(&tmp, &path))
+            .map_err(|_| file_err("unable to replace the known hosts file"));
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        written?;
+
+        // Read the file back, so that entries know their new line numbers.
+        self.files[index].hosts = self.load(&path)?;
+        Ok(())
+    }
+}
+
+/// Applies the difference between the entries `before` and `after` a change
+/// to `contents`, the file they were read from.
+///
+/// Each entry comes with the number of the line it was read from. A line
+/// whose entries changed is replaced by the entries it has now, entries
+/// without a line are appended, and every other line is kept as it is. New
+/// lines end the way the line they replace did, or the way the file's first
+/// line does.
+fn patch(
+    contents: &str,
+    before: &[(Option<usize>, String)],
+    after: &[(Option<usize>, String)],
+) -> String {
+    let eol = match contents.find('\n') {
+        Some(end) if contents[..end].ends_with('\r') => "\r\n",
+        _ => "\n",
+    };
+    let entries_of = |entries: &[(Option<usize>, String)], n: usize| {
+        entries
+            .iter()
+            .filter(|&&(line, _)| line == Some(n))
+            .map(|(_, entry)| entry.clone())
+            .collect::<Vec<_>>()
+    };
+    let mut out = String::with_capacity(contents.len());
+    for (i, line) in contents.split_inclusive('\n').enumerate() {
+        let old = entries_of(before, i + 1);
+        let new = entries_of(after, i + 1);
+        if old == new {
+            out.push_str(line);
+            continue;
+        }
+        let ending = if line.ends_with("\r\n") {
+            "\r\n"
+        } else if line.ends_with('\n') {
+            "\n"
+        } else {
+            ""
+        };
+        let lines = join_entries(new);
+        for (j, entry) in lines.iter().enumerate() {
+            out.push_str(entry);
+            out.push_str(if j + 1 < lines.len() { eol } else { ending });
+        }
+    }
+    let added = after
+        .iter()
+        .filter(|&&(line, _)| line.is_none())
+        .map(|(_, entry)| format!("{}{}", entry.trim_end(), eol))
+        .collect::<String>();
+    if !added.is_empty() && !out.is_empty() && !out.ends_with('\n') {
+        out.push_str(eol);
+    }
+    out.push_str(&added);
+    out
+}
+
+/// Turns the entries libssh2 made from one line back into a line, if they
+/// only differ in their name, and into one line each otherwise. The lines
+/// are returned without their endings.
+///
+/// libssh2 keeps the names of a line last to first.
+fn join_entries(mut entries: Vec<String>) -> Vec<String> {
+    entries.reverse();
+    let split = |entry: &str| {
+        let entry = entry.trim();
+        let end = entry.find(char::is_whitespace).unwrap_or(entry.len());
+        (entry[..end].to_string(), entry[end..].to_string())
+    };
+    let fields = entries.iter().map(|e| split(e)).collect::<Vec<_>>();
+    let joinable = fields.len() > 1
+        && fields.iter().all(|(name, rest)| {
+            !name.starts_with('@') && !name.starts_with('|') && *rest == fields[0].1
+        });
+    if joinable {
+        let names = fields
+            .iter()
+            .map(|(name, _)| &**name)
+            .collect::<Vec<_>>()
+            .join(",");
+        vec![format!("{}{}", names, fields[0].1)]
+    } else {
+        fields
+            .iter()
+            .map(|(name, rest)| format!("{}{}", name, rest))
+            .collect()
+    }
+}
+
+/// An exclusive lock on a known hosts file, released when dropped.
+struct Lock {
+    #[cfg(unix)]
+    _file: File,
+    #[cfg(unix)]
+    path: PathBuf,
+}
+
+impl Lock {
+    #[cfg(unix)]
+    fn acquire(path: &Path) -> Result<Lock, Error> {
+        let err = || {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_FILE),
+                "unable to lock the known hosts file",
+            )
+        };
+        let path = util::with_suffix(path, ".lock");
+        loop {
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(&path)
+                .map_err(|_| err())?;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+                return Err(err());
+            }
+            // The holder before may have removed the file while this one
+            // waited, and another store may have created and locked a new
+            // one since.
+            let locked = file.metadata().map_err(|_| err())?;
+            if let Ok(current) = fs::metadata(&path) {
+                if current.dev() == locked.dev() && current.ino() == locked.ino() {
+                    return Ok(Lock { _file: file, path });
+                }
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn acquire(path: &Path) -> Result<Lock, Error> {
+        let _ = path;
+        Ok(Lock {})
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // Removed while still locked, so that nobody else locks it after
+        // this, and before the file is closed.
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -219,19 +219,63 @@ extern crate libc;
 extern crate libssh2_sys as raw;
 #[macro_use]
 extern crate bitflags;
+#[cfg(feature = "keys")]
+extern crate ed25519_dalek;
+extern crate getrandom;
+extern crate hmac;
+#[cfg(feature = "keys")]
+extern crate p256;
+#[cfg(feature = "keys")]
+extern crate p384;
+#[cfg(feature = "keys")]
+extern crate p521;
 extern crate parking_lot;
+#[cfg(feature = "keys")]
+extern crate pkcs8;
+#[cfg(feature = "prompters")]
+extern crate regex;
+#[cfg(feature = "keys")]
+extern crate rsa;
+extern crate sha1;
+extern crate sha2;
+#[cfg(feature = "keys")]
+extern crate ssh_encoding;
+#[cfg(feature = "keys")]
+extern crate ssh_key;
+extern crate zeroize;
 
 use std::ffi::CStr;
 
-pub use agent::{Agent, PublicKey};
-pub use channel::{Channel, ExitSignal, ReadWindow, Stream, WriteWindow};
+pub use agent::{Agent, IdentityFilter, PublicKey};
+pub use agent_client::{AgentClient, KeyConstraints, SignFlags, Signature};
+#[cfg(feature = "keys")]
+pub use agent_server::AgentServer;
+pub use channel::{Channel, ExitSignal, ExitStatus, ReadWindow, Signal, Stream, WriteWindow};
+pub use command::{Output, RemoteChild, RemoteCommand};
+pub use credentials::{CredentialKind, CredentialProvider, CredentialRequest};
+pub use credentials::{EnvCredentials, FileCredentials, NetrcCredentials, StaticCredentials};
 pub use error::{Error, ErrorCode};
-pub use knownhosts::{Host, KnownHosts};
+pub use forward::{ForwardHandle, ForwardReport, LocalForward, RemoteForward};
+#[cfg(feature = "keys")]
+pub use keys::{KeyType, PrivateKey};
+pub use knownhosts::{Host, HostPattern, KnownHostMarker, KnownHosts};
+pub use knownhosts_store::KnownHostsStore;
 pub use listener::Listener;
+#[cfg(unix)]
+pub use prompter::TtyPrompter;
+#[cfg(feature = "prompters")]
+pub use prompter::{RegexPrompter, TotpResponder};
+pub use secret::Secret;
 use session::SessionInner;
-pub use session::{BlockDirections, KeyboardInteractivePrompt, Prompt, ScpFileStat, Session, TraceFlags};
+pub use session::{
+    AuthMethod, AuthMethods, BlockDirections, KeyboardInteractivePrompt, Prompt, PublicKeySigner,
+    ScpFileStat, Session, TraceFlags,
+};
 pub use sftp::{File, FileStat, FileType, OpenType};
 pub use sftp::{OpenFlags, RenameFlags, Sftp};
+#[cfg(unix)]
+pub use shell::InteractiveShell;
+pub use x11::{X11Channel, X11Cookie, X11Forwarding};
 pub use DisconnectCode::{AuthCancelledByUser, TooManyConnections};
 pub use DisconnectCode::{ByApplication, ConnectionLost, HostKeyNotVerifiable};
 pub use DisconnectCode::{CompressionError, KeyExchangeFailed, MacError, Reserved};
@@ -240,13 +284,30 @@ pub use DisconnectCode::{IllegalUserName, NoMoreAuthMethodsAvailable};
 pub use DisconnectCode::{ProtocolVersionNotSupported, ServiceNotAvailable};
 
 mod agent;
+mod agent_client;
+#[cfg(feature = "keys")]
+mod agent_server;
 mod channel;
+mod command;
+mod credentials;
 mod error;
+mod forward;
+#[cfg(feature = "keys")]
+mod keys;
 mod knownhosts;
+mod knownhosts_store;
 mod listener;
+mod prompter;
+mod pump;
+mod secret;
 mod session;
 mod sftp;
+#[cfg(unix)]
+mod shell;
+mod socks;
 mod util;
+mod wire;
+mod x11;
 
 /// Initialize the libssh2 library.
 ///
@@ -334,7 +395,10 @@ pub enum KnownHostFileKind {
 }
 
 /// Possible results of a call to `KnownHosts::check`
+///
+/// More results may be added, so matches need a wildcard arm.
 #[derive(Copy, Clone, Debug)]
+#[non_exhaustive]
 pub enum CheckResult {
     /// Hosts and keys match
     Match = raw::LIBSSH2_KNOWNHOST_CHECK_MATCH as isize,
@@ -344,10 +408,12 @@ pub enum CheckResult {
     NotFound = raw::LIBSSH2_KNOWNHOST_CHECK_NOTFOUND as isize,
     /// Something prevented the check to be made
     Failure = raw::LIBSSH2_KNOWNHOST_CHECK_FAILURE as isize,
+    /// The key is listed as `@revoked` for the host and must not be accepted
+    Revoked,
 }
 
 #[allow(missing_docs)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum KnownHostKeyFormat {
     Unknown = raw::LIBSSH2_KNOWNHOST_KEY_UNKNOWN as isize,
     Rsa1 = raw::LIBSSH2_KNOWNHOST_KEY_RSA1 as isize,
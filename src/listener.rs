@@ -1,4 +1,5 @@
 use parking_lot::Mutex;
+use std::ptr::null_mut;
 use std::sync::Arc;
 use {raw, Channel, Error, SessionInner};
 
@@ -28,6 +29,21 @@ impl Listener {
         }
     }
 
+    /// Asks the server to stop listening, freeing the listener. Channels
+    /// not yet accepted are closed.
+    ///
+    /// In non-blocking mode this has to be called again until it stops
+    /// returning `LIBSSH2_ERROR_EAGAIN`.
+    pub(crate) fn cancel(&mut self) -> Result<(), Error> {
+        if self.raw.is_null() {
+            return Ok(());
+        }
+        let sess = self.sess.lock();
+        sess.rc(unsafe { raw::libssh2_channel_forward_cancel(self.raw) })?;
+        self.raw = null_mut();
+        Ok(())
+    }
+
     pub(crate) fn from_raw_opt(
         raw: *mut raw::LIBSSH2_LISTENER,
         err: Option<Error>,
@@ -46,6 +62,9 @@ impl Listener {
 
 impl Drop for Listener {
     fn drop(&mut self) {
+        if self.raw.is_null() {
+            return;
+        }
         let _sess = self.sess.lock();
         unsafe {
             let _ = raw::libssh2_channel_forward_cancel(self.raw);
@@ -0,0 +1,425 @@
+//! Ready-made implementations of `KeyboardInteractivePrompt`.
+
+#[cfg(feature = "prompters")]
+use hmac::{Hmac, Mac};
+#[cfg(feature = "prompters")]
+use regex::Regex;
+#[cfg(feature = "prompters")]
+use sha1::Sha1;
+#[cfg(unix)]
+use std::fs::{File, OpenOptions};
+#[cfg(unix)]
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(feature = "prompters")]
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(any(unix, feature = "prompters"))]
+use zeroize::Zeroizing;
+
+#[cfg(unix)]
+use libc;
+
+#[cfg(feature = "prompters")]
+use {raw, Error, ErrorCode, Secret};
+use {KeyboardInteractivePrompt, Prompt};
+
+/// Answers keyboard-interactive prompts from a list of regular expressions,
+/// for unattended use.
+///
+/// Each prompt is answered with the answer of the first pattern matching the
+/// prompt text. Prompts which match no pattern are answered with an empty
+/// string.
+///
+/// Only available with the `prompters` feature.
+///
+/// # Example
+///
+/// ```no_run
+/// use ssh2::{RegexPrompter, Session};
+///
+/// # let sess: Session = panic!();
+/// let mut prompter = RegexPrompter::new();
+/// prompter.answer("(?i)password", "hunter2").unwrap();
+/// sess.userauth_keyboard_interactive("username", &mut prompter).unwrap();
+/// ```
+#[cfg(feature = "prompters")]
+#[derive(Debug, Default, Clone)]
+pub struct RegexPrompter {
+    answers: Vec<(Regex, Secret)>,
+}
+
+#[cfg(feature = "prompters")]
+impl RegexPrompter {
+    /// Create a prompter with no answers.
+    pub fn new() -> RegexPrompter {
+        RegexPrompter::default()
+    }
+
+    /// Answer prompts matching `pattern` with `answer`.
+    ///
+    /// Patterns are tried in the order they were added. Returns an error if
+    /// `pattern` is not a valid regular expression.
+    pub fn answer(&mut self, pattern: &str, answer: &str) -> Result<&mut RegexPrompter, Error> {
+        let regex = Regex::new(pattern).map_err(|_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_INVAL),
+                "invalid regular expression for a prompt",
+            )
+        })?;
+        self.answers.push((regex, Secret::from(answer)));
+        Ok(self)
+    }
+
+    fn lookup(&self, text: &str) -> Option<&str> {
+        self.answers
+            .iter()
+            .find(|(regex, _)| regex.is_match(text))
+            .map(|(_, answer)| answer.expose())
+    }
+}
+
+#[cfg(feature = "prompters")]
+impl KeyboardInteractivePrompt for RegexPrompter {
+    fn prompt<'a>(
+        &mut self,
+        _username: &str,
+        _instructions: &str,
+        prompts: &[Prompt<'a>],
+    ) -> Vec<String> {
+        prompts
+            .iter()
+            .map(|p| self.lookup(&p.text).unwrap_or("").to_string())
+            .collect()
+    }
+}
+
+/// Asks the user on the controlling terminal, like the `ssh` command does.
+///
+/// Prompts are written to and answers read from `/dev/tty`, so this works
+/// even when stdin and stdout are redirected. Echo is turned off while
+/// reading the answer to prompts whose `echo` flag is false. If there is no
+/// controlling terminal every prompt is answered with an empty string.
+#[cfg(unix)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TtyPrompter {
+    _priv: (),
+}
+
+#[cfg(unix)]
+impl TtyPrompter {
+    /// Create a prompter for the controlling terminal.
+    pub fn new() -> TtyPrompter {
+        TtyPrompter::default()
+    }
+}
+
+#[cfg(unix)]
+impl KeyboardInteractivePrompt for TtyPrompter {
+    fn prompt<'a>(
+        &mut self,
+        _username: &str,
+        instructions: &str,
+        prompts: &[Prompt<'a>],
+    ) -> Vec<String> {
+        let tty = match OpenOptions::new().read(true).write(true).open("/dev/tty") {
+            Ok(tty) => tty,
+            Err(_) => return vec![String::new(); prompts.len()],
+        };
+        let mut output = &tty;
+        if !instructions.is_empty() {
+            let _ = writeln!(output, "{}", instructions);
+        }
+        let mut input = &tty;
+        prompts
+            .iter()
+            .map(|p| {
+                let _ = write!(output, "{}", p.text);
+                let _ = output.flush();
+                let _echo = if p.echo {
+                    None
+                } else {
+                    Some(EchoOff::new(&tty))
+                };
+                read_line(&mut input).unwrap_or_default()
+            })
+            .collect()
+    }
+}
+
+/// Reads an answer a byte at a time, so that none of it is left behind in a
+/// read buffer, into storage which is wiped unless the answer is returned.
+#[cfg(unix)]
+fn read_line<R: Read>(input: &mut R) -> Option<String> {
+    let mut line = Zeroizing::new(Vec::with_capacity(64));
+    let mut byte = [0; 1];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return None,
+        }
+        if line.len() == line.capacity() {
+            // Grow by hand, as reallocating would leave the old buffer behind.
+            let mut bigger = Zeroizing::new(Vec::with_capacity(line.capacity() * 2));
+            bigger.extend_from_slice(&line);
+            line = bigger;
+        }
+        line.push(byte[0]);
+    }
+    if line.last() == Some(&b'\r') {
+        let _ = line.pop();
+    }
+    String::from_utf8(mem::take(&mut *line))
+        .map_err(|e| Zeroizing::new(e.into_bytes()))
+        .ok()
+}
+
+/// Turns off echo on a terminal until dropped.
+#[cfg(unix)]
+struct EchoOff<'a> {
+    tty: &'a File,
+    saved: Option<libc::termios>,
+}
+
+#[cfg(unix)]
+impl<'a> EchoOff<'a> {
+    fn new(tty: &'a File) -> EchoOff<'a> {
+        unsafe {
+            let mut termios = ::std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(tty.as_raw_fd(), &mut termios) != 0 {
+                return EchoOff { tty, saved: None };
+            }
+            let saved = termios;
+            // Keep echoing the newline which ends the answer.
+            termios.c_lflag &= !libc::ECHO;
+            termios.c_lflag |= libc::ECHONL;
+            let _ = libc::tcsetattr(tty.as_raw_fd(), libc::TCSAFLUSH, &termios);
+            EchoOff {
+                tty,
+                saved: Some(saved),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl<'a> Drop for EchoOff<'a> {
+    fn drop(&mut self) {
+        if let Some(ref saved) = self.saved {
+            unsafe {
+                let _ = libc::tcsetattr(self.tty.as_raw_fd(), libc::TCSAFLUSH, saved);
+            }
+        }
+    }
+}
+
+/// Answers one-time password prompts with a TOTP code (RFC 6238) computed
+/// from a shared secret, as generated by authenticator apps.
+///
+/// Prompts matching the prompt pattern, `(?i)verification code` unless
+/// changed with `prompt_pattern`, are answered with the current code. Other
+/// prompts are passed on to the fallback prompter if one was set, and
+/// answered with an empty string otherwise.
+///
+/// Only available with the `prompters` feature.
+///
+/// # Example
+///
+/// ```no_run
+/// use ssh2::{RegexPrompter, Session, TotpResponder};
+///
+/// # let sess: Session = panic!();
+/// let mut password = RegexPrompter::new();
+/// password.answer("(?i)password", "hunter2").unwrap();
+///
+/// let mut totp = TotpResponder::from_base32("JBSWY3DPEHPK3PXP").unwrap();
+/// totp.fallback(password);
+/// sess.userauth_keyboard_interactive("username", &mut totp).unwrap();
+/// ```
+#[cfg(feature = "prompters")]
+pub struct TotpResponder {
+    secret: Zeroizing<Vec<u8>>,
+    digits: u32,
+    step: u64,
+    pattern: Regex,
+    clock: Box<dyn Fn() -> u64 + Send>,
+    fallback: Option<Box<dyn KeyboardInteractivePrompt + Send>>,
+}
+
+#[cfg(feature = "prompters")]
+impl TotpResponder {
+    /// Create a responder for the raw shared `secret`, producing 6 digit
+    /// codes which change every 30 seconds.
+    pub fn new(secret: &[u8]) -> TotpResponder {
+        TotpResponder {
+            secret: Zeroizing::new(secret.to_vec()),
+            digits: 6,
+            step: 30,
+            pattern: Regex::new("(?i)verification code").unwrap(),
+            clock: Box::new(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0)
+            }),
+            fallback: None,
+        }
+    }
+
+    /// Create a responder for a secret in the base32 form used by
+    /// `otpauth://` URIs and authenticator app setup screens.
+    ///
+    /// Spaces, padding and case are ignored.
+    pub fn from_base32(secret: &str) -> Result<TotpResponder, Error> {
+        let secret = base32_decode(secret).map(Zeroizing::new).ok_or_else(|| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_INVAL),
+                "invalid base32 TOTP secret",
+            )
+        })?;
+        Ok(TotpResponder::new(&secret))
+    }
+
+    /// Set the number of digits in each code. The default is 6.
+    ///
+    /// Returns an error unless `digits` is between 1 and 10; codes come
+    /// from a 31 bit number, so more digits wouldn't add anything.
+    pub fn digits(&mut self, digits: u32) -> Result<&mut TotpResponder, Error> {
+        if digits == 0 || digits > 10 {
+            return Err(Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_INVAL),
+                "TOTP codes must have between 1 and 10 digits",
+            ));
+        }
+        self.digits = digits;
+        Ok(self)
+    }
+
+    /// Set the number of seconds each code is valid for. The default is 30.
+    pub fn step(&mut self, step: u64) -> &mut TotpResponder {
+        self.step = step.max(1);
+        self
+    }
+
+    /// Set the pattern identifying the prompts to answer with a code.
+    ///
+    /// Returns an error if `pattern` is not a valid regular expression.
+    pub fn prompt_pattern(&mut self, pattern: &str) -> Result<&mut TotpResponder, Error> {
+        self.pattern = Regex::new(pattern).map_err(|_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_INVAL),
+                "invalid regular expression for a prompt",
+            )
+        })?;
+        Ok(self)
+    }
+
+    /// Replace the clock used to compute codes, returning the number of
+    /// seconds since the Unix epoch. This is mostly useful for testing.
+    pub fn clock<F: Fn() -> u64 + Send + 'static>(&mut self, clock: F) -> &mut TotpResponder {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Pass prompts which aren't asking for a code, such as a password
+    /// prompt, on to `prompter`. Prompts for a code aren't passed on.
+    pub fn fallback<P>(&mut self, prompter: P) -> &mut TotpResponder
+    where
+        P: KeyboardInteractivePrompt + Send + 'static,
+    {
+        self.fallback = Some(Box::new(prompter));
+        self
+    }
+
+    /// Returns the code for the current time according to the clock.
+    pub fn code(&self) -> String {
+        self.code_at((self.clock)())
+    }
+
+    /// Returns the code for `time`, in seconds since the Unix epoch.
+    pub fn code_at(&self, time: u64) -> String {
+        let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(&(time / self.step).to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        // Dynamic truncation, RFC 4226 section 5.3.
+        let offset = (hash[hash.len() - 1] & 0xf) as usize;
+        let binary = (hash[offset] as u32 & 0x7f) << 24
+            | (hash[offset + 1] as u32) << 16
+            | (hash[offset + 2] as u32) << 8
+            | hash[offset + 3] as u32;
+        let code = binary as u64 % 10u64.pow(self.digits);
+        format!("{:0width$}", code, width = self.digits as usize)
+    }
+}
+
+#[cfg(feature = "prompters")]
+impl KeyboardInteractivePrompt for TotpResponder {
+    fn prompt<'a>(
+        &mut self,
+        username: &str,
+        instructions: &str,
+        prompts: &[Prompt<'a>],
+    ) -> Vec<String> {
+        // The fallback only sees the prompts which aren't answered here.
+        let others: Vec<Prompt> = prompts
+            .iter()
+            .filter(|prompt| !self.pattern.is_match(&prompt.text))
+            .map(|prompt| Prompt {
+                text: prompt.text.clone(),
+                echo: prompt.echo,
+            })
+            .collect();
+        let mut other_answers = match self.fallback {
+            Some(ref mut fallback) if !others.is_empty() => {
+                fallback.prompt(username, instructions, &others)
+            }
+            _ => Vec::new(),
+        };
+        other_answers.resize(others.len(), String::new());
+        let mut other_answers = other_answers.into_iter();
+        let code = if others.len() < prompts.len() {
+            self.code()
+        } else {
+            String::new()
+        };
+        prompts
+            .iter()
+            .map(|prompt| {
+                if self.pattern.is_match(&prompt.text) {
+                    code.clone()
+                } else {
+                    other_answers.next().unwrap_or_default()
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "prompters")]
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut out = Vec::new();
+    let mut acc = 0u64;
+    let mut bits = 0;
+    for c in data.bytes() {
+        if c == b' ' || c == b'=' || c == b'-' {
+            continue;
+        }
+        let val = ALPHABET.iter().position(|&b| b == c.to_ascii_uppercase())?;
+        acc = acc << 5 | val as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
@@ -0,0 +1,479 @@
+//! Relays data between a channel and a local socket or stream.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(unix))]
+use std::thread;
+use std::time::Duration;
+
+use util::nonblocking;
+use {raw, Channel, Error, ErrorCode, Session, Stream};
+
+/// How long to wait for either side before checking both again. Other users
+/// of the session can read data for this channel off the socket, so waiting
+/// on the socket alone could miss it.
+const WAIT: Duration = Duration::from_millis(20);
+
+const BUF_SIZE: usize = 32 * 1024;
+
+/// The local end of a relay.
+pub trait Local: Read + Write {
+    /// Tells the local side no more data is coming.
+    fn shutdown_write(&mut self) -> io::Result<()>;
+    /// The descriptor to poll on, if the local side has one.
+    #[cfg(unix)]
+    fn poll_fd(&self) -> Option<RawFd>;
+}
+
+/// A local socket which a channel is relayed to.
+pub trait LocalStream: Local + Send {
+    /// Moves the socket in or out of non-blocking mode.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    /// Sets how long a blocking read may wait.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Local for TcpStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    #[cfg(unix)]
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl LocalStream for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Local for UnixStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+#[cfg(unix)]
+impl LocalStream for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Any reader and writer, which can't be polled or shut down; end of file
+/// is passed on by flushing.
+pub struct Plain<'a, L: 'a>(pub &'a mut L);
+
+impl<'a, L: Read> Read for Plain<'a, L> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<'a, L: Write> Write for Plain<'a, L> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<'a, L: Read + Write> Local for Plain<'a, L> {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+
+    #[cfg(unix)]
+    fn poll_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// Fails unless `sess` is in non-blocking mode, which services relaying on
+/// threads of their own need: a blocking call on one of their channels, such
+/// as opening it, would hold up every other user of the session.
+pub fn check_nonblocking(sess: &Session) -> Result<(), Error> {
+    if sess.is_blocking() {
+        return Err(Error::new(
+            ErrorCode::Session(raw::LIBSSH2_ERROR_BAD_USE),
+            "the session must be in non-blocking mode",
+        ));
+    }
+    Ok(())
+}
+
+/// Data read from one side and not yet written to the other.
+struct Pending {
+    buf: Box<[u8]>,
+    start: usize,
+    end: usize,
+}
+
+impl Pending {
+    fn new() -> Pending {
+        Pending {
+            buf: vec![0; BUF_SIZE].into_boxed_slice(),
+            start: 0,
+            end: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+
+    fn filled(&mut self, n: usize) {
+        self.start = 0;
+        self.end = n;
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.start = cmp::min(self.start + n, self.end);
+    }
+
+    fn clear(&mut self) {
+        self.start = 0;
+        self.end = 0;
+    }
+}
+
+/// Copies data both ways between a channel and a local socket, until both
+/// directions have seen end of file.
+///
+/// The channel is only ever called without blocking, whatever the session's
+/// mode. A local side which blocks holds up the other direction while it
+/// does.
+pub struct Pump<S> {
+    channel: Channel,
+    stream: Stream,
+    stderr: Option<Stream>,
+    local: S,
+    to_remote: Pending,
+    to_local: Pending,
+    local_eof: bool,
+    eof_sent: bool,
+    remote_eof: bool,
+    local_shut: bool,
+    local_error: Option<io::Error>,
+    until_remote_eof: bool,
+    sent: u64,
+    received: u64,
+}
+
+impl<S: Local> Pump<S> {
+    pub fn new(channel: Channel, local: S) -> Pump<S> {
+        Pump {
+            stream: channel.stream(0),
+            stderr: None,
+            channel,
+            local,
+            to_remote: Pending::new(),
+            to_local: Pending::new(),
+            local_eof: false,
+            eof_sent: false,
+            remote_eof: false,
+            local_shut: false,
+            local_error: None,
+            until_remote_eof: false,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// Also copies the channel's stderr to the local side, interleaved with
+    /// stdout.
+    pub fn with_stderr(mut self) -> Pump<S> {
+        self.stderr = Some(self.channel.stderr());
+        self
+    }
+
+    /// Finishes once the remote end has sent end of file, or closed the
+    /// channel, and its data has been passed on, even if the local side has
+    /// more to send.
+    pub fn until_remote_eof(mut self) -> Pump<S> {
+        self.until_remote_eof = true;
+        self
+    }
+
+    /// Returns the bytes sent to and received from the channel so far.
+    pub fn counts(&self) -> (u64, u64) {
+        (self.sent, self.received)
+    }
+
+    /// Takes the error which ended reading from the local side, if any.
+    pub fn take_local_error(&mut self) -> Option<io::Error> {
+        self.local_error.take()
+    }
+
+    /// Returns the local side.
+    pub fn local_mut(&mut self) -> &mut S {
+        &mut self.local
+    }
+
+    /// Whether everything the channel sent has been passed on.
+    pub fn received_all(&self) -> bool {
+        self.local_shut
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.local_shut && (self.eof_sent || self.until_remote_eof && self.to_remote.is_empty())
+    }
+
+    /// Moves whatever data can be moved without blocking, returning whether
+    /// anything happened.
+    pub fn step(&mut self) -> Result<bool, Error> {
+        let sent = self.send()?;
+        let received = self.receive()?;
+        Ok(sent || received)
+    }
+
+    fn send(&mut self) -> Result<bool, Error> {
+        let mut progress = false;
+        while !self.eof_sent {
+            if self.to_remote.is_empty() {
+                if self.local_eof {
+                    match nonblocking(self.channel.try_send_eof()) {
+                        Ok(None) => break,
+                        Ok(Some(())) => {}
+                        Err(ref e) if closed(e) => {}
+                        Err(e) => return Err(e),
+                    }
+                    self.eof_sent = true;
+                    progress = true;
+                    break;
+                }
+                match self.local.read(&mut self.to_remote.buf) {
+                    Ok(0) => self.local_eof = true,
+                    Ok(n) => self.to_remote.filled(n),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    // A reset connection ends the data the same way; the
+                    // error is kept for callers which report it.
+                    Err(e) => {
+                        self.local_eof = true;
+                        self.local_error = Some(e);
+                    }
+                }
+                progress = true;
+                continue;
+            }
+            match nonblocking(self.stream.try_write(self.to_remote.data())) {
+                Ok(None) => break,
+                Ok(Some(n)) => {
+                    self.to_remote.consume(n);
+                    self.sent += n as u64;
+                    progress = true;
+                }
+                Err(ref e) if closed(e) => {
+                    // Nobody is left to read the data.
+                    self.to_remote.clear();
+                    self.local_eof = true;
+                    self.eof_sent = true;
+                    progress = true;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(progress)
+    }
+
+    fn receive(&mut self) -> Result<bool, Error> {
+        let mut progress = false;
+        while !self.local_shut {
+            if self.to_local.is_empty() {
+                if self.remote_eof {
+                    let _ = self.local.shutdown_write();
+                    self.local_shut = true;
+                    progress = true;
+                    break;
+                }
+                match self.read_channel() {
+                    Ok(None) => break,
+                    Ok(Some(0)) => {
+                        if !self.channel.eof() {
+                            break;
+                        }
+                        self.remote_eof = true;
+                    }
+                    Ok(Some(n)) => {
+                        self.to_local.filled(n);
+                        self.received += n as u64;
+                    }
+                    Err(ref e) if closed(e) => self.remote_eof = true,
+                    Err(e) => return Err(e),
+                }
+                progress = true;
+                continue;
+            }
+            match self.local.write(self.to_local.data()) {
+                Ok(0) => {
+                    self.to_local.clear();
+                    self.remote_eof = true;
+                }
+                Ok(n) => self.to_local.consume(n),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    // The local side went away; drop what the channel sends.
+                    self.to_local.clear();
+                    self.remote_eof = true;
+                }
+            }
+            progress = true;
+        }
+        Ok(progress)
+    }
+
+    /// Reads from stdout, or from stderr when stdout has nothing.
+    fn read_channel(&mut self) -> Result<Option<usize>, Error> {
+        let n = nonblocking(self.stream.try_read(&mut self.to_local.buf))?;
+        if let Some(ref mut stderr) = self.stderr {
+            if n.is_none() || n == Some(0) {
+                if let Some(m) = nonblocking(stderr.try_read(&mut self.to_local.buf))? {
+                    if m > 0 {
+                        return Ok(Some(m));
+                    }
+                }
+            }
+        }
+        Ok(n)
+    }
+
+    /// Waits until either side might have something to do.
+    pub fn wait(&self, sess: &Session) {
+        #[cfg(unix)]
+        {
+            use libc;
+            use BlockDirections;
+
+            let mut session = libc::POLLIN;
+            if let BlockDirections::Outbound | BlockDirections::Both = sess.block_directions() {
+                session |= libc::POLLOUT;
+            }
+            let mut local = 0;
+            if !self.local_eof && self.to_remote.is_empty() {
+                local |= libc::POLLIN;
+            }
+            if !self.to_local.is_empty() {
+                local |= libc::POLLOUT;
+            }
+            let mut fds = [
+                libc::pollfd {
+                    fd: sess.as_raw_fd(),
+                    events: session,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    // Without a descriptor, the local side is tried again
+                    // after the timeout.
+                    fd: self.local.poll_fd().unwrap_or(-1),
+                    events: local,
+                    revents: 0,
+                },
+            ];
+            let _ = unsafe { libc::poll(fds.as_mut_ptr(), 2, WAIT.as_millis() as libc::c_int) };
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = sess;
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Relays until both directions are finished, or `stop` is set, then
+    /// closes the channel.
+    pub fn run(&mut self, sess: &Session, stop: Option<&AtomicBool>) -> Result<(), Error> {
+        self.copy(sess, stop)?;
+        close(&mut self.channel, sess)
+    }
+
+    /// Relays until both directions are finished, or `stop` is set, leaving
+    /// the channel open.
+    pub fn copy(&mut self, sess: &Session, stop: Option<&AtomicBool>) -> Result<(), Error> {
+        while !self.is_done() {
+            if stop.is_some_and(|stop| stop.load(Ordering::SeqCst)) {
+                break;
+            }
+            if !self.step()? {
+                self.wait(sess);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Closes `channel` on a non-blocking session.
+pub fn close(channel: &mut Channel, sess: &Session) -> Result<(), Error> {
+    loop {
+        match nonblocking(channel.close()) {
+            Ok(Some(())) => break,
+            Ok(None) => wait(sess),
+            Err(ref e) if closed(e) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+    loop {
+        match nonblocking(channel.wait_close()) {
+            Ok(Some(())) => return Ok(()),
+            Ok(None) => wait(sess),
+            Err(ref e) if closed(e) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn wait(sess: &Session) {
+    #[cfg(unix)]
+    {
+        use libc;
+
+        let mut fd = libc::pollfd {
+            fd: sess.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let _ = unsafe { libc::poll(&mut fd, 1, WAIT.as_millis() as libc::c_int) };
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = sess;
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Whether `err` means the other side has closed the channel.
+fn closed(err: &Error) -> bool {
+    matches!(
+        err.code(),
+        ErrorCode::Session(raw::LIBSSH2_ERROR_CHANNEL_CLOSED)
+            | ErrorCode::Session(raw::LIBSSH2_ERROR_CHANNEL_EOF_SENT)
+    )
+}
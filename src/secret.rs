@@ -0,0 +1,167 @@
+use libc::{self, c_void, size_t};
+use std::fmt;
+use std::ops::Deref;
+use std::ptr::{self, null_mut};
+use std::slice;
+use zeroize::{Zeroize, Zeroizing};
+
+use {raw, Error, ErrorCode};
+
+/// A password, passphrase or other secret string which is overwritten with
+/// zeros when dropped.
+///
+/// `Secret` dereferences to `str`, so it can be passed to any of the
+/// authentication methods of `Session`, for example as `&secret` or
+/// `Some(&*secret)`. Every copy the crate makes of such strings, including the
+/// nul-terminated copies handed to libssh2 and keyboard-interactive
+/// responses, is wiped in the same way, and sessions created with
+/// `Session::new_zeroizing` also wipe libssh2's own buffers before freeing
+/// them.
+///
+/// # Example
+///
+/// ```no_run
+/// use ssh2::{Secret, Session};
+///
+/// # let sess: Session = panic!();
+/// let password = Secret::from("hunter2");
+/// sess.userauth_password("username", &password).unwrap();
+/// ```
+#[derive(Clone, Default)]
+pub struct Secret {
+    data: String,
+}
+
+impl Secret {
+    /// Wrap `data`, taking ownership of its buffer.
+    pub fn new(data: String) -> Secret {
+        Secret { data }
+    }
+
+    /// Returns the secret string.
+    pub fn expose(&self) -> &str {
+        &self.data
+    }
+}
+
+impl From<String> for Secret {
+    fn from(data: String) -> Secret {
+        Secret::new(data)
+    }
+}
+
+impl<'a> From<&'a str> for Secret {
+    fn from(data: &'a str) -> Secret {
+        Secret::new(data.to_string())
+    }
+}
+
+impl Deref for Secret {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.data
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.data.zeroize();
+    }
+}
+
+/// Builds the nul-terminated copy of a secret passed to libssh2, which is
+/// wiped when dropped.
+///
+/// Unlike `CString::new`, this never reallocates, so no stray copies are left
+/// behind, and rejecting interior nul bytes doesn't copy the data at all.
+pub fn c_secret(data: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+    if data.as_bytes().contains(&0) {
+        return Err(Error::new(
+            ErrorCode::Session(raw::LIBSSH2_ERROR_INVAL),
+            "provided data contained a nul byte and could not be used \
+             as as string",
+        ));
+    }
+    let mut buf = Zeroizing::new(Vec::with_capacity(data.len() + 1));
+    buf.extend_from_slice(data.as_bytes());
+    buf.push(0);
+    Ok(buf)
+}
+
+// The allocator installed into sessions created by `Session::new_zeroizing`.
+// Each block is prefixed with its size, so that it can be wiped when freed or
+// moved by a reallocation. The prefix is 16 bytes to keep the alignment
+// malloc guarantees.
+const HEADER: usize = 16;
+
+/// Allocates `size` bytes from the allocator of a session, which wipes them
+/// when freeing them if `zeroizing` is set and is malloc(3) otherwise.
+///
+/// Memory handed over to libssh2 to be freed by it, such as
+/// keyboard-interactive responses, must come from here.
+pub unsafe fn malloc(zeroizing: bool, size: size_t) -> *mut c_void {
+    if zeroizing {
+        wiping_malloc(size)
+    } else {
+        libc::malloc(size)
+    }
+}
+
+unsafe fn wiping_malloc(size: size_t) -> *mut c_void {
+    let total = match size.checked_add(HEADER) {
+        Some(total) => total,
+        None => return null_mut(),
+    };
+    let base = libc::malloc(total) as *mut u8;
+    if base.is_null() {
+        return null_mut();
+    }
+    *(base as *mut size_t) = size;
+    base.add(HEADER) as *mut c_void
+}
+
+unsafe fn free(ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+    let base = (ptr as *mut u8).sub(HEADER);
+    let size = *(base as *const size_t);
+    slice::from_raw_parts_mut(ptr as *mut u8, size).zeroize();
+    libc::free(base as *mut c_void);
+}
+
+pub extern "C" fn alloc_func(size: size_t, _abstrakt: *mut *mut c_void) -> *mut c_void {
+    unsafe { wiping_malloc(size) }
+}
+
+pub extern "C" fn free_func(ptr: *mut c_void, _abstrakt: *mut *mut c_void) {
+    unsafe { free(ptr) }
+}
+
+pub extern "C" fn realloc_func(
+    ptr: *mut c_void,
+    size: size_t,
+    _abstrakt: *mut *mut c_void,
+) -> *mut c_void {
+    unsafe {
+        if ptr.is_null() {
+            return wiping_malloc(size);
+        }
+        // Always move the data, so the old block can be wiped.
+        let new = wiping_malloc(size);
+        if new.is_null() {
+            return null_mut();
+        }
+        let old_size = *((ptr as *mut u8).sub(HEADER) as *const size_t);
+        ptr::copy_nonoverlapping(ptr as *const u8, new as *mut u8, old_size.min(size));
+        free(ptr);
+        new
+    }
+}
@@ -1,10 +1,9 @@
-// Usings for openssl function userauth_pubkey_memory()
-#[cfg(any(unix, feature = "vendored-openssl", feature = "openssl-on-win32"))]
-use libc::size_t;
-use libc::{self, c_char, c_int, c_long, c_uint, c_void};
+use libc::{self, c_char, c_int, c_long, c_uchar, c_uint, c_void, size_t};
 use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
 use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::ffi::CString;
+use std::fmt;
 use std::ptr::{null, null_mut};
 use std::mem;
 #[cfg(unix)]
@@ -15,10 +14,19 @@ use std::path::Path;
 use std::slice;
 use std::str;
 use std::sync::Arc;
+use zeroize::Zeroizing;
 
+use credentials::PasswordPrompter;
+use secret;
 use util;
+use wire;
+use x11;
+#[cfg(feature = "keys")]
+use PrivateKey;
+use X11Channel;
 use {raw, ByApplication, DisconnectCode, Error, ErrorCode, HostKeyType};
-use {Agent, Channel, HashType, KnownHosts, Listener, MethodType, Sftp};
+use {Agent, Channel, HashType, IdentityFilter, KnownHosts, Listener, MethodType, Sftp};
+use {CredentialKind, CredentialProvider, CredentialRequest};
 
 bitflags! {
     /// Flags which can be used with the session trace method to set
@@ -64,6 +72,18 @@ pub trait KeyboardInteractivePrompt {
     ) -> Vec<String>;
 }
 
+/// Called by libssh2 to sign the authentication request as part of public key
+/// authentication with `Session::userauth_pubkey_sign`.
+pub trait PublicKeySigner {
+    /// `algorithm` is the public key algorithm named in the request being
+    /// signed, such as `ssh-ed25519` or `rsa-sha2-256`. For RSA keys it
+    /// selects the hash which must be used.
+    /// `data` is the data to be signed.
+    /// The return value is the signature blob, without the leading algorithm
+    /// name of the RFC 4253 signature encoding.
+    fn sign(&mut self, algorithm: &str, data: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
 /// A prompt/challenge returned as part of keyboard-interactive authentication
 #[derive(Debug)]
 pub struct Prompt<'a> {
@@ -100,6 +120,32 @@ pub(crate) struct SessionInner {
     tcp: Option<Box<dyn AsRawFd>>,
     #[cfg(windows)]
     tcp: Option<Box<dyn AsRawSocket>>,
+    opened: Option<Box<OpenedChannels>>,
+    zeroizing: bool,
+}
+
+/// Channels opened by the server and not yet accepted. Lives behind the
+/// session's abstract pointer, so that libssh2's callbacks can reach it.
+#[derive(Default)]
+pub(crate) struct OpenedChannels {
+    pub(crate) x11: VecDeque<(*mut raw::LIBSSH2_CHANNEL, String, u16)>,
+    auth_agent: VecDeque<*mut raw::LIBSSH2_CHANNEL>,
+}
+
+/// Called by libssh2, with the session locked, when the server opens an
+/// agent channel.
+extern "C" fn auth_agent_open(
+    _sess: *mut raw::LIBSSH2_SESSION,
+    channel: *mut raw::LIBSSH2_CHANNEL,
+    abstrakt: *mut *mut c_void,
+) {
+    unsafe {
+        let opened = *abstrakt as *mut OpenedChannels;
+        if opened.is_null() {
+            return;
+        }
+        (*opened).auth_agent.push_back(channel);
+    }
 }
 
 // The compiler doesn't know that it is Send safe because of the raw
@@ -148,6 +194,124 @@ pub enum BlockDirections {
     Both,
 }
 
+/// An authentication method offered by a server.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AuthMethod {
+    /// `publickey`, used by `userauth_pubkey_file` and the agent.
+    PublicKey,
+    /// `password`, used by `userauth_password`.
+    Password,
+    /// `keyboard-interactive`, used by `userauth_keyboard_interactive`.
+    KeyboardInteractive,
+    /// `hostbased`, used by `userauth_hostbased_file`.
+    HostBased,
+    /// `gssapi-with-mic`, Kerberos authentication. Not supported by libssh2.
+    GssapiWithMic,
+    /// `gssapi-keyex`, Kerberos authentication tied to the key exchange. Not
+    /// supported by libssh2.
+    GssapiKeyex,
+    /// Any other method, by name.
+    Unknown(String),
+}
+
+impl AuthMethod {
+    fn from_name(name: &str) -> AuthMethod {
+        match name {
+            "publickey" => AuthMethod::PublicKey,
+            "password" => AuthMethod::Password,
+            "keyboard-interactive" => AuthMethod::KeyboardInteractive,
+            "hostbased" => AuthMethod::HostBased,
+            "gssapi-with-mic" => AuthMethod::GssapiWithMic,
+            "gssapi-keyex" => AuthMethod::GssapiKeyex,
+            other => AuthMethod::Unknown(other.to_string()),
+        }
+    }
+
+    /// Returns the protocol name of this method, e.g. `publickey`.
+    pub fn name(&self) -> &str {
+        match *self {
+            AuthMethod::PublicKey => "publickey",
+            AuthMethod::Password => "password",
+            AuthMethod::KeyboardInteractive => "keyboard-interactive",
+            AuthMethod::HostBased => "hostbased",
+            AuthMethod::GssapiWithMic => "gssapi-with-mic",
+            AuthMethod::GssapiKeyex => "gssapi-keyex",
+            AuthMethod::Unknown(ref name) => name,
+        }
+    }
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The authentication methods a server supports, as returned by
+/// `Session::auth_methods`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthMethods {
+    methods: Vec<AuthMethod>,
+    authenticated: bool,
+}
+
+impl AuthMethods {
+    fn parse(list: &str, authenticated: bool) -> AuthMethods {
+        AuthMethods {
+            methods: list
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(AuthMethod::from_name)
+                .collect(),
+            authenticated,
+        }
+    }
+
+    /// Returns whether the server offers `method`.
+    pub fn contains(&self, method: &AuthMethod) -> bool {
+        self.methods.contains(method)
+    }
+
+    /// Returns the offered methods, in the server's order of preference.
+    pub fn iter(&self) -> slice::Iter<'_, AuthMethod> {
+        self.methods.iter()
+    }
+
+    /// Returns whether no methods were offered.
+    pub fn is_empty(&self) -> bool {
+        self.methods.is_empty()
+    }
+
+    /// Returns whether the "none" request used to fetch the list was itself
+    /// accepted, in which case the session is already authenticated and no
+    /// methods are listed.
+    pub fn authenticated(&self) -> bool {
+        self.authenticated
+    }
+}
+
+impl<'a> IntoIterator for &'a AuthMethods {
+    type Item = &'a AuthMethod;
+    type IntoIter = slice::Iter<'a, AuthMethod>;
+
+    fn into_iter(self) -> slice::Iter<'a, AuthMethod> {
+        self.methods.iter()
+    }
+}
+
+/// Formats the methods as the comma-separated list sent by the server.
+impl fmt::Display for AuthMethods {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, method) in self.methods.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(method.name())?;
+        }
+        Ok(())
+    }
+}
+
 impl Session {
     /// Initializes an SSH session object.
     ///
@@ -158,9 +322,33 @@ impl Session {
     /// already-established TCP socket, and then follow up with a call to
     /// `handshake` to perform the ssh protocol handshake.
     pub fn new() -> Result<Session, Error> {
+        Session::init(false)
+    }
+
+    /// Initializes an SSH session object which wipes all memory libssh2
+    /// allocates for it before freeing it.
+    ///
+    /// This keeps passwords, keys and the session's own key material from
+    /// lingering on the heap once libssh2 is done with them, at the cost of
+    /// wiping every buffer the session frees. Otherwise the session is the
+    /// same as one created with `new`.
+    pub fn new_zeroizing() -> Result<Session, Error> {
+        Session::init(true)
+    }
+
+    fn init(zeroizing: bool) -> Result<Session, Error> {
         ::init();
         unsafe {
-            let ret = raw::libssh2_session_init_ex(None, None, None, null_mut());
+            let ret = if zeroizing {
+                raw::libssh2_session_init_ex(
+                    Some(secret::alloc_func),
+                    Some(secret::free_func),
+                    Some(secret::realloc_func),
+                    null_mut(),
+                )
+            } else {
+                raw::libssh2_session_init_ex(None, None, None, null_mut())
+            };
             if ret.is_null() {
                 Err(Error::unknown())
             } else {
@@ -168,6 +356,8 @@ impl Session {
                     inner: Arc::new(Mutex::new(SessionInner {
                         raw: ret,
                         tcp: None,
+                        opened: None,
+                        zeroizing,
                     })),
                 })
             }
@@ -335,8 +525,8 @@ impl Session {
     pub fn userauth_password(&self, username: &str, password: &str) -> Result<(), Error> {
         let username = CString::new(username)?;
         let username = username.as_bytes();
-        let password = CString::new(password)?;
-        let password = password.as_bytes();
+        let password = secret::c_secret(password)?;
+        let password = &password[..password.len() - 1];
         let inner = self.inner();
         inner.rc(unsafe {
             raw::libssh2_userauth_password_ex(
@@ -390,7 +580,8 @@ impl Session {
                     return;
                 }
 
-                let prompter = unsafe { &mut **(abstrakt as *mut *mut P) };
+                let (ref mut prompter, zeroizing) =
+                    *unsafe { &mut **(abstrakt as *mut *mut (&mut P, bool)) };
 
                 let username = if !username.is_null() && username_len >= 0 {
                     let username = unsafe {
@@ -427,13 +618,13 @@ impl Session {
                     })
                     .collect();
 
-                // libssh2 wants to be able to free(3) the response strings, so allocate
-                // storage and copy the responses into appropriately owned memory.
+                // libssh2 wants to be able to free the response strings, so allocate
+                // storage from the session allocator and copy the responses into it.
                 // We can't simply call strdup(3) here because the rust string types
                 // are not NUL terminated.
-                fn strdup_string(s: &str) -> *mut c_char {
+                fn strdup_string(s: &str, zeroizing: bool) -> *mut c_char {
                     let len = s.len();
-                    let ptr = unsafe { libc::malloc(len + 1) as *mut c_char };
+                    let ptr = unsafe { secret::malloc(zeroizing, len + 1) as *mut c_char };
                     if !ptr.is_null() {
                         unsafe {
                             ::std::ptr::copy_nonoverlapping(
@@ -447,13 +638,10 @@ impl Session {
                     ptr
                 }
 
-                for (i, response) in (*prompter)
-                    .prompt(&username, &instruction, &prompts)
-                    .into_iter()
-                    .take(prompts.len())
-                    .enumerate()
-                {
-                    let ptr = strdup_string(&response);
+                // The responses may well be passwords, so wipe them once copied.
+                let answers = Zeroizing::new((*prompter).prompt(&username, &instruction, &prompts));
+                for (i, response) in answers.iter().take(prompts.len()).enumerate() {
+                    let ptr = strdup_string(response, zeroizing);
                     if !ptr.is_null() {
                         responses[i].length = response.len() as c_uint;
                     } else {
@@ -467,8 +655,10 @@ impl Session {
         let username = CString::new(username)?;
         let username = username.as_bytes();
         let inner = self.inner();
+        let mut state = (prompter, inner.zeroizing);
+        let state = &mut state as *mut (&mut P, bool) as *mut c_void;
         unsafe {
-            with_abstract(inner.raw, prompter as *mut P as *mut c_void, || {
+            with_abstract(inner.raw, state, || {
                 inner.rc(raw::libssh2_userauth_keyboard_interactive_ex(
                     inner.raw,
                     username.as_ptr() as *const _,
@@ -479,6 +669,99 @@ impl Session {
         }
     }
 
+    /// Authenticate with credentials from `provider`, trying each method the
+    /// server offers in turn: public key, then password, then
+    /// keyboard-interactive.
+    ///
+    /// `host` identifies the server to the provider, as the session itself
+    /// doesn't know what name it was connected to. Each method is attempted
+    /// at most once per call, and credentials the server refuses are passed
+    /// to `CredentialProvider::reject`. Public key authentication requires a
+    /// build where `userauth_pubkey_memory` is available.
+    ///
+    /// The session must be in blocking mode. If no credential was accepted,
+    /// the error from the last attempt is returned.
+    pub fn userauth_credentials<P: CredentialProvider + ?Sized>(
+        &self,
+        username: &str,
+        host: &str,
+        provider: &mut P,
+    ) -> Result<(), Error> {
+        let methods = self.auth_methods(username)?;
+        if methods.authenticated() {
+            return Ok(());
+        }
+        let request = |method: AuthMethod, kind: CredentialKind| CredentialRequest {
+            username,
+            host,
+            method,
+            kind,
+        };
+        let mut last_err = None;
+
+        #[cfg(any(unix, feature = "vendored-openssl", feature = "openssl-on-win32"))]
+        {
+            if methods.contains(&AuthMethod::PublicKey) {
+                let key_req = request(AuthMethod::PublicKey, CredentialKind::PrivateKey);
+                if let Some(key) = provider.credential(&key_req) {
+                    let pass_req = request(AuthMethod::PublicKey, CredentialKind::Passphrase);
+                    let passphrase = provider.credential(&pass_req);
+                    match self.userauth_pubkey_memory(
+                        username,
+                        None,
+                        &key,
+                        passphrase.as_ref().map(|p| p.expose()),
+                    ) {
+                        Ok(()) => return Ok(()),
+                        Err(e) => {
+                            provider.reject(&key_req, &key);
+                            if let Some(ref passphrase) = passphrase {
+                                provider.reject(&pass_req, passphrase);
+                            }
+                            last_err = Some(e);
+                        }
+                    }
+                }
+            }
+        }
+
+        if methods.contains(&AuthMethod::Password) {
+            let req = request(AuthMethod::Password, CredentialKind::Password);
+            if let Some(password) = provider.credential(&req) {
+                match self.userauth_password(username, &password) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => {
+                        provider.reject(&req, &password);
+                        last_err = Some(e);
+                    }
+                }
+            }
+        }
+
+        if methods.contains(&AuthMethod::KeyboardInteractive) {
+            let req = request(AuthMethod::KeyboardInteractive, CredentialKind::Password);
+            if let Some(password) = provider.credential(&req) {
+                let mut prompter = PasswordPrompter {
+                    password: &password,
+                };
+                match self.userauth_keyboard_interactive(username, &mut prompter) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => {
+                        provider.reject(&req, &password);
+                        last_err = Some(e);
+                    }
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_AUTHENTICATION_FAILED),
+                "no credentials available for the offered authentication methods",
+            )
+        }))
+    }
+
     /// Attempt to perform SSH agent authentication.
     ///
     /// This is a helper method for attempting to authenticate the current
@@ -502,6 +785,127 @@ impl Session {
         agent.userauth(username, &identity)
     }
 
+    /// Attempt public key authentication with the identities of an SSH agent
+    /// which are selected by `filter`.
+    ///
+    /// Unlike `userauth_agent`, each selected identity is tried in turn until
+    /// one is accepted. Restricting the identities offered avoids hitting the
+    /// server's `MaxAuthTries` limit when the agent holds many keys.
+    pub fn userauth_agent_filtered(
+        &self,
+        username: &str,
+        filter: &IdentityFilter,
+    ) -> Result<(), Error> {
+        let mut agent = self.agent()?;
+        agent.connect()?;
+        agent.list_identities()?;
+        let identities = agent.filtered_identities(filter)?;
+        let mut last_err = Error::new(
+            ErrorCode::Session(raw::LIBSSH2_ERROR_INVAL),
+            "no matching identities found in the ssh agent",
+        );
+        for identity in &identities {
+            match agent.userauth(username, identity) {
+                Ok(()) => return Ok(()),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Attempt public key authentication, delegating the signature to a
+    /// callback.
+    ///
+    /// `pubkey` is the public key blob in the SSH wire format, as returned by
+    /// `PublicKey::blob`. The private key never needs to be visible to
+    /// libssh2, which makes this suitable for keys held by an agent, a
+    /// hardware token or the pure-Rust `keys` module.
+    pub fn userauth_pubkey_sign<S: PublicKeySigner>(
+        &self,
+        username: &str,
+        pubkey: &[u8],
+        signer: &mut S,
+    ) -> Result<(), Error> {
+        // The abstract pointer handed to libssh2_userauth_publickey is passed
+        // back verbatim to the callback, so unlike keyboard-interactive
+        // authentication there is no need to swap out the session abstract.
+        extern "C" fn sign<S: PublicKeySigner>(
+            _session: *mut raw::LIBSSH2_SESSION,
+            sig: *mut *mut c_uchar,
+            sig_len: *mut size_t,
+            data: *const c_uchar,
+            data_len: size_t,
+            abstrakt: *mut *mut c_void,
+        ) -> c_int {
+            use std::panic::{catch_unwind, AssertUnwindSafe};
+            let res = catch_unwind(AssertUnwindSafe(|| {
+                if data.is_null() || sig.is_null() || sig_len.is_null() {
+                    return -1;
+                }
+                let (ref mut signer, zeroizing) =
+                    *unsafe { &mut **(abstrakt as *mut *mut (&mut S, bool)) };
+                let data = unsafe { slice::from_raw_parts(data, data_len) };
+                let algorithm = wire::userauth_request_algorithm(data).unwrap_or("");
+                let blob = match signer.sign(algorithm, data) {
+                    Ok(blob) => blob,
+                    Err(_) => return -1,
+                };
+
+                // libssh2 releases the signature with LIBSSH2_FREE, so it
+                // must come from the session allocator.
+                let ptr = unsafe { secret::malloc(zeroizing, blob.len()) as *mut c_uchar };
+                if ptr.is_null() {
+                    return -1;
+                }
+                unsafe {
+                    ::std::ptr::copy_nonoverlapping(blob.as_ptr(), ptr, blob.len());
+                    *sig = ptr;
+                    *sig_len = blob.len() as size_t;
+                }
+                0
+            }));
+            res.unwrap_or(-1)
+        }
+
+        let username = CString::new(username)?;
+        let inner = self.inner();
+        let mut state = (signer, inner.zeroizing);
+        let mut abstrakt = &mut state as *mut (&mut S, bool) as *mut c_void;
+        inner.rc(unsafe {
+            raw::libssh2_userauth_publickey(
+                inner.raw,
+                username.as_ptr(),
+                pubkey.as_ptr(),
+                pubkey.len() as size_t,
+                Some(sign::<S>),
+                &mut abstrakt,
+            )
+        })
+    }
+
+    /// Attempt public key authentication with a private key parsed by this
+    /// crate rather than by libssh2's crypto backend.
+    ///
+    /// Without the `rsa-signing` feature, RSA keys are handed to libssh2 to
+    /// sign with, as with `userauth_pubkey_memory`; see `PrivateKey::sign`.
+    ///
+    /// Only available with the `keys` feature.
+    #[cfg(feature = "keys")]
+    pub fn userauth_privatekey(&self, username: &str, key: &PrivateKey) -> Result<(), Error> {
+        #[cfg(all(
+            not(feature = "rsa-signing"),
+            any(unix, feature = "vendored-openssl", feature = "openssl-on-win32")
+        ))]
+        {
+            if key.algorithm() == "ssh-rsa" {
+                let pem = Zeroizing::new(key.to_openssh_pem(None)?);
+                return self.userauth_pubkey_memory(username, None, &pem, None);
+            }
+        }
+        let pubkey = key.public_key_blob();
+        self.userauth_pubkey_sign(username, &pubkey, &mut &*key)
+    }
+
     /// Attempt public key authentication using a PEM encoded private key file
     /// stored on disk.
     pub fn userauth_pubkey_file(
@@ -519,7 +923,7 @@ impl Session {
         };
         let privatekey = CString::new(util::path2bytes(privatekey)?)?;
         let passphrase = match passphrase {
-            Some(s) => Some(CString::new(s)?),
+            Some(s) => Some(secret::c_secret(s)?),
             None => None,
         };
         let inner = self.inner();
@@ -532,7 +936,7 @@ impl Session {
                 privatekey.as_ptr(),
                 passphrase
                     .as_ref()
-                    .map(|s| s.as_ptr())
+                    .map(|s| s.as_ptr() as *const _)
                     .unwrap_or(null()),
             )
         })
@@ -556,9 +960,9 @@ impl Session {
             None => (None, 0),
         };
         let privatekeydata_len = privatekeydata.len();
-        let privatekeydata = CString::new(privatekeydata)?;
+        let privatekeydata = secret::c_secret(privatekeydata)?;
         let passphrase = match passphrase {
-            Some(s) => Some(CString::new(s)?),
+            Some(s) => Some(secret::c_secret(s)?),
             None => None,
         };
         let inner = self.inner();
@@ -572,11 +976,11 @@ impl Session {
                     .map(|s| s.as_ptr())
                     .unwrap_or(null()),
                 pubkeydata_len as size_t,
-                privatekeydata.as_ptr(),
+                privatekeydata.as_ptr() as *const _,
                 privatekeydata_len as size_t,
                 passphrase
                     .as_ref()
-                    .map(|s| s.as_ptr())
+                    .map(|s| s.as_ptr() as *const _)
                     .unwrap_or(null()),
             )
         })
@@ -596,7 +1000,7 @@ impl Session {
         let publickey = CString::new(util::path2bytes(publickey)?)?;
         let privatekey = CString::new(util::path2bytes(privatekey)?)?;
         let passphrase = match passphrase {
-            Some(s) => Some(CString::new(s)?),
+            Some(s) => Some(secret::c_secret(s)?),
             None => None,
         };
         let local_username = match local_username {
@@ -617,7 +1021,7 @@ impl Session {
                 privatekey.as_ptr(),
                 passphrase
                     .as_ref()
-                    .map(|s| s.as_ptr())
+                    .map(|s| s.as_ptr() as *const _)
                     .unwrap_or(null()),
                 hostname.as_ptr() as *const _,
                 hostname.len() as c_uint,
@@ -627,38 +1031,91 @@ impl Session {
         })
     }
 
+    /// Attempt host-based authentication using the host's public and private
+    /// key material from memory, without writing it to disk.
+    ///
+    /// `publickeydata` is in the `authorized_keys` format (`type base64
+    /// [comment]`) and `privatekeydata` is PEM encoded. The other parameters
+    /// are the same as for `userauth_hostbased_file`.
+    ///
+    /// The keys are handed to libssh2 through anonymous memory-backed files
+    /// opened via `/proc/self/fd`, so this only works on Linux with `/proc`
+    /// mounted. Elsewhere it fails with `LIBSSH2_ERROR_METHOD_NOT_SUPPORTED`.
+    pub fn userauth_hostbased_memory(
+        &self,
+        username: &str,
+        publickeydata: &str,
+        privatekeydata: &str,
+        passphrase: Option<&str>,
+        hostname: &str,
+        local_username: Option<&str>,
+    ) -> Result<(), Error> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::prelude::*;
+
+            if Path::new("/proc/self/fd").is_dir() {
+                let publickey = util::memfd(publickeydata.as_bytes())?;
+                let privatekey = util::memfd(privatekeydata.as_bytes())?;
+                let path = |file: &::std::fs::File| format!("/proc/self/fd/{}", file.as_raw_fd());
+                return self.userauth_hostbased_file(
+                    username,
+                    Path::new(&path(&publickey)),
+                    Path::new(&path(&privatekey)),
+                    passphrase,
+                    hostname,
+                    local_username,
+                );
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (
+            username,
+            publickeydata,
+            privatekeydata,
+            passphrase,
+            hostname,
+            local_username,
+        );
+        Err(Error::new(
+            ErrorCode::Session(raw::LIBSSH2_ERROR_METHOD_NOT_SUPPORTED),
+            "in-memory host keys need Linux with /proc mounted",
+        ))
+    }
+
     /// Indicates whether or not the named session has been successfully
     /// authenticated.
     pub fn authenticated(&self) -> bool {
-        let inner = self.inner();
-        unsafe { raw::libssh2_userauth_authenticated(inner.raw) != 0 }
+        self.inner().authenticated()
     }
 
-    /// Send a SSH_USERAUTH_NONE request to the remote host.
+    /// Send a SSH_USERAUTH_NONE request to the remote host and return the
+    /// authentication methods it supports.
     ///
     /// Unless the remote host is configured to accept none as a viable
     /// authentication scheme (unlikely), it will return SSH_USERAUTH_FAILURE
-    /// along with a listing of what authentication schemes it does support. In
-    /// the unlikely event that none authentication succeeds, this method with
-    /// return an error. This case may be distinguished from a failing case by
-    /// examining the return value of the `authenticated` method.
+    /// along with a listing of what authentication schemes it does support.
+    /// In the unlikely event that none authentication succeeds the session is
+    /// now authenticated, the returned list is empty and its `authenticated`
+    /// method returns true.
+    pub fn auth_methods(&self, username: &str) -> Result<AuthMethods, Error> {
+        let inner = self.inner();
+        match inner.userauth_list(username)? {
+            Some(list) => Ok(AuthMethods::parse(list, false)),
+            None => Ok(AuthMethods::parse("", inner.authenticated())),
+        }
+    }
+
+    /// Attempt "none" authentication, which succeeds only if the remote host
+    /// lets `username` log in without any credentials.
     ///
-    /// The return value is a comma-separated string of supported auth schemes,
-    /// and may be an empty string.
-    pub fn auth_methods(&self, username: &str) -> Result<&str, Error> {
-        let len = username.len();
-        let username = CString::new(username)?;
+    /// Returns whether the session is now authenticated. Use `auth_methods`
+    /// instead to also learn which methods the server supports if it isn't.
+    pub fn userauth_none(&self, username: &str) -> Result<bool, Error> {
         let inner = self.inner();
-        unsafe {
-            let ret = raw::libssh2_userauth_list(inner.raw, username.as_ptr(), len as c_uint);
-            if ret.is_null() {
-                match inner.last_error() {
-                    Some(err) => Err(err),
-                    None => Ok(""),
-                }
-            } else {
-                Ok(str::from_utf8(::opt_bytes(self, ret).unwrap()).unwrap())
-            }
+        match inner.userauth_list(username)? {
+            Some(_) => Ok(false),
+            None => Ok(inner.authenticated()),
         }
     }
 
@@ -736,6 +1193,62 @@ impl Session {
         Ok(ret)
     }
 
+    /// Prefer the host key algorithms in `preferred`, most preferred first,
+    /// over the other supported ones, replacing any earlier `HostKey`
+    /// preference.
+    ///
+    /// Algorithms libssh2 doesn't support are skipped. If none are left the
+    /// default preference is kept.
+    pub fn prefer_host_key_algorithms(&self, preferred: &[&str]) -> Result<(), Error> {
+        let supported = self.supported_algs(MethodType::HostKey)?;
+        let mut prefs = preferred
+            .iter()
+            .cloned()
+            .filter(|alg| supported.contains(alg))
+            .collect::<Vec<_>>();
+        if prefs.is_empty() {
+            return Ok(());
+        }
+        for alg in supported {
+            if !prefs.contains(&alg) {
+                prefs.push(alg);
+            }
+        }
+        self.method_pref(MethodType::HostKey, &prefs.join(","))
+    }
+
+    /// Prefer the host key algorithms of the keys `known_hosts` has for
+    /// `host`, so that the server offers a key which can be checked rather
+    /// than one of a type which isn't known yet.
+    ///
+    /// This must be called before `handshake`, like OpenSSH does when
+    /// ordering its `HostKeyAlgorithms`.
+    ///
+    /// ```no_run
+    /// use ssh2::{KnownHostFileKind, Session};
+    /// use std::net::TcpStream;
+    /// use std::path::Path;
+    ///
+    /// let mut sess = Session::new().unwrap();
+    /// let mut known_hosts = sess.known_hosts().unwrap();
+    /// known_hosts
+    ///     .read_file(Path::new("known_hosts"), KnownHostFileKind::OpenSSH)
+    ///     .unwrap();
+    /// sess.prefer_known_host_keys(&known_hosts, "example.com", 22)
+    ///     .unwrap();
+    /// sess.set_tcp_stream(TcpStream::connect("example.com:22").unwrap());
+    /// sess.handshake().unwrap();
+    /// ```
+    pub fn prefer_known_host_keys(
+        &self,
+        known_hosts: &KnownHosts,
+        host: &str,
+        port: u16,
+    ) -> Result<(), Error> {
+        let algorithms = known_hosts.host_key_algorithms(host, port)?;
+        self.prefer_host_key_algorithms(&algorithms)
+    }
+
     /// Init an ssh-agent handle.
     ///
     /// The returned agent will still need to be connected manually before use.
@@ -872,6 +1385,33 @@ impl Session {
         }
     }
 
+    /// Returns the next X11 connection the server has opened, if any.
+    ///
+    /// The server opens them after `Channel::request_x11`, when remote
+    /// programs connect to the display it set up. They arrive while data is
+    /// read from the session, so this never waits for one.
+    pub fn accept_x11(&self) -> Option<X11Channel> {
+        let mut inner = self.inner();
+        let (raw, host, port) = inner.opened.as_mut()?.x11.pop_front()?;
+        drop(inner);
+        Channel::from_raw_opt(raw, None, &self.inner)
+            .ok()
+            .map(|channel| X11Channel::new(channel, host, port))
+    }
+
+    /// Returns the next agent connection the server has opened, if any.
+    ///
+    /// The server opens them after `Channel::request_auth_agent_forwarding`,
+    /// when remote programs use the forwarded agent. They arrive while data
+    /// is read from the session, so this never waits for one. See
+    /// `AgentServer::forward` for serving them.
+    pub fn accept_auth_agent(&self) -> Option<Channel> {
+        let mut inner = self.inner();
+        let raw = inner.opened.as_mut()?.auth_agent.pop_front()?;
+        drop(inner);
+        Channel::from_raw_opt(raw, None, &self.inner).ok()
+    }
+
     /// Request a file from the remote host via SCP.
     ///
     /// The path specified is a path on the remote host which will attempt to be
@@ -1117,6 +1657,11 @@ impl Session {
         self.inner.lock()
     }
 
+    /// Returns a handle to the session which `inner` belongs to.
+    pub(crate) fn from_inner(inner: Arc<Mutex<SessionInner>>) -> Session {
+        Session { inner }
+    }
+
     /// Sets the trace level for the session.
     ///
     pub fn trace(&self, bitmask: TraceFlags) {
@@ -1148,6 +1693,32 @@ impl AsRawSocket for Session {
 }
 
 impl SessionInner {
+    /// Sends the "none" authentication request, returning the list of
+    /// methods the server supports, or `None` if no list was received, which
+    /// happens when the none request succeeded.
+    fn userauth_list(&self, username: &str) -> Result<Option<&str>, Error> {
+        let len = username.len();
+        let username = CString::new(username)?;
+        unsafe {
+            let ret = raw::libssh2_userauth_list(self.raw, username.as_ptr(), len as c_uint);
+            if !ret.is_null() {
+                let list = ::std::ffi::CStr::from_ptr(ret).to_bytes();
+                return Ok(Some(str::from_utf8(list).unwrap_or("")));
+            }
+            if self.authenticated() {
+                return Ok(None);
+            }
+            match self.last_error() {
+                Some(err) => Err(err),
+                None => Ok(None),
+            }
+        }
+    }
+
+    fn authenticated(&self) -> bool {
+        unsafe { raw::libssh2_userauth_authenticated(self.raw) != 0 }
+    }
+
     /// Translate a return code into a Rust-`Result`.
     pub fn rc(&self, rc: c_int) -> Result<(), Error> {
         if rc >= 0 {
@@ -1170,6 +1741,56 @@ impl SessionInner {
     pub fn is_blocking(&self) -> bool {
         unsafe { raw::libssh2_session_get_blocking(self.raw) != 0 }
     }
+
+    /// Calls `f` with the session in non-blocking mode, and restores the
+    /// mode afterwards. The session is locked throughout, so other users of
+    /// the session never see the change.
+    pub fn nonblocking<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        let blocking = self.is_blocking();
+        if blocking {
+            self.set_blocking(false);
+        }
+        let result = f();
+        if blocking {
+            self.set_blocking(true);
+        }
+        result
+    }
+
+    /// Makes libssh2 accept X11 channels from the server, queueing them for
+    /// `Session::accept_x11`. Without the callback they are refused.
+    pub(crate) fn enable_x11(&mut self) {
+        self.set_open_callback(raw::LIBSSH2_CALLBACK_X11, x11::x11_open as *mut c_void);
+    }
+
+    /// Makes libssh2 accept agent channels from the server, queueing them
+    /// for `Session::accept_auth_agent`. Without the callback they are
+    /// refused.
+    pub(crate) fn enable_auth_agent(&mut self) {
+        self.set_open_callback(
+            raw::LIBSSH2_CALLBACK_AUTHAGENT,
+            auth_agent_open as *mut c_void,
+        );
+    }
+
+    /// Installs a callback which queues channels opened by the server.
+    ///
+    /// The queues are reached through the session's abstract pointer, which
+    /// `with_abstract` only borrows during authentication, before any
+    /// forwarding can have been requested.
+    fn set_open_callback(&mut self, cbtype: c_int, callback: *mut c_void) {
+        if self.opened.is_none() {
+            let mut opened = Box::new(OpenedChannels::default());
+            unsafe {
+                *raw::libssh2_session_abstract(self.raw) =
+                    &mut *opened as *mut OpenedChannels as *mut c_void;
+            }
+            self.opened = Some(opened);
+        }
+        unsafe {
+            let _ = raw::libssh2_session_callback_set(self.raw, cbtype, callback);
+        }
+    }
 }
 
 impl Drop for SessionInner {
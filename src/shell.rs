@@ -0,0 +1,523 @@
+//! An interactive shell on a pseudo terminal, driven from the local terminal.
+
+use libc::{self, c_int};
+use std::env;
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use pump::{self, Local, Pump};
+use util::{self, nonblocking};
+use {Error, ExitStatus, PtyModes, Session};
+
+/// Set by the `SIGWINCH` handler when the local terminal was resized.
+static WINCH: AtomicBool = AtomicBool::new(false);
+
+/// Runs an interactive login shell on the remote host, connected to the
+/// local terminal, much like `ssh` does without a command.
+///
+/// While the shell runs, the local terminal is put in raw mode, so that keys
+/// such as `^C` go to the remote end, and changes to its window size are
+/// passed on with `Channel::request_pty_size`. The terminal's mode is
+/// restored when the shell ends, even if it ends with an error.
+///
+/// As with OpenSSH, the escape character (`~` unless changed) is
+/// recognized at the start of a line:
+///
+/// * `~.` closes the channel,
+/// * `~B` sends a break,
+/// * `~^Z` suspends the local process,
+/// * `~?` lists the escapes,
+/// * `~~` sends the escape character itself.
+///
+/// The session must be in non-blocking mode, so that keys can be sent while
+/// waiting for output. The window size is only followed for one shell at a
+/// time, as it is signalled to the whole process.
+///
+/// # Example
+///
+/// ```no_run
+/// use ssh2::{InteractiveShell, Session};
+///
+/// # let sess: Session = panic!();
+/// sess.set_blocking(false);
+/// let status = InteractiveShell::new().run(&sess).unwrap();
+/// println!("shell exited: {}", status);
+/// ```
+pub struct InteractiveShell {
+    term: String,
+    modes: Option<PtyModes>,
+    escape: Option<u8>,
+}
+
+impl Default for InteractiveShell {
+    fn default() -> InteractiveShell {
+        InteractiveShell::new()
+    }
+}
+
+impl InteractiveShell {
+    /// Create a builder for an interactive shell.
+    ///
+    /// The terminal type defaults to `$TERM`, or `xterm` when that isn't
+    /// set, and the escape character to `~`.
+    pub fn new() -> InteractiveShell {
+        InteractiveShell {
+            term: env::var("TERM").unwrap_or_else(|_| "xterm".to_string()),
+            modes: None,
+            escape: Some(b'~'),
+        }
+    }
+
+    /// Set the terminal type requested for the pseudo terminal.
+    pub fn term(&mut self, term: &str) -> &mut InteractiveShell {
+        self.term = term.to_string();
+        self
+    }
+
+    /// Set the terminal modes requested for the pseudo terminal.
+    pub fn modes(&mut self, modes: PtyModes) -> &mut InteractiveShell {
+        self.modes = Some(modes);
+        self
+    }
+
+    /// Set the escape character, or turn escapes off with `None`.
+    pub fn escape_char(&mut self, escape: Option<u8>) -> &mut InteractiveShell {
+        self.escape = escape;
+        self
+    }
+
+    /// Run the shell on the terminal of this process's stdin and stdout,
+    /// returning how it exited.
+    pub fn run(&self, sess: &Session) -> Result<ExitStatus, Error> {
+        let _ = io::stdout().flush();
+        self.run_on(sess, &io::stdin(), &io::stdout())
+    }
+
+    /// Run the shell with keys read from `input` and its output written to
+    /// `output`, returning how it exited.
+    ///
+    /// `input` is put in raw mode, and its window size is the one passed
+    /// on, if it is a terminal. The status is `ExitStatus::Unknown` if the
+    /// channel was closed with `~.` before the shell exited.
+    pub fn run_on<I: AsRawFd, O: AsRawFd>(
+        &self,
+        sess: &Session,
+        input: &I,
+        output: &O,
+    ) -> Result<ExitStatus, Error> {
+        pump::check_nonblocking(sess)?;
+        let (input, output) = (input.as_raw_fd(), output.as_raw_fd());
+        let _winch = WinchHandler::install();
+        WINCH.store(false, Ordering::SeqCst);
+
+        let mut channel = drive(sess, || sess.channel_session())?;
+        let size = window_size(input).unwrap_or((80, 24, 0, 0));
+        drive(sess, || {
+            channel.request_pty(&self.term, self.modes.clone(), Some(size))
+        })?;
+        drive(sess, || channel.shell())?;
+
+        let terminal = Terminal::new(input, output, self.escape);
+        let mut pump = Pump::new(channel.clone(), terminal).with_stderr();
+        let mut resize = None;
+        let mut disconnected = false;
+        while !pump.received_all() {
+            if WINCH.swap(false, Ordering::SeqCst) {
+                resize = window_size(input);
+            }
+            if let Some((width, height, width_px, height_px)) = resize {
+                let request =
+                    channel.request_pty_size(width, height, Some(width_px), Some(height_px));
+                if nonblocking(request)?.is_some() {
+                    resize = None;
+                }
+            }
+            {
+                let terminal = pump.local_mut();
+                if terminal.disconnect {
+                    disconnected = true;
+                    break;
+                }
+                if terminal.send_break {
+                    match nonblocking(channel.send_break()) {
+                        Ok(None) => {}
+                        // A refused break isn't worth ending the shell for.
+                        Ok(Some(())) | Err(_) => terminal.send_break = false,
+                    }
+                }
+                // Keys typed after a resize or break go after it.
+                terminal.hold = resize.is_some() || terminal.send_break;
+            }
+            if !pump.step()? {
+                pump.wait(sess);
+            }
+        }
+        pump::close(&mut channel, sess)?;
+        if disconnected {
+            // Closing makes libssh2 consider the channel finished, with a
+            // status of 0.
+            return Ok(ExitStatus::Unknown);
+        }
+        channel.process_status()
+    }
+}
+
+/// Calls `f` until it no longer fails with `LIBSSH2_ERROR_EAGAIN`, waiting
+/// for the session in between.
+fn drive<T, F>(sess: &Session, mut f: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    loop {
+        if let Some(value) = nonblocking(f())? {
+            return Ok(value);
+        }
+        util::wait_session(sess, None);
+    }
+}
+
+/// Returns the size of the terminal `fd` as the columns, rows, width and
+/// height in pixels which `request_pty` takes.
+fn window_size(fd: RawFd) -> Option<(u32, u32, u32, u32)> {
+    unsafe {
+        let mut size = mem::zeroed::<libc::winsize>();
+        if libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) != 0 || size.ws_col == 0 {
+            return None;
+        }
+        Some((
+            size.ws_col as u32,
+            size.ws_row as u32,
+            size.ws_xpixel as u32,
+            size.ws_ypixel as u32,
+        ))
+    }
+}
+
+extern "C" fn on_winch(_: c_int) {
+    WINCH.store(true, Ordering::SeqCst);
+}
+
+/// Catches `SIGWINCH` until dropped.
+struct WinchHandler {
+    old: Option<libc::sigaction>,
+}
+
+impl WinchHandler {
+    fn install() -> WinchHandler {
+        unsafe {
+            let mut action = mem::zeroed::<libc::sigaction>();
+            action.sa_sigaction = on_winch as extern "C" fn(c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            let _ = libc::sigemptyset(&mut action.sa_mask);
+            let mut old = mem::zeroed::<libc::sigaction>();
+            if libc::sigaction(libc::SIGWINCH, &action, &mut old) != 0 {
+                return WinchHandler { old: None };
+            }
+            WinchHandler { old: Some(old) }
+        }
+    }
+}
+
+impl Drop for WinchHandler {
+    fn drop(&mut self) {
+        if let Some(ref old) = self.old {
+            unsafe {
+                let _ = libc::sigaction(libc::SIGWINCH, old, null_mut());
+            }
+        }
+    }
+}
+
+/// Puts a terminal in raw mode until dropped.
+struct RawMode {
+    fd: RawFd,
+    saved: Option<libc::termios>,
+}
+
+impl RawMode {
+    fn new(fd: RawFd) -> RawMode {
+        unsafe {
+            let mut termios = mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(fd, &mut termios) != 0 {
+                return RawMode { fd, saved: None };
+            }
+            let raw = RawMode {
+                fd,
+                saved: Some(termios),
+            };
+            raw.enter();
+            raw
+        }
+    }
+
+    fn enter(&self) {
+        if let Some(mut termios) = self.saved {
+            unsafe {
+                libc::cfmakeraw(&mut termios);
+                let _ = libc::tcsetattr(self.fd, libc::TCSADRAIN, &termios);
+            }
+        }
+    }
+
+    fn leave(&self) {
+        if let Some(ref saved) = self.saved {
+            unsafe {
+                let _ = libc::tcsetattr(self.fd, libc::TCSADRAIN, saved);
+            }
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        self.leave();
+    }
+}
+
+/// The local terminal, with escape sequences taken out of what is typed.
+struct Terminal {
+    input: RawFd,
+    output: RawFd,
+    raw: RawMode,
+    escape: Option<u8>,
+    line_start: bool,
+    escaped: bool,
+    hold: bool,
+    disconnect: bool,
+    send_break: bool,
+}
+
+impl Terminal {
+    fn new(input: RawFd, output: RawFd, escape: Option<u8>) -> Terminal {
+        Terminal {
+            input,
+            output,
+            raw: RawMode::new(input),
+            escape,
+            line_start: true,
+            escaped: false,
+            hold: false,
+            disconnect: false,
+            send_break: false,
+        }
+    }
+
+    fn readable(&self) -> io::Result<bool> {
+        let mut fd = libc::pollfd {
+            fd: self.input,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fd, 1, 0) } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    /// Copies `keys` to `buf`, acting on escape sequences instead of
+    /// copying them, and returns how much was copied.
+    fn filter(&mut self, keys: &[u8], buf: &mut [u8]) -> usize {
+        let escape = match self.escape {
+            Some(escape) => escape,
+            None => {
+                buf[..keys.len()].copy_from_slice(keys);
+                return keys.len();
+            }
+        };
+        let mut n = 0;
+        for &key in keys {
+            if self.escaped {
+                self.escaped = false;
+                match key {
+                    b'.' => {
+                        self.disconnect = true;
+                        break;
+                    }
+                    b'B' => self.send_break = true,
+                    b'?' => self.help(escape),
+                    0x1a => self.suspend(),
+                    _ if key == escape => {
+                        buf[n] = key;
+                        n += 1;
+                        self.line_start = false;
+                    }
+                    _ => {
+                        // Not an escape after all, so both keys go through.
+                        buf[n] = escape;
+                        buf[n + 1] = key;
+                        n += 2;
+                        self.line_start = key == b'\r' || key == b'\n';
+                    }
+                }
+            } else if self.line_start && key == escape {
+                self.escaped = true;
+            } else {
+                buf[n] = key;
+                n += 1;
+                self.line_start = key == b'\r' || key == b'\n';
+            }
+        }
+        n
+    }
+
+    fn help(&mut self, escape: u8) {
+        let escape = escape as char;
+        let help = format!(
+            "\r\nSupported escape sequences:\r\n \
+             {0}.   - terminate the shell\r\n \
+             {0}B   - send a BREAK to the remote system\r\n \
+             {0}^Z  - suspend\r\n \
+             {0}?   - this message\r\n \
+             {0}{0}   - send the escape character by typing it twice\r\n\
+             (Note that escapes are only recognized immediately after newline.)\r\n",
+            escape
+        );
+        let _ = self.write_all(help.as_bytes());
+    }
+
+    fn suspend(&mut self) {
+        self.raw.leave();
+        unsafe {
+            let _ = libc::raise(libc::SIGTSTP);
+        }
+        // Running again; the terminal may have changed in the meantime.
+        self.raw.enter();
+        WINCH.store(true, Ordering::SeqCst);
+    }
+}
+
+impl io::Read for Terminal {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.hold || self.disconnect || WINCH.load(Ordering::SeqCst) || !self.readable()? {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        // Room for an escape character held over from the last read.
+        let mut keys = [0; 1024];
+        let len = keys.len().min(buf.len() - 1);
+        let n = unsafe { libc::read(self.input, keys.as_mut_ptr() as *mut _, len) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n == 0 {
+            return Ok(0);
+        }
+        match self.filter(&keys[..n as usize], buf) {
+            // All of it was escapes; that isn't the end of input.
+            0 => Err(io::ErrorKind::WouldBlock.into()),
+            n => Ok(n),
+        }
+    }
+}
+
+impl Write for Terminal {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = unsafe { libc::write(self.output, buf.as_ptr() as *const _, buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Local for Terminal {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RawMode, Terminal};
+    use libc;
+    use std::mem;
+    use std::ptr::null_mut;
+
+    /// A terminal which isn't one, so nothing is put in raw mode.
+    fn terminal(escape: Option<u8>) -> Terminal {
+        Terminal::new(-1, -1, escape)
+    }
+
+    fn filter(terminal: &mut Terminal, keys: &[u8]) -> Vec<u8> {
+        let mut buf = [0; 64];
+        let n = terminal.filter(keys, &mut buf);
+        buf[..n].to_vec()
+    }
+
+    #[test]
+    fn filter_escapes() {
+        let mut t = terminal(Some(b'~'));
+        // Only at the start of a line.
+        assert_eq!(filter(&mut t, b"a~.b\r"), b"a~.b\r");
+        assert!(!t.disconnect);
+        assert_eq!(filter(&mut t, b"~~x"), b"~x");
+        assert_eq!(filter(&mut t, b"\n~x"), b"\n~x");
+        assert_eq!(filter(&mut t, b"\r~B"), b"\r");
+        assert!(t.send_break);
+        // An escape split across reads.
+        assert_eq!(filter(&mut t, b"\r~"), b"\r");
+        assert_eq!(filter(&mut t, b".ignored"), b"");
+        assert!(t.disconnect);
+    }
+
+    #[test]
+    fn filter_other_escape_char() {
+        let mut t = terminal(Some(b'#'));
+        assert_eq!(filter(&mut t, b"~.#x"), b"~.#x");
+        assert!(!t.disconnect);
+        assert_eq!(filter(&mut t, b"\r#."), b"\r");
+        assert!(t.disconnect);
+    }
+
+    #[test]
+    fn filter_without_escapes() {
+        let mut t = terminal(None);
+        assert_eq!(filter(&mut t, b"~.~B"), b"~.~B");
+        assert!(!t.disconnect);
+        assert!(!t.send_break);
+    }
+
+    fn canonical(fd: libc::c_int) -> bool {
+        unsafe {
+            let mut termios = mem::zeroed::<libc::termios>();
+            assert_eq!(libc::tcgetattr(fd, &mut termios), 0);
+            termios.c_lflag & libc::ICANON != 0
+        }
+    }
+
+    #[test]
+    fn raw_mode_restored() {
+        let (mut master, mut slave) = (0, 0);
+        unsafe {
+            assert_eq!(
+                libc::openpty(&mut master, &mut slave, null_mut(), null_mut(), null_mut()),
+                0
+            );
+        }
+        assert!(canonical(slave));
+        {
+            let raw = RawMode::new(slave);
+            assert!(!canonical(slave));
+            raw.leave();
+            assert!(canonical(slave));
+            raw.enter();
+            assert!(!canonical(slave));
+        }
+        assert!(canonical(slave));
+        unsafe {
+            let _ = libc::close(slave);
+            let _ = libc::close(master);
+        }
+    }
+}
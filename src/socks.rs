@@ -0,0 +1,207 @@
+//! The server side of SOCKS4, SOCKS4a and SOCKS5 `CONNECT` requests, for
+//! dynamic forwarding.
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use {raw, Error, ErrorCode};
+
+const SOCKS4_GRANTED: u8 = 90;
+const SOCKS4_REJECTED: u8 = 91;
+
+const SOCKS5_SUCCEEDED: u8 = 0x00;
+const SOCKS5_GENERAL_FAILURE: u8 = 0x01;
+const SOCKS5_NOT_ALLOWED: u8 = 0x02;
+const SOCKS5_HOST_UNREACHABLE: u8 = 0x04;
+const SOCKS5_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const SOCKS5_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+const CONNECT: u8 = 1;
+
+/// A `CONNECT` request read from a client.
+pub struct Request {
+    version: u8,
+    pub host: String,
+    pub port: u16,
+}
+
+fn invalid() -> Error {
+    Error::new(
+        ErrorCode::Session(raw::LIBSSH2_ERROR_PROTO),
+        "invalid SOCKS request",
+    )
+}
+
+fn io_err(_: io::Error) -> Error {
+    Error::new(
+        ErrorCode::Session(raw::LIBSSH2_ERROR_SOCKET_RECV),
+        "SOCKS client connection failed",
+    )
+}
+
+fn read_u8<R: Read>(stream: &mut R) -> Result<u8, Error> {
+    let mut b = [0];
+    stream.read_exact(&mut b).map_err(io_err)?;
+    Ok(b[0])
+}
+
+fn read_port<R: Read>(stream: &mut R) -> Result<u16, Error> {
+    let mut b = [0; 2];
+    stream.read_exact(&mut b).map_err(io_err)?;
+    Ok(u16::from_be_bytes(b))
+}
+
+/// Reads up to a NUL byte, as SOCKS4 user IDs and SOCKS4a host names end.
+fn read_cstr<R: Read>(stream: &mut R) -> Result<Vec<u8>, Error> {
+    let mut s = Vec::new();
+    loop {
+        match read_u8(stream)? {
+            0 => return Ok(s),
+            _ if s.len() >= 255 => return Err(invalid()),
+            b => s.push(b),
+        }
+    }
+}
+
+/// Reads the greeting and `CONNECT` request of a client. Requests which
+/// can't be served are answered with a failure here.
+pub fn read_request<S: Read + Write>(stream: &mut S) -> Result<Request, Error> {
+    match read_u8(stream)? {
+        4 => read_socks4(stream),
+        5 => read_socks5(stream),
+        _ => Err(invalid()),
+    }
+}
+
+fn read_socks4<S: Read + Write>(stream: &mut S) -> Result<Request, Error> {
+    let command = read_u8(stream)?;
+    let port = read_port(stream)?;
+    let mut ip = [0; 4];
+    stream.read_exact(&mut ip).map_err(io_err)?;
+    let _user = read_cstr(stream)?;
+    // SOCKS4a: 0.0.0.x, with x not 0, means a host name follows.
+    let host = if ip[..3] == [0, 0, 0] && ip[3] != 0 {
+        String::from_utf8(read_cstr(stream)?).map_err(|_| invalid())?
+    } else {
+        Ipv4Addr::from(ip).to_string()
+    };
+    let request = Request {
+        version: 4,
+        host,
+        port,
+    };
+    if command != CONNECT {
+        let _ = request.fail(stream, SOCKS4_REJECTED);
+        return Err(unsupported());
+    }
+    Ok(request)
+}
+
+fn read_socks5<S: Read + Write>(stream: &mut S) -> Result<Request, Error> {
+    let count = read_u8(stream)?;
+    let mut methods = vec![0; count as usize];
+    stream.read_exact(&mut methods).map_err(io_err)?;
+    // Only "no authentication" is offered; the client is local.
+    if !methods.contains(&0) {
+        let _ = stream.write_all(&[5, 0xff]);
+        return Err(Error::new(
+            ErrorCode::Session(raw::LIBSSH2_ERROR_PROTO),
+            "SOCKS client requires authentication",
+        ));
+    }
+    stream.write_all(&[5, 0]).map_err(io_err)?;
+
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).map_err(io_err)?;
+    if header[0] != 5 {
+        return Err(invalid());
+    }
+    let host = match header[3] {
+        1 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).map_err(io_err)?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut name = vec![0; read_u8(stream)? as usize];
+            stream.read_exact(&mut name).map_err(io_err)?;
+            String::from_utf8(name).map_err(|_| invalid())?
+        }
+        4 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).map_err(io_err)?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => {
+            let _ = stream.write_all(&socks5_reply(SOCKS5_ADDRESS_NOT_SUPPORTED));
+            return Err(unsupported());
+        }
+    };
+    let request = Request {
+        version: 5,
+        host,
+        port: read_port(stream)?,
+    };
+    if header[1] != CONNECT {
+        let _ = request.fail(stream, SOCKS5_COMMAND_NOT_SUPPORTED);
+        return Err(unsupported());
+    }
+    Ok(request)
+}
+
+fn unsupported() -> Error {
+    Error::new(
+        ErrorCode::Session(raw::LIBSSH2_ERROR_PROTO),
+        "unsupported SOCKS request",
+    )
+}
+
+fn socks5_reply(code: u8) -> [u8; 10] {
+    // The bound address isn't known; clients ignore it for CONNECT.
+    [5, code, 0, 1, 0, 0, 0, 0, 0, 0]
+}
+
+impl Request {
+    /// Tells the client its connection is through.
+    pub fn succeed<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        match self.version {
+            4 => stream.write_all(&[0, SOCKS4_GRANTED, 0, 0, 0, 0, 0, 0]),
+            _ => stream.write_all(&socks5_reply(SOCKS5_SUCCEEDED)),
+        }
+    }
+
+    /// Tells the client the channel couldn't be opened, with the reply code
+    /// closest to why.
+    pub fn fail_open<W: Write>(&self, stream: &mut W, err: &Error) -> io::Result<()> {
+        match self.version {
+            4 => self.fail(stream, SOCKS4_REJECTED),
+            _ => self.fail(stream, socks5_code(err)),
+        }
+    }
+
+    fn fail<W: Write>(&self, stream: &mut W, code: u8) -> io::Result<()> {
+        match self.version {
+            4 => stream.write_all(&[0, code, 0, 0, 0, 0, 0, 0]),
+            _ => stream.write_all(&socks5_reply(code)),
+        }
+    }
+}
+
+/// Picks the SOCKS5 reply code for a failure to open a `direct-tcpip`
+/// channel. libssh2 only passes on the reason the server gave in the
+/// message.
+pub fn socks5_code(err: &Error) -> u8 {
+    match err.code() {
+        ErrorCode::Session(raw::LIBSSH2_ERROR_CHANNEL_FAILURE) => {
+            let msg = err.message();
+            if msg.contains("administratively prohibited") {
+                SOCKS5_NOT_ALLOWED
+            } else if msg.contains("connect failed") {
+                SOCKS5_HOST_UNREACHABLE
+            } else {
+                SOCKS5_GENERAL_FAILURE
+            }
+        }
+        _ => SOCKS5_GENERAL_FAILURE,
+    }
+}
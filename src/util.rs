@@ -1,7 +1,11 @@
 use std::borrow::Cow;
+use std::cmp;
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use {raw, Error, ErrorCode};
+use {raw, Error, ErrorCode, Session};
 
 #[cfg(unix)]
 pub fn path2bytes(p: &Path) -> Result<Cow<'_, [u8]>, Error> {
@@ -37,6 +41,82 @@ pub fn path2bytes(p: &Path) -> Result<Cow<'_, [u8]>, Error> {
         .and_then(check)
 }
 
+/// Returns `path` with `suffix` appended to its file name.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Creates an anonymous, memory-backed file holding `data`, which can be
+/// passed to libssh2 functions wanting a path through `/proc/self/fd`.
+#[cfg(target_os = "linux")]
+pub fn memfd(data: &[u8]) -> Result<::std::fs::File, Error> {
+    use libc;
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::prelude::*;
+
+    let err = || {
+        Error::new(
+            ErrorCode::Session(raw::LIBSSH2_ERROR_FILE),
+            "unable to create an in-memory key file",
+        )
+    };
+    let fd = unsafe { libc::memfd_create(b"ssh2-key\0".as_ptr() as *const _, libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(err());
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(data).map_err(|_| err())?;
+    Ok(file)
+}
+
+/// Turns a `LIBSSH2_ERROR_EAGAIN` error into `None`.
+pub fn nonblocking<T>(result: Result<T, Error>) -> Result<Option<T>, Error> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(ref e) if e.code() == ErrorCode::Session(raw::LIBSSH2_ERROR_EAGAIN) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Waits for the session's socket to become ready in the direction libssh2
+/// is blocked on, for loops driving a non-blocking session.
+///
+/// Returns early after a short while, and at `deadline`, so that callers
+/// can also check on other sources of data.
+pub fn wait_session(sess: &Session, deadline: Option<Instant>) {
+    let mut wait = Duration::from_millis(100);
+    if let Some(deadline) = deadline {
+        wait = cmp::min(wait, deadline.saturating_duration_since(Instant::now()));
+    }
+    #[cfg(unix)]
+    {
+        use libc;
+        use std::os::unix::prelude::*;
+        use BlockDirections;
+
+        let events = match sess.block_directions() {
+            BlockDirections::Inbound => libc::POLLIN,
+            BlockDirections::Outbound => libc::POLLOUT,
+            BlockDirections::Both => libc::POLLIN | libc::POLLOUT,
+            BlockDirections::None => {
+                thread::sleep(cmp::min(wait, Duration::from_millis(10)));
+                return;
+            }
+        };
+        let mut fd = libc::pollfd {
+            fd: sess.as_raw_fd(),
+            events,
+            revents: 0,
+        };
+        let _ = unsafe { libc::poll(&mut fd, 1, wait.as_millis() as libc::c_int) };
+    }
+    #[cfg(not(unix))]
+    thread::sleep(cmp::min(wait, Duration::from_millis(10)));
+}
+
 #[cfg(unix)]
 pub fn mkpath(bytes: &[u8]) -> PathBuf {
     use std::ffi::OsStr;
@@ -59,3 +139,49 @@ fn check(b: Cow<[u8]>) -> Result<Cow<[u8]>, Error> {
         Ok(b)
     }
 }
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 encoding, with `=` padding if `pad` is set.
+pub fn base64_encode(data: &[u8], pad: bool) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else if pad {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decodes standard base64, with or without padding. Returns `None` if `data`
+/// isn't valid base64.
+pub fn base64_decode(data: &str) -> Option<Vec<u8>> {
+    let data = data.trim_end_matches('=').as_bytes();
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &c in data {
+        let val = BASE64.iter().position(|&b| b == c)? as u32;
+        acc = acc << 6 | val;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    if bits >= 6 {
+        return None;
+    }
+    Some(out)
+}
@@ -0,0 +1,139 @@
+//! Helpers for the SSH wire encoding described in RFC 4251 section 5.
+//!
+//! libssh2 takes care of the transport protocol itself, but a few places in
+//! this crate need to look inside the data that libssh2 hands back to us.
+
+use std::str;
+
+use {raw, Error, ErrorCode};
+
+/// A cursor over SSH-encoded data.
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data }
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() < len {
+            return Err(truncated());
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, Error> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, Error> {
+        let b = self.bytes(4)?;
+        Ok((b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32)
+    }
+
+    /// Reads a length-prefixed `string`.
+    pub(crate) fn string(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    /// Returns whether all of the data has been consumed.
+    #[cfg(feature = "keys")]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Consumes and returns all of the remaining data.
+    #[cfg(feature = "keys")]
+    pub(crate) fn rest(&mut self) -> &'a [u8] {
+        let rest = self.data;
+        self.data = &[];
+        rest
+    }
+
+    /// Reads a `string` which is expected to hold UTF-8 text, such as an
+    /// algorithm name.
+    pub(crate) fn str(&mut self) -> Result<&'a str, Error> {
+        str::from_utf8(self.string()?).map_err(|_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_PROTO),
+                "SSH wire data contained invalid utf-8",
+            )
+        })
+    }
+}
+
+/// A buffer which SSH-encoded data is appended to.
+pub(crate) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub(crate) fn new() -> Writer {
+        Writer { buf: Vec::new() }
+    }
+
+    /// Creates a writer with room for `capacity` bytes, so that secrets
+    /// written within it are never left behind by a reallocation.
+    pub(crate) fn with_capacity(capacity: usize) -> Writer {
+        Writer {
+            buf: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub(crate) fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub(crate) fn u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&[
+            (val >> 24) as u8,
+            (val >> 16) as u8,
+            (val >> 8) as u8,
+            val as u8,
+        ]);
+    }
+
+    /// Appends `data` without a length prefix.
+    pub(crate) fn raw(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Appends a length-prefixed `string`.
+    pub(crate) fn string(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.raw(data);
+    }
+
+    pub(crate) fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+fn truncated() -> Error {
+    Error::new(
+        ErrorCode::Session(raw::LIBSSH2_ERROR_PROTO),
+        "SSH wire data was truncated",
+    )
+}
+
+/// Extracts the public key algorithm name from the data signed during
+/// `publickey` authentication (RFC 4252 section 7).
+pub(crate) fn userauth_request_algorithm(data: &[u8]) -> Result<&str, Error> {
+    let mut r = Reader::new(data);
+    let _session_id = r.string()?;
+    let _msg = r.u8()?;
+    let _user = r.string()?;
+    let _service = r.string()?;
+    let _method = r.string()?;
+    let _has_signature = r.bool()?;
+    r.str()
+}
@@ -0,0 +1,456 @@
+use libc::{c_char, c_int, c_void};
+use std::env;
+use std::ffi::CStr;
+use std::fmt;
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use pump::{self, LocalStream, Pump};
+use session::OpenedChannels;
+use util::{self, nonblocking};
+use {raw, Channel, Error, ErrorCode, Session};
+
+/// The authentication protocol X servers use by default, and the one
+/// `X11Cookie::generate` creates cookies for.
+const MIT_MAGIC_COOKIE: &str = "MIT-MAGIC-COOKIE-1";
+
+/// The X11 port for display 0; display `n` listens on `6000 + n`.
+const X11_BASE_PORT: u16 = 6000;
+
+/// How long an X11 client gets to send its connection setup.
+const SETUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An X11 authentication cookie: the name of an authentication protocol and
+/// its data.
+#[derive(Clone, PartialEq, Eq)]
+pub struct X11Cookie {
+    protocol: String,
+    data: Vec<u8>,
+}
+
+impl X11Cookie {
+    /// Creates a cookie from a protocol name, such as `MIT-MAGIC-COOKIE-1`,
+    /// and its data.
+    pub fn new(protocol: &str, data: &[u8]) -> X11Cookie {
+        X11Cookie {
+            protocol: protocol.to_string(),
+            data: data.to_vec(),
+        }
+    }
+
+    /// Generates a random `MIT-MAGIC-COOKIE-1` cookie.
+    ///
+    /// Such a fake cookie is what should be sent to the server, so that the
+    /// real cookie of the local display never leaves this machine.
+    pub fn generate() -> Result<X11Cookie, Error> {
+        let mut data = [0u8; 16];
+        getrandom::getrandom(&mut data).map_err(|_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_RANDGEN),
+                "unable to generate an X11 cookie",
+            )
+        })?;
+        Ok(X11Cookie::new(MIT_MAGIC_COOKIE, &data))
+    }
+
+    /// Parses a cookie from a protocol name and its data as hex digits, the
+    /// way `xauth list` prints them.
+    pub fn from_hex(protocol: &str, hex: &str) -> Option<X11Cookie> {
+        if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+            return None;
+        }
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(X11Cookie::new(protocol, &data))
+    }
+
+    /// Returns the name of the authentication protocol.
+    pub fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    /// Returns the authentication data.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the authentication data as lowercase hex digits, the form
+    /// `Channel::request_x11` takes it in.
+    pub fn hex(&self) -> String {
+        self.data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl fmt::Debug for X11Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("X11Cookie")
+            .field("protocol", &self.protocol)
+            .finish()
+    }
+}
+
+/// An X11 connection opened by the server, as returned by
+/// `Session::accept_x11`.
+pub struct X11Channel {
+    channel: Channel,
+    originator: String,
+    originator_port: u16,
+}
+
+impl X11Channel {
+    pub(crate) fn new(channel: Channel, originator: String, originator_port: u16) -> X11Channel {
+        X11Channel {
+            channel,
+            originator,
+            originator_port,
+        }
+    }
+
+    /// Returns the channel carrying the connection.
+    pub fn channel(&mut self) -> &mut Channel {
+        &mut self.channel
+    }
+
+    /// Returns the address and port of the X11 client on the remote side,
+    /// as reported by the server.
+    pub fn originator(&self) -> (&str, u16) {
+        (&self.originator, self.originator_port)
+    }
+
+    /// Returns the channel carrying the connection.
+    pub fn into_channel(self) -> Channel {
+        self.channel
+    }
+}
+
+/// Called by libssh2, with the session locked, when the server opens an X11
+/// channel.
+pub(crate) extern "C" fn x11_open(
+    _sess: *mut raw::LIBSSH2_SESSION,
+    channel: *mut raw::LIBSSH2_CHANNEL,
+    shost: *const c_char,
+    sport: c_int,
+    abstrakt: *mut *mut c_void,
+) {
+    unsafe {
+        let opened = *abstrakt as *mut OpenedChannels;
+        if opened.is_null() {
+            return;
+        }
+        let host = if shost.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(shost).to_string_lossy().into_owned()
+        };
+        (*opened).x11.push_back((channel, host, sport as u16));
+    }
+}
+
+/// Where a display's X server listens.
+enum DisplayAddr {
+    #[cfg(unix)]
+    Unix(String),
+    Tcp(String, u16),
+}
+
+/// Parses a `DISPLAY` value, `[host]:display[.screen]`, into the address of
+/// the X server and the screen number.
+fn parse_display(display: &str) -> Option<(DisplayAddr, i32)> {
+    let colon = display.rfind(':')?;
+    let (host, rest) = (&display[..colon], &display[colon + 1..]);
+    let (number, screen) = match rest.find('.') {
+        Some(dot) => (&rest[..dot], rest[dot + 1..].parse().ok()?),
+        None => (rest, 0),
+    };
+    let number: u16 = number.parse().ok()?;
+    let addr = match host {
+        #[cfg(unix)]
+        "" | "unix" => DisplayAddr::Unix(format!("/tmp/.X11-unix/X{}", number)),
+        // A path to the socket, as XQuartz sets.
+        #[cfg(unix)]
+        _ if host.starts_with('/') => DisplayAddr::Unix(format!("{}:{}", host, number)),
+        _ => DisplayAddr::Tcp(
+            if host.is_empty() { "localhost" } else { host }.to_string(),
+            X11_BASE_PORT.checked_add(number)?,
+        ),
+    };
+    Some((addr, screen))
+}
+
+/// Forwards the X11 connections of remote programs to a local display,
+/// replacing the fake cookie given to the server with the display's real
+/// one, as `ssh -X` does.
+///
+/// Relaying shares the session with the rest of the program, so it needs the
+/// session to be in non-blocking mode.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::thread;
+/// use ssh2::{Session, X11Forwarding};
+///
+/// # let sess: Session = panic!();
+/// let x11 = X11Forwarding::from_env().unwrap();
+/// let mut channel = sess.channel_session().unwrap();
+/// x11.request(&mut channel, false).unwrap();
+/// channel.exec("xclock").unwrap();
+///
+/// sess.set_blocking(false);
+/// loop {
+///     // Reading from `channel` lets X11 channels arrive.
+///     while let Some(conn) = sess.accept_x11() {
+///         let (x11, sess) = (x11.clone(), sess.clone());
+///         thread::spawn(move || x11.relay(&sess, conn));
+///     }
+///     # break
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct X11Forwarding {
+    display: String,
+    screen: i32,
+    fake: X11Cookie,
+    real: Option<X11Cookie>,
+}
+
+impl X11Forwarding {
+    /// Forwards to `display`, given the way `DISPLAY` is, such as `:0` or
+    /// `localhost:10.0`, with a freshly generated fake cookie.
+    ///
+    /// The display's real cookie is unknown; see `real_cookie`.
+    pub fn new(display: &str) -> Result<X11Forwarding, Error> {
+        let (_, screen) = parse_display(display).ok_or_else(|| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_INVAL),
+                "invalid X11 display",
+            )
+        })?;
+        Ok(X11Forwarding {
+            display: display.to_string(),
+            screen,
+            fake: X11Cookie::generate()?,
+            real: None,
+        })
+    }
+
+    /// Forwards to the display named by `DISPLAY`, with the real cookie for
+    /// it from `xauth list`, if `xauth` knows one.
+    pub fn from_env() -> Result<X11Forwarding, Error> {
+        let display = env::var("DISPLAY").map_err(|_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_INVAL),
+                "DISPLAY is not set",
+            )
+        })?;
+        let mut forwarding = X11Forwarding::new(&display)?;
+        forwarding.real = xauth_cookie(&display);
+        Ok(forwarding)
+    }
+
+    /// Sets the cookie the local X server expects.
+    ///
+    /// Without one, connections are passed to the X server with the fake
+    /// cookie, which works for servers that don't check authentication.
+    pub fn real_cookie(&mut self, cookie: X11Cookie) -> &mut X11Forwarding {
+        self.real = Some(cookie);
+        self
+    }
+
+    /// Returns the display connections are forwarded to.
+    pub fn display(&self) -> &str {
+        &self.display
+    }
+
+    /// Returns the fake cookie given to the server.
+    pub fn fake_cookie(&self) -> &X11Cookie {
+        &self.fake
+    }
+
+    /// Requests X11 forwarding on a session channel, before starting a
+    /// command or shell on it, with the fake cookie and the display's screen.
+    ///
+    /// If `single_connection` is set, the server forwards only the first
+    /// X11 connection.
+    pub fn request(&self, channel: &mut Channel, single_connection: bool) -> Result<(), Error> {
+        channel.request_x11(
+            single_connection,
+            Some(self.fake.protocol()),
+            Some(&self.fake.hex()),
+            self.screen,
+        )
+    }
+
+    /// Connects an X11 channel from `Session::accept_x11` to the local
+    /// display, and relays its data until both sides are done.
+    ///
+    /// The connection is refused if the X11 client didn't authenticate with
+    /// the fake cookie, and fails with `LIBSSH2_ERROR_TIMEOUT` if it didn't
+    /// send its connection setup within 30 seconds. `sess` must be in
+    /// non-blocking mode.
+    pub fn relay(&self, sess: &Session, x11: X11Channel) -> Result<(), Error> {
+        pump::check_nonblocking(sess)?;
+        let mut channel = x11.channel;
+        let setup = read_setup(sess, &mut channel)?;
+        let setup = match self.rewrite_setup(&setup) {
+            Some(setup) => setup,
+            None => {
+                let _ = pump::close(&mut channel, sess);
+                return Err(Error::new(
+                    ErrorCode::Session(raw::LIBSSH2_ERROR_AUTHENTICATION_FAILED),
+                    "X11 connection used the wrong authentication cookie",
+                ));
+            }
+        };
+        let connect_err = || {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_SOCKET_SEND),
+                "unable to connect to the local X11 display",
+            )
+        };
+        match parse_display(&self.display).map(|(addr, _)| addr) {
+            #[cfg(unix)]
+            Some(DisplayAddr::Unix(path)) => {
+                let local = UnixStream::connect(path).map_err(|_| connect_err())?;
+                relay_to(sess, channel, local, &setup)
+            }
+            Some(DisplayAddr::Tcp(host, port)) => {
+                let local = TcpStream::connect((&*host, port)).map_err(|_| connect_err())?;
+                relay_to(sess, channel, local, &setup)
+            }
+            None => Err(connect_err()),
+        }
+    }
+
+    /// Checks the cookie in an X11 connection setup and swaps in the real
+    /// one. `setup` holds the whole setup request, and whatever followed it.
+    fn rewrite_setup(&self, setup: &[u8]) -> Option<Vec<u8>> {
+        let (name, data, len) = parse_setup(setup)?;
+        if name != self.fake.protocol.as_bytes() || data != &self.fake.data[..] {
+            return None;
+        }
+        let real = self.real.as_ref().unwrap_or(&self.fake);
+        let big_endian = setup[0] == b'B';
+        let encode = |n: usize| {
+            let n = n as u16;
+            if big_endian {
+                n.to_be_bytes()
+            } else {
+                n.to_le_bytes()
+            }
+        };
+        let mut out = setup[..6].to_vec();
+        out.extend_from_slice(&encode(real.protocol.len()));
+        out.extend_from_slice(&encode(real.data.len()));
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(real.protocol.as_bytes());
+        out.resize(12 + pad(real.protocol.len()), 0);
+        out.extend_from_slice(&real.data);
+        out.resize(12 + pad(real.protocol.len()) + pad(real.data.len()), 0);
+        out.extend_from_slice(&setup[len..]);
+        Some(out)
+    }
+}
+
+fn pad(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// Splits an X11 connection setup request into the authentication protocol
+/// name and data, and returns its length, or `None` if `setup` doesn't hold
+/// a whole request yet.
+fn parse_setup(setup: &[u8]) -> Option<(&[u8], &[u8], usize)> {
+    if setup.len() < 12 {
+        return None;
+    }
+    let field = |i: usize| match setup[0] {
+        b'B' => Some(u16::from_be_bytes([setup[i], setup[i + 1]]) as usize),
+        b'l' => Some(u16::from_le_bytes([setup[i], setup[i + 1]]) as usize),
+        _ => None,
+    };
+    let (name_len, data_len) = (field(6)?, field(8)?);
+    let len = 12 + pad(name_len) + pad(data_len);
+    if setup.len() < len {
+        return None;
+    }
+    let data_start = 12 + pad(name_len);
+    Some((
+        &setup[12..12 + name_len],
+        &setup[data_start..data_start + data_len],
+        len,
+    ))
+}
+
+/// Reads from `channel` until it holds a whole X11 connection setup request.
+fn read_setup(sess: &Session, channel: &mut Channel) -> Result<Vec<u8>, Error> {
+    let deadline = Instant::now() + SETUP_TIMEOUT;
+    let mut setup = Vec::new();
+    let mut buf = [0; 1024];
+    loop {
+        if parse_setup(&setup).is_some() {
+            return Ok(setup);
+        }
+        if setup.len() >= 12 && setup[0] != b'B' && setup[0] != b'l' {
+            return Err(Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_PROTO),
+                "invalid X11 connection setup",
+            ));
+        }
+        match nonblocking(channel.stream(0).read_(&mut buf))? {
+            Some(0) if channel.eof() => {
+                return Err(Error::new(
+                    ErrorCode::Session(raw::LIBSSH2_ERROR_CHANNEL_CLOSED),
+                    "X11 connection closed before its setup",
+                ))
+            }
+            Some(n) if n > 0 => setup.extend_from_slice(&buf[..n]),
+            _ => {
+                if Instant::now() >= deadline {
+                    return Err(Error::new(
+                        ErrorCode::Session(raw::LIBSSH2_ERROR_TIMEOUT),
+                        "timed out waiting for the X11 connection setup",
+                    ));
+                }
+                util::wait_session(sess, Some(deadline));
+            }
+        }
+    }
+}
+
+fn relay_to<S: LocalStream>(
+    sess: &Session,
+    channel: Channel,
+    mut local: S,
+    setup: &[u8],
+) -> Result<(), Error> {
+    local
+        .write_all(setup)
+        .and_then(|()| local.set_nonblocking(true))
+        .map_err(|_| {
+            Error::new(
+                ErrorCode::Session(raw::LIBSSH2_ERROR_SOCKET_SEND),
+                "unable to write to the local X11 display",
+            )
+        })?;
+    Pump::new(channel, local).run(sess, None)
+}
+
+/// Looks up the real cookie of `display` with `xauth list`.
+fn xauth_cookie(display: &str) -> Option<X11Cookie> {
+    let output = Command::new("xauth")
+        .arg("list")
+        .arg(display)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    let mut fields = stdout.lines().next()?.split_whitespace().skip(1);
+    X11Cookie::from_hex(fields.next()?, fields.next()?)
+}
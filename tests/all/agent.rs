@@ -1,4 +1,6 @@
-use ssh2::Session;
+use ssh2::{AgentClient, IdentityFilter, Session, SignFlags};
+use std::env;
+use std::path::Path;
 
 #[test]
 fn smoke() {
@@ -13,3 +15,315 @@ fn smoke() {
     }
     agent.disconnect().unwrap();
 }
+
+#[test]
+fn client_smoke() {
+    let sess = Session::new().unwrap();
+    let mut agent = sess.agent().unwrap();
+    agent.connect().unwrap();
+    agent.list_identities().unwrap();
+    let identities = agent.identities().unwrap();
+
+    let mut client = agent.client().unwrap();
+    assert_eq!(client.identities().unwrap(), identities);
+    let sig = client
+        .sign(&identities[0], b"challenge", SignFlags::RSA_SHA2_256)
+        .unwrap();
+    assert_eq!(sig.algorithm(), "rsa-sha2-256");
+    assert!(!sig.blob().is_empty());
+}
+
+#[test]
+fn client_userauth() {
+    let user = env::var("USER").unwrap();
+    let mut sess = Session::new().unwrap();
+    sess.set_tcp_stream(::socket());
+    sess.handshake().unwrap();
+
+    let mut client = AgentClient::connect_env().unwrap();
+    let identities = client.identities().unwrap();
+    client.userauth(&sess, &user, &identities[0]).unwrap();
+    assert!(sess.authenticated());
+}
+
+#[cfg(all(unix, feature = "keys"))]
+#[test]
+fn client_manage_keys() {
+    use ssh2::{KeyConstraints, KeyType, PrivateKey};
+    use std::process::{Command, Stdio};
+    use std::thread;
+    use std::time::Duration;
+
+    let td = tempfile::tempdir().unwrap();
+    let sock = td.path().join("agent.sock");
+    let mut child = Command::new("ssh-agent")
+        .arg("-D")
+        .arg("-a")
+        .arg(&sock)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    for _ in 0..50 {
+        if sock.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let mut client = AgentClient::connect(&sock).unwrap();
+    assert!(client.identities().unwrap().is_empty());
+
+    let key = PrivateKey::generate(KeyType::Ed25519, "managed@test").unwrap();
+    let constraints = KeyConstraints {
+        lifetime: Some(600),
+        confirm: false,
+    };
+    client.add_identity(&key, &constraints).unwrap();
+    let identities = client.identities().unwrap();
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].blob(), &key.public_key_blob()[..]);
+    assert_eq!(identities[0].comment(), "managed@test");
+
+    let sig = client
+        .sign(&identities[0], b"data", SignFlags::empty())
+        .unwrap();
+    assert_eq!(sig.algorithm(), "ssh-ed25519");
+    assert_eq!(sig.blob(), &key.sign("ssh-ed25519", b"data").unwrap()[..]);
+
+    client.lock("hunter2").unwrap();
+    assert!(client.identities().unwrap().is_empty());
+    assert!(client.unlock("wrong").is_err());
+    client.unlock("hunter2").unwrap();
+
+    client.remove_identity(&identities[0]).unwrap();
+    assert!(client.identities().unwrap().is_empty());
+    client
+        .add_identity(&key, &KeyConstraints::default())
+        .unwrap();
+    client.remove_all_identities().unwrap();
+    assert!(client.identities().unwrap().is_empty());
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[cfg(all(unix, feature = "keys"))]
+#[test]
+fn server_smoke() {
+    use ssh2::{AgentServer, KeyConstraints, KeyType, PrivateKey};
+    use std::process::Command;
+
+    let td = tempfile::tempdir().unwrap();
+    let sock = td.path().join("agent.sock");
+    let server = AgentServer::bind(&sock).unwrap();
+    let key = PrivateKey::generate(KeyType::Ecdsa256, "served@test").unwrap();
+    server.add_key(key.clone());
+
+    // The crate's own libssh2-backed agent.
+    let sess = Session::new().unwrap();
+    let mut agent = sess.agent().unwrap();
+    agent.set_identity_path(&sock).unwrap();
+    agent.connect().unwrap();
+    agent.list_identities().unwrap();
+    let identities = agent.identities().unwrap();
+    assert_eq!(identities, server.identities());
+    assert_eq!(identities[0].comment(), "served@test");
+
+    // The protocol client.
+    let mut client = AgentClient::connect(&sock).unwrap();
+    let sig = client
+        .sign(&identities[0], b"data", SignFlags::empty())
+        .unwrap();
+    assert_eq!(sig.algorithm(), "ecdsa-sha2-nistp256");
+
+    let added = PrivateKey::generate(KeyType::Ed25519, "added@test").unwrap();
+    let constraints = KeyConstraints {
+        lifetime: Some(600),
+        confirm: false,
+    };
+    client.add_identity(&added, &constraints).unwrap();
+    let all = client.identities().unwrap();
+    assert_eq!(all.len(), 2);
+    let sig = client.sign(&all[1], b"data", SignFlags::empty()).unwrap();
+    assert_eq!(sig.blob(), &added.sign("ssh-ed25519", b"data").unwrap()[..]);
+
+    client.lock("hunter2").unwrap();
+    assert!(client.identities().unwrap().is_empty());
+    assert!(client
+        .sign(&identities[0], b"data", SignFlags::empty())
+        .is_err());
+    assert!(client.unlock("wrong").is_err());
+    client.unlock("hunter2").unwrap();
+
+    // External tools.
+    let output = Command::new("ssh-add")
+        .arg("-l")
+        .env("SSH_AUTH_SOCK", &sock)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let listing = String::from_utf8(output.stdout).unwrap();
+    assert!(listing.contains("served@test"));
+    assert!(listing.contains("added@test"));
+
+    client.remove_all_identities().unwrap();
+    assert!(server.identities().is_empty());
+
+    drop(server);
+    assert!(!sock.exists());
+}
+
+#[cfg(all(unix, feature = "keys"))]
+#[test]
+fn server_drop_without_socket() {
+    use ssh2::AgentServer;
+    use std::fs;
+
+    // Dropping must not depend on reaching the socket.
+    let td = tempfile::tempdir().unwrap();
+    let sock = td.path().join("agent.sock");
+    let server = AgentServer::bind(&sock).unwrap();
+    fs::remove_file(&sock).unwrap();
+    drop(server);
+}
+
+#[cfg(feature = "keys")]
+#[test]
+fn server_forward() {
+    use ssh2::{AgentServer, KeyType, PrivateKey};
+    use std::io::{self, Read};
+    use std::thread;
+    use std::time::Duration;
+
+    let server = AgentServer::new();
+    let key = PrivateKey::generate(KeyType::Ed25519, "forwarded@test").unwrap();
+    server.add_key(key);
+
+    let sess = ::authed_session();
+    assert!(sess.accept_auth_agent().is_none());
+    let mut channel = sess.channel_session().unwrap();
+    channel.request_auth_agent_forwarding().unwrap();
+    channel.exec("ssh-add -l").unwrap();
+
+    sess.set_blocking(false);
+    let mut served = 0;
+    let mut output = Vec::new();
+    let mut buf = [0; 64];
+    loop {
+        match channel.read(&mut buf) {
+            Ok(0) if channel.eof() => break,
+            Ok(n) => output.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            Err(e) => panic!("{}", e),
+        }
+        while let Some(agent) = sess.accept_auth_agent() {
+            server.forward(&sess, agent).unwrap();
+            served += 1;
+        }
+    }
+    let listing = String::from_utf8(output).unwrap();
+    assert!(listing.contains("forwarded@test"));
+    assert_eq!(served, 1);
+}
+
+#[test]
+fn identity_details() {
+    let sess = Session::new().unwrap();
+    let mut agent = sess.agent().unwrap();
+    agent.connect().unwrap();
+    agent.list_identities().unwrap();
+    let identities = agent.identities().unwrap();
+    let identity = &identities[0];
+    assert_eq!(identity.algorithm(), "ssh-rsa");
+    assert!(identity.bits().unwrap() >= 2048);
+    assert!(!identity.is_certificate());
+    assert!(identity.fingerprint().starts_with("SHA256:"));
+
+    let mut filter = IdentityFilter::new();
+    filter
+        .public_key_file(Path::new("tests/sshd/id_rsa.pub"))
+        .unwrap();
+    assert_eq!(agent.filtered_identities(&filter).unwrap().len(), 1);
+
+    let mut filter = IdentityFilter::new();
+    filter.fingerprint(&identity.fingerprint());
+    assert!(filter.matches(identity));
+
+    let mut filter = IdentityFilter::new();
+    filter.comment("no such key");
+    assert!(agent.filtered_identities(&filter).unwrap().is_empty());
+}
+
+#[test]
+fn filter_needs_a_public_key() {
+    use ssh2::ErrorCode;
+    use std::fs;
+
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("empty.pub");
+    fs::write(&path, "# no keys here\n\n").unwrap();
+    let mut filter = IdentityFilter::new();
+    let err = filter.public_key_file(&path).err().unwrap();
+    assert_eq!(err.code(), ErrorCode::Session(-16)); // LIBSSH2_ERROR_FILE
+}
+
+#[test]
+fn userauth_filtered() {
+    let user = env::var("USER").unwrap();
+    let mut sess = Session::new().unwrap();
+    sess.set_tcp_stream(::socket());
+    sess.handshake().unwrap();
+
+    let mut filter = IdentityFilter::new();
+    filter.comment("no such key");
+    assert!(sess.userauth_agent_filtered(&user, &filter).is_err());
+
+    let mut filter = IdentityFilter::new();
+    filter
+        .public_key_file(Path::new("tests/sshd/id_rsa.pub"))
+        .unwrap();
+    sess.userauth_agent_filtered(&user, &filter).unwrap();
+    assert!(sess.authenticated());
+}
+
+#[cfg(all(unix, feature = "keys"))]
+#[test]
+fn identity_key_types() {
+    use ssh2::{AgentServer, KeyType, PrivateKey};
+    use std::process::Command;
+
+    let td = tempfile::tempdir().unwrap();
+    let server = AgentServer::new();
+    for &(key_type, name, bits) in &[
+        (KeyType::Ed25519, "ssh-ed25519", 256),
+        (KeyType::Ecdsa384, "ecdsa-sha2-nistp384", 384),
+        (KeyType::Rsa(2048), "ssh-rsa", 2048),
+    ] {
+        let key = PrivateKey::generate(key_type, name).unwrap();
+        let path = td.path().join(name);
+        key.write_openssh_file(&path, None).unwrap();
+        server.add_key(key);
+
+        let identities = server.identities();
+        let identity = identities.iter().find(|i| i.comment() == name).unwrap();
+        assert_eq!(identity.algorithm(), name);
+        assert_eq!(identity.bits(), Some(bits));
+
+        let output = Command::new("ssh-keygen")
+            .arg("-l")
+            .arg("-E")
+            .arg("sha256")
+            .arg("-f")
+            .arg(path.with_extension("pub"))
+            .output()
+            .unwrap();
+        let output = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(output.split(' ').nth(1), Some(&identity.fingerprint()[..]));
+
+        let mut filter = IdentityFilter::new();
+        filter.public_key_file(&path.with_extension("pub")).unwrap();
+        assert!(filter.matches(identity));
+    }
+}
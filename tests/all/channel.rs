@@ -1,4 +1,5 @@
-use ssh2::Channel;
+use ssh2::{Channel, ExitStatus, Signal};
+use std::io;
 use std::io::prelude::*;
 use std::net::{TcpListener, TcpStream};
 use std::thread;
@@ -270,6 +271,72 @@ fn exit_code_ignores_other_errors() {
     assert!(channel.exit_status().unwrap() == 0);
 }
 
+#[test]
+fn process_status() {
+    let sess = ::authed_session();
+    let mut channel = sess.channel_session().unwrap();
+    channel.exec("exit 7").unwrap();
+    assert_eq!(channel.process_status().unwrap(), ExitStatus::Unknown);
+    consume_stdio(&mut channel);
+    // The exit status may follow EOF, so it isn't known before the close.
+    assert!(channel.eof());
+    assert_eq!(channel.process_status().unwrap(), ExitStatus::Unknown);
+    channel.wait_close().unwrap();
+    let status = channel.process_status().unwrap();
+    assert_eq!(status, ExitStatus::Code(7));
+    assert!(!status.success());
+    assert_eq!(status.code(), Some(7));
+
+    let mut channel = sess.channel_session().unwrap();
+    channel.exec("kill -KILL $$").unwrap();
+    consume_stdio(&mut channel);
+    channel.wait_close().unwrap();
+    let status = channel.process_status().unwrap();
+    assert_eq!(status.signal(), Some(&Signal::KILL));
+    assert_eq!(status.code(), None);
+    assert_eq!(status.to_string(), "signal: SIGKILL");
+}
+
+#[test]
+fn signal() {
+    let sess = ::authed_session();
+    let mut channel = sess.channel_session().unwrap();
+    channel.exec("sleep 10").unwrap();
+    channel.signal(Signal::TERM).unwrap();
+    consume_stdio(&mut channel);
+    channel.wait_close().unwrap();
+    assert_eq!(
+        channel.process_status().unwrap().signal(),
+        Some(&Signal::TERM)
+    );
+}
+
+#[test]
+fn send_break() {
+    let sess = ::authed_session();
+    let mut channel = sess.channel_session().unwrap();
+    // OpenSSH passes breaks on to the pseudo terminal, and refuses them
+    // without one.
+    channel.request_pty("xterm", None, None).unwrap();
+    channel.exec("sleep 10").unwrap();
+    channel.send_break().unwrap();
+    channel.signal(Signal::KILL).unwrap();
+    channel.close().unwrap();
+}
+
+#[test]
+fn signal_names() {
+    assert_eq!(Signal::from_name("TERM"), Signal::TERM);
+    assert_eq!(Signal::TERM.name(), "TERM");
+    let other = Signal::from_name("WINCH");
+    assert_eq!(other, Signal::Other("WINCH".to_string()));
+    assert_eq!(other.to_string(), "WINCH");
+    assert!(ExitStatus::Code(0).success());
+    assert!(!ExitStatus::Unknown.success());
+    assert_eq!(ExitStatus::Unknown.code(), None);
+    assert_eq!(ExitStatus::Code(2).to_string(), "exit status: 2");
+}
+
 #[test]
 fn pty_modes_are_propagated() {
     let sess = ::authed_session();
@@ -290,3 +357,116 @@ fn pty_modes_are_propagated() {
     // This may well be linux specific
     assert!(out.contains("intr = y"), "mode was propagated");
 }
+
+/// Reads from `input` and collects what is written. Once `input` runs out,
+/// reading fails with `end` if it is set.
+struct Local {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+    end: Option<io::ErrorKind>,
+}
+
+impl Read for Local {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match (self.input.read(buf)?, self.end) {
+            (0, Some(kind)) => Err(io::Error::new(kind, "local side went away")),
+            (n, _) => Ok(n),
+        }
+    }
+}
+
+impl Write for Local {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn copy_bidirectional() {
+    let sess = ::authed_session();
+    let mut channel = sess.channel_session().unwrap();
+    channel.exec("cat; echo done >&2").unwrap();
+
+    // More than a window's worth each way, which the remote end only sends
+    // back while it is still being written to.
+    let data = vec![b'x'; 3 * 1024 * 1024];
+    let mut local = Local {
+        input: io::Cursor::new(data.clone()),
+        output: Vec::new(),
+        end: None,
+    };
+    let (sent, received) = channel.copy_bidirectional(&mut local).unwrap();
+    assert_eq!(sent, data.len() as u64);
+    assert_eq!(received, data.len() as u64 + 5);
+    assert!(local.output[..data.len()] == data[..]);
+    assert_eq!(&local.output[data.len()..], b"done\n");
+
+    assert!(sess.is_blocking());
+    assert!(channel.eof());
+    channel.wait_close().unwrap();
+    assert_eq!(channel.exit_status().unwrap(), 0);
+}
+
+#[test]
+fn copy_bidirectional_nonblocking() {
+    let sess = ::authed_session();
+    let mut channel = sess.channel_session().unwrap();
+    channel.exec("cat").unwrap();
+
+    let mut local = Local {
+        input: io::Cursor::new(b"data".to_vec()),
+        output: Vec::new(),
+        end: None,
+    };
+    sess.set_blocking(false);
+    let (sent, received) = channel.copy_bidirectional(&mut local).unwrap();
+    assert!(!sess.is_blocking());
+    sess.set_blocking(true);
+    assert_eq!((sent, received), (4, 4));
+    assert_eq!(local.output, b"data");
+    channel.wait_close().unwrap();
+}
+
+#[test]
+fn copy_bidirectional_remote_exit() {
+    let sess = ::authed_session();
+    let mut channel = sess.channel_session().unwrap();
+    channel.exec("echo bye").unwrap();
+
+    // The local side never ends, like an idle terminal.
+    let mut local = Local {
+        input: io::Cursor::new(Vec::new()),
+        output: Vec::new(),
+        end: Some(io::ErrorKind::WouldBlock),
+    };
+    let (sent, received) = channel.copy_bidirectional(&mut local).unwrap();
+    assert_eq!((sent, received), (0, 4));
+    assert_eq!(local.output, b"bye\n");
+    channel.wait_close().unwrap();
+    assert_eq!(channel.exit_status().unwrap(), 0);
+}
+
+#[test]
+fn copy_bidirectional_local_error() {
+    let sess = ::authed_session();
+    let mut channel = sess.channel_session().unwrap();
+    channel.exec("cat").unwrap();
+
+    let mut local = Local {
+        input: io::Cursor::new(b"partial".to_vec()),
+        output: Vec::new(),
+        end: Some(io::ErrorKind::ConnectionReset),
+    };
+    let err = channel.copy_bidirectional(&mut local).unwrap_err();
+    assert!(err.message().contains("local side went away"), "{}", err);
+    assert!(err.message().contains("ConnectionReset"), "{}", err);
+    // End of file was still sent, so the remote end finished.
+    assert_eq!(local.output, b"partial");
+    assert!(channel.eof());
+    channel.wait_close().unwrap();
+    assert_eq!(channel.exit_status().unwrap(), 0);
+}
@@ -0,0 +1,113 @@
+use ssh2::{ErrorCode, RemoteCommand, Session};
+use std::io::{self, Read};
+use std::time::Duration;
+
+fn session() -> Session {
+    let sess = ::authed_session();
+    sess.set_blocking(false);
+    sess
+}
+
+#[test]
+fn command_line() {
+    let mut cmd = RemoteCommand::new("printf '%s\\n'");
+    let _ = cmd.arg("plain-arg_1.txt").args(["two words", "it's", ""]);
+    assert_eq!(
+        cmd.command_line(),
+        "printf '%s\\n' plain-arg_1.txt 'two words' 'it'\\''s' ''"
+    );
+}
+
+#[test]
+fn output() {
+    let sess = session();
+    let output = RemoteCommand::new("printf '%s\\n'")
+        .args(["two words", "it's"])
+        .output(&sess)
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"two words\nit's\n");
+    assert!(output.stderr.is_empty());
+    assert!(!output.truncated);
+}
+
+#[test]
+fn stdin_and_exit_code() {
+    let sess = session();
+    let output = RemoteCommand::new("cat; echo done >&2; exit 3")
+        .stdin_data(&b"hello"[..])
+        .output(&sess)
+        .unwrap();
+    assert_eq!(output.stdout, b"hello");
+    assert_eq!(output.stderr, b"done\n");
+    assert!(!output.status.success());
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn large_output_on_both_streams() {
+    let sess = session();
+    // More than a channel window on each stream, written stderr first.
+    let output = RemoteCommand::new("head -c 3000000 /dev/zero >&2; head -c 3000000 /dev/zero")
+        .output(&sess)
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout.len(), 3_000_000);
+    assert_eq!(output.stderr.len(), 3_000_000);
+
+    let output = RemoteCommand::new("head -c 100000 /dev/zero")
+        .output_limit(1000)
+        .output(&sess)
+        .unwrap();
+    assert_eq!(output.stdout.len(), 1000);
+    assert!(output.truncated);
+}
+
+#[test]
+fn timeout() {
+    let sess = session();
+    let err = RemoteCommand::new("sleep 10")
+        .timeout(Duration::from_millis(200))
+        .status(&sess)
+        .unwrap_err();
+    assert_eq!(io::Error::from(err).kind(), io::ErrorKind::TimedOut);
+    assert!(!sess.is_blocking());
+}
+
+#[test]
+fn needs_nonblocking_session() {
+    let sess = Session::new().unwrap();
+    let err = RemoteCommand::new("true").status(&sess).unwrap_err();
+    // LIBSSH2_ERROR_BAD_USE
+    assert_eq!(err.code(), ErrorCode::Session(-39));
+    assert!(sess.is_blocking());
+}
+
+struct Failing;
+
+impl Read for Failing {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "source went away",
+        ))
+    }
+}
+
+#[test]
+fn stdin_reader() {
+    let sess = session();
+    let output = RemoteCommand::new("cat")
+        .stdin_reader(io::Cursor::new(b"piped".to_vec()))
+        .output(&sess)
+        .unwrap();
+    assert_eq!(output.stdout, b"piped");
+
+    let err = RemoteCommand::new("cat")
+        .stdin_reader(Failing)
+        .output(&sess)
+        .unwrap_err();
+    assert!(err.message().contains("source went away"), "{}", err);
+    assert!(err.message().contains("BrokenPipe"), "{}", err);
+}
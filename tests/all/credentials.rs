@@ -0,0 +1,135 @@
+use ssh2::{AuthMethod, CredentialKind, CredentialProvider, CredentialRequest};
+use ssh2::{EnvCredentials, FileCredentials, NetrcCredentials, StaticCredentials};
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+
+fn request<'a>(username: &'a str, host: &'a str, kind: CredentialKind) -> CredentialRequest<'a> {
+    CredentialRequest {
+        username,
+        host,
+        method: AuthMethod::Password,
+        kind,
+    }
+}
+
+fn get<P: CredentialProvider>(provider: &mut P, req: &CredentialRequest) -> Option<String> {
+    provider.credential(req).map(|s| s.expose().to_string())
+}
+
+#[test]
+fn env_and_file() {
+    env::set_var("SSH2_TEST_CREDENTIALS_PASSWORD", "from-env");
+    let mut creds = EnvCredentials::new();
+    let _ = creds.password("SSH2_TEST_CREDENTIALS_PASSWORD");
+    let req = request("user", "host", CredentialKind::Password);
+    assert_eq!(get(&mut creds, &req), Some("from-env".to_string()));
+    assert_eq!(
+        get(
+            &mut creds,
+            &request("user", "host", CredentialKind::Passphrase)
+        ),
+        None
+    );
+
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("passphrase");
+    File::create(&path)
+        .unwrap()
+        .write_all(b"from-file\n")
+        .unwrap();
+    let mut creds = FileCredentials::new();
+    let _ = creds
+        .passphrase(&path)
+        .private_key(td.path().join("missing"));
+    let req = request("user", "host", CredentialKind::Passphrase);
+    assert_eq!(get(&mut creds, &req), Some("from-file".to_string()));
+    assert_eq!(
+        get(
+            &mut creds,
+            &request("user", "host", CredentialKind::PrivateKey)
+        ),
+        None
+    );
+}
+
+#[test]
+fn netrc() {
+    let mut creds = NetrcCredentials::parse(
+        "machine example.com login alice password a1\n\
+         macdef init\n\
+         machine bogus password nope\n\
+         \n\
+         machine example.com\n\
+         \tlogin bob password b1\n\
+         default login alice password fallback\n",
+    );
+    let get_password = |creds: &mut NetrcCredentials, user, host| {
+        get(creds, &request(user, host, CredentialKind::Password))
+    };
+    assert_eq!(
+        get_password(&mut creds, "alice", "example.com"),
+        Some("a1".to_string())
+    );
+    assert_eq!(
+        get_password(&mut creds, "bob", "example.com"),
+        Some("b1".to_string())
+    );
+    assert_eq!(
+        get_password(&mut creds, "alice", "other.com"),
+        Some("fallback".to_string())
+    );
+    assert_eq!(get_password(&mut creds, "carol", "other.com"), None);
+    assert_eq!(get_password(&mut creds, "carol", "bogus"), None);
+}
+
+#[test]
+fn static_rejection_and_chaining() {
+    let mut creds = StaticCredentials::new();
+    let _ = creds
+        .insert(CredentialKind::Password, "anyone".into())
+        .insert_for("root", "db", CredentialKind::Password, "root-db".into());
+    let root = request("root", "db", CredentialKind::Password);
+    let other = request("alice", "db", CredentialKind::Password);
+    assert_eq!(get(&mut creds, &root), Some("root-db".to_string()));
+    assert_eq!(get(&mut creds, &other), Some("anyone".to_string()));
+
+    let secret = creds.credential(&root).unwrap();
+    creds.reject(&root, &secret);
+    assert_eq!(get(&mut creds, &root), Some("anyone".to_string()));
+    assert_eq!(get(&mut creds, &other), Some("anyone".to_string()));
+    let secret = creds.credential(&root).unwrap();
+    creds.reject(&root, &secret);
+    assert_eq!(get(&mut creds, &root), None);
+    assert_eq!(get(&mut creds, &other), Some("anyone".to_string()));
+
+    let mut fallback = StaticCredentials::new();
+    let _ = fallback.insert(CredentialKind::Password, "fallback".into());
+    let mut chain: Vec<Box<dyn CredentialProvider>> = vec![Box::new(creds), Box::new(fallback)];
+    assert_eq!(get(&mut chain, &root), Some("fallback".to_string()));
+    assert_eq!(get(&mut chain, &other), Some("anyone".to_string()));
+}
+
+#[test]
+fn chain_rejects_only_the_offered_credential() {
+    env::set_var("SSH2_TEST_CREDENTIALS_CHAIN", "stale");
+    let mut from_env = EnvCredentials::new();
+    let _ = from_env.password("SSH2_TEST_CREDENTIALS_CHAIN");
+
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("password");
+    File::create(&path).unwrap().write_all(b"fresh\n").unwrap();
+    let mut from_file = FileCredentials::new();
+    let _ = from_file.password(&path);
+
+    let mut chain: Vec<Box<dyn CredentialProvider>> = vec![Box::new(from_env), Box::new(from_file)];
+    let req = request("user", "host", CredentialKind::Password);
+    let secret = chain.credential(&req).unwrap();
+    assert_eq!(secret.expose(), "stale");
+    chain.reject(&req, &secret);
+    assert_eq!(get(&mut chain, &req), Some("fresh".to_string()));
+
+    let secret = chain.credential(&req).unwrap();
+    chain.reject(&req, &secret);
+    assert_eq!(get(&mut chain, &req), None);
+}
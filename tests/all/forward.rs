@@ -0,0 +1,170 @@
+use ssh2::{LocalForward, RemoteForward, Session};
+use std::io::prelude::*;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn local_forward_start_and_stop() {
+    let sess = Session::new().unwrap();
+    let mut forward = LocalForward::tcp("127.0.0.1", 1);
+    let _ = forward.listen("127.0.0.1:0").unwrap();
+    assert!(forward.start(&sess).is_err());
+
+    sess.set_blocking(false);
+    let handle = forward.start(&sess).unwrap();
+    assert!(handle.local_addr().unwrap().port() != 0);
+    assert!(handle.stop().unwrap().is_empty());
+    // The listener went to the first service.
+    assert!(forward.start(&sess).is_err());
+
+    // Local errors say what went wrong.
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let err = LocalForward::tcp("127.0.0.1", 1)
+        .listen(taken.local_addr().unwrap())
+        .err()
+        .unwrap();
+    assert!(err.message().contains("AddrInUse"), "{}", err);
+}
+
+/// Accepts one connection and sends back what it reads.
+fn echo_server() -> (u16, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let mut conn = listener.accept().unwrap().0;
+        let mut data = Vec::new();
+        conn.read_to_end(&mut data).unwrap();
+        conn.write_all(&data).unwrap();
+    });
+    (port, server)
+}
+
+#[test]
+fn local_forward() {
+    let (port, server) = echo_server();
+    let sess = ::authed_session();
+    sess.set_blocking(false);
+    let handle = LocalForward::tcp("127.0.0.1", port)
+        .listen("127.0.0.1:0")
+        .unwrap()
+        .start(&sess)
+        .unwrap();
+
+    let data = vec![7; 100_000];
+    let mut conn = TcpStream::connect(handle.local_addr().unwrap()).unwrap();
+    conn.write_all(&data).unwrap();
+    conn.shutdown(Shutdown::Write).unwrap();
+    let mut echoed = Vec::new();
+    conn.read_to_end(&mut echoed).unwrap();
+    assert!(echoed == data);
+    server.join().unwrap();
+
+    let mut reports = handle.reports();
+    while reports.is_empty() {
+        thread::sleep(Duration::from_millis(10));
+        reports = handle.reports();
+    }
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].peer, conn.local_addr().unwrap().to_string());
+    assert_eq!(reports[0].sent, 100_000);
+    assert_eq!(reports[0].received, 100_000);
+    assert!(reports[0].error.is_none());
+    assert!(handle.stop().unwrap().is_empty());
+}
+
+#[test]
+fn remote_forward() {
+    let (port, server) = echo_server();
+    let sess = ::authed_session();
+    assert!(RemoteForward::tcp(("127.0.0.1", port))
+        .unwrap()
+        .start(&sess)
+        .is_err());
+
+    sess.set_blocking(false);
+    let handle = RemoteForward::tcp(("127.0.0.1", port))
+        .unwrap()
+        .listen(Some("127.0.0.1"), 0)
+        .start(&sess)
+        .unwrap();
+    let remote_port = handle.remote_port().unwrap();
+    assert!(remote_port != 0);
+
+    // The server listens on this machine too.
+    let mut conn = TcpStream::connect(("127.0.0.1", remote_port)).unwrap();
+    conn.write_all(b"hello").unwrap();
+    conn.shutdown(Shutdown::Write).unwrap();
+    let mut echoed = Vec::new();
+    conn.read_to_end(&mut echoed).unwrap();
+    assert_eq!(echoed, b"hello");
+    server.join().unwrap();
+
+    let mut reports = handle.reports();
+    while reports.is_empty() {
+        thread::sleep(Duration::from_millis(10));
+        reports = handle.reports();
+    }
+    assert_eq!(reports[0].received, 5);
+    assert_eq!(reports[0].sent, 5);
+    assert!(reports[0].error.is_none());
+    handle.stop().unwrap();
+    assert!(TcpStream::connect(("127.0.0.1", remote_port)).is_err());
+}
+
+#[test]
+fn socks_proxy() {
+    let (port, server) = echo_server();
+    let sess = ::authed_session();
+    sess.set_blocking(false);
+    let handle = LocalForward::dynamic()
+        .listen("127.0.0.1:0")
+        .unwrap()
+        .start(&sess)
+        .unwrap();
+    let proxy = handle.local_addr().unwrap();
+
+    // SOCKS5, with a host name for the server to resolve.
+    let mut conn = TcpStream::connect(proxy).unwrap();
+    conn.write_all(&[5, 1, 0]).unwrap();
+    let mut reply = [0; 2];
+    conn.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [5, 0]);
+    let mut request = vec![5, 1, 0, 3, 9];
+    request.extend_from_slice(b"localhost");
+    request.extend_from_slice(&port.to_be_bytes());
+    conn.write_all(&request).unwrap();
+    let mut reply = [0; 10];
+    conn.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [5, 0]);
+    conn.write_all(b"hello").unwrap();
+    conn.shutdown(Shutdown::Write).unwrap();
+    let mut echoed = Vec::new();
+    conn.read_to_end(&mut echoed).unwrap();
+    assert_eq!(echoed, b"hello");
+    server.join().unwrap();
+
+    // SOCKS4a, to a port nothing listens on any more.
+    let mut conn = TcpStream::connect(proxy).unwrap();
+    let mut request = vec![4, 1];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&[0, 0, 0, 1]);
+    request.extend_from_slice(b"user\0localhost\0");
+    conn.write_all(&request).unwrap();
+    let mut reply = [0; 8];
+    conn.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [0, 91]);
+
+    // SOCKS5 failures say why.
+    let mut conn = TcpStream::connect(proxy).unwrap();
+    let mut request = vec![5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend_from_slice(&port.to_be_bytes());
+    conn.write_all(&request).unwrap();
+    let mut reply = [0; 12];
+    conn.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..4], [5, 0, 5, 4]);
+
+    let reports = handle.stop().unwrap();
+    assert_eq!(reports.len(), 3);
+    assert_eq!(reports.iter().filter(|r| r.error.is_some()).count(), 2);
+}
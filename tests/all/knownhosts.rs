@@ -1,4 +1,6 @@
-use ssh2::{KnownHostFileKind, Session};
+use ssh2::{CheckResult, KnownHostFileKind, KnownHostKeyFormat, KnownHostMarker};
+use ssh2::{KnownHosts, KnownHostsStore, Session};
+use std::fs;
 
 #[test]
 fn smoke() {
@@ -50,3 +52,556 @@ PW3RcT0eOzQgqlJL3RKrTJvdsjE3JEAvGq3lGHSZXy28G3skua2SmVi\
     );
     known_hosts.remove(host).unwrap();
 }
+
+#[test]
+fn hashed() {
+    let sess = Session::new().unwrap();
+    let mut known_hosts = sess.known_hosts().unwrap();
+    known_hosts
+        .add_hashed(
+            "example.com",
+            b"hello world!",
+            "",
+            KnownHostKeyFormat::SshRsa,
+        )
+        .unwrap();
+    let hosts = known_hosts.hosts().unwrap();
+    assert_eq!(hosts.len(), 1);
+    assert!(hosts[0].is_hashed());
+    assert_eq!(hosts[0].name(), None);
+    match known_hosts.check("example.com", b"hello world!") {
+        CheckResult::Match => {}
+        _ => panic!("hashed entry doesn't match"),
+    }
+    match known_hosts.check("example.org", b"hello world!") {
+        CheckResult::NotFound => {}
+        _ => panic!("hashed entry matches another host"),
+    }
+    let line = known_hosts
+        .write_string(&hosts[0], KnownHostFileKind::OpenSSH)
+        .unwrap();
+    assert!(line.starts_with("|1|"), "{}", line);
+    assert!(!line.contains("example.com"), "{}", line);
+}
+
+#[test]
+fn hash_file() {
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("known_hosts");
+    let original = "\
+# a comment
+one.example.com,two.example.com ssh-rsa aGVsbG8gd29ybGQh
+*.example.org ssh-rsa aGVsbG8gd29ybGQh
+";
+    fs::write(&path, original).unwrap();
+    assert_eq!(KnownHosts::hash_file(&path).unwrap(), 2);
+    assert_eq!(
+        fs::read_to_string(td.path().join("known_hosts.old")).unwrap(),
+        original
+    );
+
+    let contents = fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0], "# a comment");
+    assert!(lines[1].starts_with("|1|") && lines[1].ends_with(" ssh-rsa aGVsbG8gd29ybGQh"));
+    assert!(lines[2].starts_with("|1|") && lines[1] != lines[2]);
+    assert_eq!(lines[3], "*.example.org ssh-rsa aGVsbG8gd29ybGQh");
+
+    let sess = Session::new().unwrap();
+    let mut known_hosts = sess.known_hosts().unwrap();
+    known_hosts
+        .read_str(lines[1], KnownHostFileKind::OpenSSH)
+        .unwrap();
+    known_hosts
+        .read_str(lines[2], KnownHostFileKind::OpenSSH)
+        .unwrap();
+    for host in &["one.example.com", "two.example.com"] {
+        match known_hosts.check(host, b"hello world!") {
+            CheckResult::Match => {}
+            _ => panic!("{} doesn't match after hashing", host),
+        }
+    }
+}
+
+#[cfg(feature = "keys")]
+fn base64_decode(data: &str) -> Vec<u8> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = Vec::new();
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in data.trim_end_matches('=').bytes() {
+        acc = acc << 6 | ALPHABET.iter().position(|&b| b == c).unwrap() as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    out
+}
+
+fn ed25519_blob(fill: u8) -> Vec<u8> {
+    let mut blob = vec![0, 0, 0, 11];
+    blob.extend_from_slice(b"ssh-ed25519");
+    blob.extend_from_slice(&[0, 0, 0, 32]);
+    blob.extend_from_slice(&[fill; 32]);
+    blob
+}
+
+#[test]
+fn structured_entries() {
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("known_hosts");
+    fs::write(
+        &path,
+        "# comment\n\
+         [git.example.com]:2222,!bad.example.com,*.example.net ssh-rsa aGVsbG8gd29ybGQh laptop\n\
+         second.example.com ssh-rsa aGVsbG8gd29ybGQh\n\
+         @revoked *,!trusted.example.com ssh-rsa aGVsbG8gd29ybGQh old key\n",
+    )
+    .unwrap();
+
+    let sess = Session::new().unwrap();
+    let mut known_hosts = sess.known_hosts().unwrap();
+    let _ = known_hosts
+        .read_file(&path, KnownHostFileKind::OpenSSH)
+        .unwrap();
+    let hosts = known_hosts.hosts().unwrap();
+    // libssh2 keeps one entry per name of a line, markers keep them together.
+    assert_eq!(hosts.len(), 5);
+
+    // libssh2 adds the names of a line last to first.
+    let first = &hosts[2];
+    assert_eq!(first.line(), Some(2));
+    assert_eq!(first.key_format(), KnownHostKeyFormat::SshRsa);
+    assert_eq!(first.key_bytes(), b"hello world!");
+    assert_eq!(first.comment(), Some("laptop"));
+    assert_eq!(first.marker(), None);
+    assert_eq!(first.patterns().len(), 1);
+    assert_eq!(first.patterns()[0].host(), "git.example.com");
+    assert_eq!(first.patterns()[0].port(), Some(2222));
+    assert!(hosts[1].patterns()[0].is_negated());
+    assert_eq!(hosts[1].patterns()[0].host(), "bad.example.com");
+    assert!(hosts[0].patterns()[0].is_wildcard());
+    assert_eq!(hosts[0].patterns()[0].port(), None);
+    assert_eq!(hosts[0].line(), Some(2));
+
+    assert_eq!(hosts[3].line(), Some(3));
+    assert_eq!(hosts[3].comment(), None);
+    let marker = &hosts[4];
+    assert_eq!(marker.marker(), Some(KnownHostMarker::Revoked));
+    assert_eq!(marker.comment(), Some("old key"));
+    assert_eq!(marker.line(), Some(4));
+    assert_eq!(marker.patterns().len(), 2);
+    assert!(marker.patterns()[1].is_negated());
+
+    // Rotating a key keeps the entry's name, comment and position.
+    let new_key = ed25519_blob(7);
+    known_hosts.update_key(first, &new_key).unwrap();
+    let hosts = known_hosts.hosts().unwrap();
+    assert_eq!(hosts[2].key_bytes(), new_key);
+    assert_eq!(hosts[2].key_format(), KnownHostKeyFormat::Ed25519);
+    assert_eq!(hosts[2].comment(), Some("laptop"));
+    assert_eq!(hosts[2].name(), Some("[git.example.com]:2222"));
+    assert_eq!(hosts[2].line(), Some(2));
+    assert_eq!(hosts[3].name(), Some("second.example.com"));
+    match known_hosts.check_port("git.example.com", 2222, &new_key) {
+        CheckResult::Match => {}
+        _ => panic!("rotated key not found"),
+    }
+
+    known_hosts
+        .replace(&hosts[3], "third.example.com", &ed25519_blob(8), "")
+        .unwrap();
+    known_hosts
+        .replace(&hosts[4], "*.example.org", &ed25519_blob(9), "rotated")
+        .unwrap();
+    let hosts = known_hosts.hosts().unwrap();
+    assert_eq!(hosts.len(), 5);
+    assert_eq!(hosts[2].key_bytes(), new_key);
+    assert_eq!(hosts[3].name(), Some("third.example.com"));
+    assert_eq!(hosts[3].line(), Some(3));
+    assert_eq!(hosts[4].marker(), Some(KnownHostMarker::Revoked));
+    assert_eq!(hosts[4].name(), Some("*.example.org"));
+    assert_eq!(hosts[4].comment(), Some("rotated"));
+    assert!(known_hosts.update_key(&hosts[3], b"not a key").is_err());
+
+    // Entries added after others were removed don't inherit their origin,
+    // even when libssh2 reuses the memory of a removed entry.
+    known_hosts.remove(&hosts[3]).unwrap();
+    known_hosts.remove(&hosts[0]).unwrap();
+    known_hosts
+        .add(
+            "fourth.example.com",
+            b"hello world!",
+            "",
+            KnownHostKeyFormat::SshRsa,
+        )
+        .unwrap();
+    known_hosts
+        .read_str(
+            "fifth.example.com ssh-rsa aGVsbG8gd29ybGQh",
+            KnownHostFileKind::OpenSSH,
+        )
+        .unwrap();
+    let hosts = known_hosts.hosts().unwrap();
+    assert_eq!(hosts.len(), 5);
+    assert_eq!(hosts[0].line(), Some(2));
+    assert_eq!(hosts[1].name(), Some("[git.example.com]:2222"));
+    assert_eq!(hosts[1].line(), Some(2));
+    assert_eq!(hosts[2].name(), Some("fourth.example.com"));
+    assert_eq!(hosts[2].line(), None);
+    assert_eq!(hosts[3].name(), Some("fifth.example.com"));
+    assert_eq!(hosts[3].line(), None);
+}
+
+#[test]
+fn store() {
+    let td = tempfile::tempdir().unwrap();
+    let system = td.path().join("ssh_known_hosts");
+    let user = td.path().join("ssh/known_hosts");
+    fs::write(
+        &system,
+        "system.example.com ssh-rsa aGVsbG8gd29ybGQh\n\
+         @revoked * ssh-rsa cmV2b2tlZA==\n",
+    )
+    .unwrap();
+
+    let sess = Session::new().unwrap();
+    let mut store = KnownHostsStore::new(&sess).unwrap();
+    let _ = store
+        .user_file(&user)
+        .unwrap()
+        .system_file(&system)
+        .unwrap();
+    assert_eq!(store.user_path(), Some(&*user));
+    assert_eq!(store.hosts().unwrap().len(), 2);
+    match store.check("system.example.com", b"hello world!") {
+        CheckResult::Match => {}
+        _ => panic!("system entry not found"),
+    }
+    match store.check("user.example.com", b"revoked") {
+        CheckResult::Revoked => {}
+        _ => panic!("system marker ignored"),
+    }
+
+    // Entries of system files can't be changed.
+    let system_host = store.hosts().unwrap().remove(0);
+    assert_eq!(system_host.source(), Some(&*system));
+    assert!(store.is_read_only(&system_host));
+    assert!(store.remove(&system_host).is_err());
+
+    // New entries go to the user file, which is created.
+    store
+        .add(
+            "user.example.com",
+            &ed25519_blob(1),
+            "",
+            KnownHostKeyFormat::Ed25519,
+        )
+        .unwrap();
+    let written = fs::read_to_string(&user).unwrap();
+    assert!(written.starts_with("user.example.com ssh-ed25519 "));
+    assert!(!fs::read_to_string(&system).unwrap().contains("user"));
+
+    // Changes made by another process in the meantime are kept.
+    let mut other = KnownHostsStore::new(&sess).unwrap();
+    let _ = other.user_file(&user).unwrap();
+    other
+        .add(
+            "other.example.com",
+            &ed25519_blob(2),
+            "",
+            KnownHostKeyFormat::Ed25519,
+        )
+        .unwrap();
+    let hosts = store.hosts().unwrap();
+    let user_host = hosts
+        .iter()
+        .find(|h| h.name() == Some("user.example.com"))
+        .unwrap();
+    assert_eq!(user_host.source(), Some(&*user));
+    store.update_key(user_host, &ed25519_blob(3)).unwrap();
+    let written = fs::read_to_string(&user).unwrap();
+    assert_eq!(written.lines().count(), 2);
+    assert!(written.contains("other.example.com"));
+    match store.check("user.example.com", &ed25519_blob(3)) {
+        CheckResult::Match => {}
+        _ => panic!("updated key not found"),
+    }
+    assert!(fs::read_dir(td.path().join("ssh")).unwrap().all(|e| !e
+        .unwrap()
+        .file_name()
+        .to_str()
+        .unwrap()
+        .ends_with(".tmp")));
+}
+
+#[test]
+fn store_keeps_lines() {
+    let td = tempfile::tempdir().unwrap();
+    let user = td.path().join("known_hosts");
+    let original = "# my hosts\n\
+                    @cert-authority *.corp ssh-ed25519 aGVsbG8gd29ybGQh\n\
+                    multi.example.com,alias.example.com ssh-rsa aGVsbG8gd29ybGQh\n\
+                    \n\
+                    dup.example.com ssh-rsa aGVsbG8gd29ybGQh\n\
+                    dup.example.com ssh-rsa aGVsbG8gd29ybGQh\n";
+    fs::write(&user, original).unwrap();
+
+    let sess = Session::new().unwrap();
+    let mut store = KnownHostsStore::new(&sess).unwrap();
+    let _ = store.user_file(&user).unwrap();
+
+    // New entries are appended.
+    store
+        .add(
+            "new.example.com",
+            b"hello world!",
+            "",
+            KnownHostKeyFormat::SshRsa,
+        )
+        .unwrap();
+    let written = fs::read_to_string(&user).unwrap();
+    assert_eq!(
+        written,
+        format!("{}new.example.com ssh-rsa aGVsbG8gd29ybGQh\n", original)
+    );
+
+    // Changing one name of a line only rewrites that line.
+    let hosts = store.hosts().unwrap();
+    let alias = hosts
+        .iter()
+        .find(|h| h.name() == Some("alias.example.com"))
+        .unwrap();
+    store.update_key(alias, &ed25519_blob(5)).unwrap();
+    let written = fs::read_to_string(&user).unwrap();
+    let lines = written.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 8);
+    assert_eq!(lines[0], "# my hosts");
+    assert!(lines[1].starts_with("@cert-authority *.corp "));
+    assert_eq!(lines[2], "multi.example.com ssh-rsa aGVsbG8gd29ybGQh");
+    assert!(lines[3].starts_with("alias.example.com ssh-ed25519 "));
+    assert_eq!(lines[4], "");
+    assert_eq!(lines[7], "new.example.com ssh-rsa aGVsbG8gd29ybGQh");
+
+    // Removing one of two identical entries removes one line.
+    let hosts = store.hosts().unwrap();
+    let dup = hosts
+        .iter()
+        .find(|h| h.name() == Some("dup.example.com"))
+        .unwrap();
+    assert_eq!(dup.line(), Some(6));
+    store.remove(dup).unwrap();
+    let written = fs::read_to_string(&user).unwrap();
+    assert_eq!(written.matches("dup.example.com").count(), 1);
+    assert_eq!(written.lines().count(), 7);
+
+    let hosts = store.hosts().unwrap();
+    let marker = hosts.iter().find(|h| h.marker().is_some()).unwrap();
+    store.remove(marker).unwrap();
+    let written = fs::read_to_string(&user).unwrap();
+    assert!(written.starts_with("# my hosts\nmulti.example.com "));
+}
+
+#[test]
+fn store_keeps_crlf() {
+    let td = tempfile::tempdir().unwrap();
+    let user = td.path().join("known_hosts");
+    fs::write(
+        &user,
+        "# my hosts\r\n\
+         multi.example.com,alias.example.com ssh-rsa aGVsbG8gd29ybGQh\r\n",
+    )
+    .unwrap();
+
+    let sess = Session::new().unwrap();
+    let mut store = KnownHostsStore::new(&sess).unwrap();
+    let _ = store.user_file(&user).unwrap();
+    let hosts = store.hosts().unwrap();
+    let alias = hosts
+        .iter()
+        .find(|h| h.name() == Some("alias.example.com"))
+        .unwrap();
+    store.update_key(alias, &ed25519_blob(5)).unwrap();
+    store
+        .add(
+            "new.example.com",
+            b"hello world!",
+            "",
+            KnownHostKeyFormat::SshRsa,
+        )
+        .unwrap();
+
+    let written = fs::read_to_string(&user).unwrap();
+    assert_eq!(written.matches('\n').count(), 4, "{:?}", written);
+    assert_eq!(written.matches("\r\n").count(), 4, "{:?}", written);
+    assert!(written.starts_with("# my hosts\r\nmulti.example.com ssh-rsa "));
+    // The lock file is gone again.
+    assert_eq!(fs::read_dir(td.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn host_key_algorithms() {
+    let sess = Session::new().unwrap();
+    let mut known_hosts = sess.known_hosts().unwrap();
+    for line in &[
+        "example.com ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTY=",
+        "[example.com]:2222 ssh-ed25519 AAAAC3NzaC1lZDI1NTE5",
+        "*.example.com ssh-rsa AAAAB3NzaC1yc2E=",
+        "@cert-authority *.corp.example ssh-ed25519 AAAAC3NzaC1lZDI1NTE5",
+    ] {
+        known_hosts
+            .read_str(line, KnownHostFileKind::OpenSSH)
+            .unwrap();
+    }
+    known_hosts
+        .add_hashed(
+            "hashed.example.org",
+            &ed25519_blob(1),
+            "",
+            KnownHostKeyFormat::Ed25519,
+        )
+        .unwrap();
+
+    let algs = |host, port| known_hosts.host_key_algorithms(host, port).unwrap();
+    assert_eq!(algs("example.com", 22), vec!["ecdsa-sha2-nistp256"]);
+    assert_eq!(algs("example.com", 2222), vec!["ssh-ed25519"]);
+    assert_eq!(
+        algs("www.example.com", 22),
+        vec!["rsa-sha2-512", "rsa-sha2-256", "ssh-rsa"]
+    );
+    assert_eq!(algs("hashed.example.org", 22), vec!["ssh-ed25519"]);
+    assert_eq!(
+        algs("host.corp.example", 22),
+        vec!["ssh-ed25519-cert-v01@openssh.com"]
+    );
+    assert!(algs("unknown.example.net", 22).is_empty());
+
+    sess.prefer_known_host_keys(&known_hosts, "example.com", 22)
+        .unwrap();
+    sess.prefer_host_key_algorithms(&["not-an-algorithm"])
+        .unwrap();
+}
+
+#[test]
+fn revoked() {
+    let sess = Session::new().unwrap();
+    let mut known_hosts = sess.known_hosts().unwrap();
+    known_hosts
+        .read_str(
+            "example.com ssh-rsa aGVsbG8gd29ybGQh",
+            KnownHostFileKind::OpenSSH,
+        )
+        .unwrap();
+    known_hosts
+        .read_str(
+            "@revoked *.com,!example.org ssh-rsa aGVsbG8gd29ybGQh",
+            KnownHostFileKind::OpenSSH,
+        )
+        .unwrap();
+    assert!(known_hosts
+        .read_str(
+            "@bogus * ssh-rsa aGVsbG8gd29ybGQh",
+            KnownHostFileKind::OpenSSH
+        )
+        .is_err());
+    assert_eq!(known_hosts.hosts().unwrap().len(), 2);
+
+    match known_hosts.check("example.com", b"hello world!") {
+        CheckResult::Revoked => {}
+        _ => panic!("revoked key accepted"),
+    }
+    match known_hosts.check("example.org", b"hello world!") {
+        CheckResult::NotFound => {}
+        _ => panic!("negated pattern ignored"),
+    }
+
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("known_hosts");
+    known_hosts
+        .write_file(&path, KnownHostFileKind::OpenSSH)
+        .unwrap();
+    let mut reread = sess.known_hosts().unwrap();
+    assert_eq!(
+        reread.read_file(&path, KnownHostFileKind::OpenSSH).unwrap(),
+        2
+    );
+    match reread.check("example.com", b"hello world!") {
+        CheckResult::Revoked => {}
+        _ => panic!("revoked marker not written back"),
+    }
+}
+
+#[cfg(feature = "keys")]
+#[test]
+fn cert_authority() {
+    use std::process::Command;
+
+    let td = tempfile::tempdir().unwrap();
+    let keygen = |args: &[&str]| {
+        let status = Command::new("ssh-keygen")
+            .current_dir(td.path())
+            .args(["-q"])
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+    keygen(&["-t", "ed25519", "-N", "", "-f", "ca"]);
+    keygen(&["-t", "ed25519", "-N", "", "-f", "host"]);
+    keygen(&[
+        "-s",
+        "ca",
+        "-I",
+        "host",
+        "-h",
+        "-n",
+        "host.corp.example",
+        "-V",
+        "-5m:+1h",
+        "host.pub",
+    ]);
+    let read = |name: &str| fs::read_to_string(td.path().join(name)).unwrap();
+    let ca = read("ca.pub");
+    let plain = read("host.pub");
+    let cert = base64_decode(read("host-cert.pub").split(' ').nth(1).unwrap());
+
+    let sess = Session::new().unwrap();
+    let mut known_hosts = sess.known_hosts().unwrap();
+    known_hosts
+        .read_str(
+            &format!("@cert-authority *.corp.example {}", ca.trim()),
+            KnownHostFileKind::OpenSSH,
+        )
+        .unwrap();
+    let check = |known_hosts: &KnownHosts, host| match known_hosts.check(host, &cert) {
+        CheckResult::Match => "match",
+        CheckResult::Mismatch => "mismatch",
+        CheckResult::NotFound => "notfound",
+        CheckResult::Revoked => "revoked",
+        _ => "failure",
+    };
+    assert_eq!(check(&known_hosts, "host.corp.example"), "match");
+    assert_eq!(check(&known_hosts, "HOST.corp.example"), "match");
+    // Signed by a trusted authority, but not for this host.
+    assert_eq!(check(&known_hosts, "other.corp.example"), "mismatch");
+    // No authority, so the certified key is checked as a plain key.
+    assert_eq!(check(&known_hosts, "host.example.org"), "notfound");
+    known_hosts
+        .read_str(
+            &format!("host.example.org {}", plain.trim()),
+            KnownHostFileKind::OpenSSH,
+        )
+        .unwrap();
+    assert_eq!(check(&known_hosts, "host.example.org"), "match");
+
+    known_hosts
+        .read_str(
+            &format!("@revoked * {}", plain.trim()),
+            KnownHostFileKind::OpenSSH,
+        )
+        .unwrap();
+    assert_eq!(check(&known_hosts, "host.corp.example"), "revoked");
+}
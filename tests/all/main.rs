@@ -1,5 +1,7 @@
 #![deny(warnings)]
 
+#[cfg(unix)]
+extern crate libc;
 extern crate ssh2;
 extern crate tempfile;
 
@@ -8,9 +10,19 @@ use std::net::TcpStream;
 
 mod agent;
 mod channel;
+mod command;
+mod credentials;
+mod forward;
+#[cfg(feature = "keys")]
+mod keys;
 mod knownhosts;
+#[cfg(feature = "prompters")]
+mod prompter;
 mod session;
 mod sftp;
+#[cfg(unix)]
+mod shell;
+mod x11;
 
 pub fn test_addr() -> String {
     let port = env::var("RUST_SSH2_FIXTURE_PORT")
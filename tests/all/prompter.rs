@@ -0,0 +1,90 @@
+use ssh2::{KeyboardInteractivePrompt, Prompt, RegexPrompter, TotpResponder};
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+
+fn prompts(texts: &[&'static str]) -> Vec<Prompt<'static>> {
+    texts
+        .iter()
+        .map(|text| Prompt {
+            text: Cow::Borrowed(*text),
+            echo: false,
+        })
+        .collect()
+}
+
+#[test]
+fn regex_prompter() {
+    let mut prompter = RegexPrompter::new();
+    prompter
+        .answer("(?i)^password", "hunter2")
+        .unwrap()
+        .answer("(?i)pin", "1234")
+        .unwrap();
+    assert!(prompter.answer("(", "").is_err());
+
+    let answers = prompter.prompt(
+        "user",
+        "",
+        &prompts(&["Password: ", "Enter PIN: ", "Something else: "]),
+    );
+    assert_eq!(answers, vec!["hunter2", "1234", ""]);
+}
+
+#[test]
+fn totp_rfc6238_vectors() {
+    // The SHA-1 test vectors from RFC 6238 appendix B.
+    let mut totp = TotpResponder::new(b"12345678901234567890");
+    totp.digits(8).unwrap();
+    assert!(totp.digits(0).is_err());
+    assert!(totp.digits(20).is_err());
+    assert_eq!(totp.code_at(59), "94287082");
+    assert_eq!(totp.code_at(1111111109), "07081804");
+    assert_eq!(totp.code_at(1111111111), "14050471");
+    assert_eq!(totp.code_at(1234567890), "89005924");
+    assert_eq!(totp.code_at(2000000000), "69279037");
+    assert_eq!(totp.code_at(20000000000), "65353130");
+
+    let base32 = TotpResponder::from_base32("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap();
+    assert_eq!(base32.code_at(59), "287082");
+    assert!(TotpResponder::from_base32("not base32!").is_err());
+}
+
+/// Answers each prompt with its own text, and records what it was asked.
+struct Recorder(Arc<Mutex<Vec<String>>>);
+
+impl KeyboardInteractivePrompt for Recorder {
+    fn prompt<'a>(&mut self, _: &str, _: &str, prompts: &[Prompt<'a>]) -> Vec<String> {
+        let texts: Vec<String> = prompts.iter().map(|p| p.text.to_string()).collect();
+        self.0.lock().unwrap().extend(texts.iter().cloned());
+        texts
+    }
+}
+
+#[test]
+fn totp_prompt() {
+    let mut password = RegexPrompter::new();
+    password.answer("(?i)password", "hunter2").unwrap();
+
+    let mut totp = TotpResponder::new(b"12345678901234567890");
+    totp.clock(|| 1111111109).fallback(password);
+    assert_eq!(totp.code(), "081804");
+
+    let answers = totp.prompt("user", "", &prompts(&["Password: ", "Verification code: "]));
+    assert_eq!(answers, vec!["hunter2", "081804"]);
+
+    totp.prompt_pattern("^OTP").unwrap();
+    let answers = totp.prompt("user", "", &prompts(&["OTP: ", "Verification code: "]));
+    assert_eq!(answers, vec!["081804", ""]);
+
+    // Prompts for the code never reach the fallback.
+    let mut totp = TotpResponder::new(b"12345678901234567890");
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    totp.clock(|| 1111111109).fallback(Recorder(asked.clone()));
+    let answers = totp.prompt(
+        "user",
+        "",
+        &prompts(&["Verification code: ", "Password: ", "Verification code: "]),
+    );
+    assert_eq!(answers, vec!["081804", "Password: ", "081804"]);
+    assert_eq!(*asked.lock().unwrap(), vec!["Password: "]);
+}
@@ -4,7 +4,10 @@ use std::io::{self, prelude::*};
 use std::path::Path;
 use tempfile::TempDir;
 
-use ssh2::{BlockDirections, HashType, KeyboardInteractivePrompt, MethodType, Prompt, Session};
+use ssh2::{
+    AuthMethod, BlockDirections, ErrorCode, HashType, KeyboardInteractivePrompt, MethodType,
+    Prompt, Secret, Session,
+};
 
 #[test]
 fn session_is_send() {
@@ -35,6 +38,42 @@ fn smoke() {
     sess.channel_session().err().unwrap();
 }
 
+#[test]
+fn secret_is_redacted() {
+    let password = Secret::from("hunter2");
+    assert_eq!(&*password, "hunter2");
+    assert_eq!(format!("{:?}", password), "Secret(..)");
+    assert!(Session::new()
+        .unwrap()
+        .userauth_password("foo", &password)
+        .is_err());
+}
+
+#[test]
+fn hostbased_memory_reads_keys() {
+    let sess = Session::new().unwrap();
+    let err = sess
+        .userauth_hostbased_memory("foo", "x", "", None, "localhost", None)
+        .unwrap_err();
+    if cfg!(target_os = "linux") && Path::new("/proc/self/fd").is_dir() {
+        // LIBSSH2_ERROR_FILE for the key's contents, so the file was read
+        assert_eq!(err.code(), ErrorCode::Session(-16));
+        assert!(err.message().contains("Invalid data"), "{}", err);
+    } else {
+        // LIBSSH2_ERROR_METHOD_NOT_SUPPORTED
+        assert_eq!(err.code(), ErrorCode::Session(-33));
+    }
+}
+
+#[test]
+fn new_zeroizing() {
+    let sess = Session::new_zeroizing().unwrap();
+    sess.set_banner("foo").unwrap();
+    sess.supported_algs(MethodType::Kex).unwrap();
+    let password = Secret::from("hunter2");
+    assert!(sess.userauth_password("foo", &password).is_err());
+}
+
 #[test]
 fn smoke_handshake() {
     let user = env::var("USER").unwrap();
@@ -44,7 +83,9 @@ fn smoke_handshake() {
     sess.handshake().unwrap();
     sess.host_key().unwrap();
     let methods = sess.auth_methods(&user).unwrap();
-    assert!(methods.contains("publickey"), "{}", methods);
+    assert!(methods.contains(&AuthMethod::PublicKey), "{}", methods);
+    assert!(!methods.authenticated());
+    assert!(!sess.userauth_none(&user).unwrap());
     assert!(!sess.authenticated());
 
     let mut agent = sess.agent().unwrap();
@@ -81,7 +122,7 @@ fn keyboard_interactive() {
     sess.host_key().unwrap();
     let methods = sess.auth_methods(&user).unwrap();
     assert!(
-        methods.contains("keyboard-interactive"),
+        methods.contains(&AuthMethod::KeyboardInteractive),
         "test server ({}) must support `ChallengeResponseAuthentication yes`, not just {}",
         ::test_addr(),
         methods
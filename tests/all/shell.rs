@@ -0,0 +1,142 @@
+use libc;
+use ssh2::{ErrorCode, ExitStatus, InteractiveShell, Session};
+use std::fs::File;
+use std::io::prelude::*;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr::null_mut;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// Held by each test running a shell, as they share the `SIGWINCH` handler.
+static SHELL: Mutex<()> = Mutex::new(());
+
+fn one_at_a_time() -> MutexGuard<'static, ()> {
+    SHELL.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Opens a pseudo terminal, returning the master and slave ends.
+fn pty_pair(cols: u16, rows: u16) -> (File, File) {
+    unsafe {
+        let (mut master, mut slave) = (0, 0);
+        let mut size = mem::zeroed::<libc::winsize>();
+        size.ws_col = cols;
+        size.ws_row = rows;
+        assert_eq!(
+            libc::openpty(&mut master, &mut slave, null_mut(), null_mut(), &size),
+            0
+        );
+        (File::from_raw_fd(master), File::from_raw_fd(slave))
+    }
+}
+
+fn canonical(tty: &File) -> bool {
+    unsafe {
+        let mut termios = mem::zeroed::<libc::termios>();
+        assert_eq!(libc::tcgetattr(tty.as_raw_fd(), &mut termios), 0);
+        termios.c_lflag & libc::ICANON != 0
+    }
+}
+
+/// Waits until the shell has put the terminal in raw mode, so that keys
+/// aren't echoed or translated locally, then closes this handle to it.
+fn wait_raw(tty: File) {
+    while canonical(&tty) {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Reads what the shell wrote until it contains `pattern`.
+fn read_until(master: &mut File, pattern: &str) -> String {
+    let mut output = String::new();
+    let mut buf = [0; 1024];
+    while !output.contains(pattern) {
+        let n = master.read(&mut buf).unwrap();
+        output.push_str(&String::from_utf8_lossy(&buf[..n]));
+    }
+    output
+}
+
+/// Reads what the shell wrote until the slave end is closed.
+fn read_all(master: &mut File) -> String {
+    let mut output = Vec::new();
+    let mut buf = [0; 1024];
+    // Reading the master fails once the slave is closed.
+    while let Ok(n) = master.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        output.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+#[test]
+fn resize_and_exit_status() {
+    let _shell = one_at_a_time();
+    let (mut master, slave) = pty_pair(100, 30);
+    let tty = slave.try_clone().unwrap();
+    let sess = ::authed_session();
+    sess.set_blocking(false);
+    let shell = thread::spawn(move || {
+        let status = InteractiveShell::new()
+            .term("dumb")
+            .run_on(&sess, &slave, &slave);
+        assert!(canonical(&slave));
+        assert!(!sess.is_blocking());
+        status
+    });
+
+    wait_raw(tty);
+    master.write_all(b"stty size\r").unwrap();
+    let mut output = read_until(&mut master, "30 100");
+    unsafe {
+        let mut size = mem::zeroed::<libc::winsize>();
+        size.ws_col = 120;
+        size.ws_row = 40;
+        assert_eq!(libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &size), 0);
+        assert_eq!(libc::raise(libc::SIGWINCH), 0);
+    }
+    master.write_all(b"stty size; exit 3\r").unwrap();
+
+    output.push_str(&read_all(&mut master));
+    assert_eq!(shell.join().unwrap().unwrap(), ExitStatus::Code(3));
+    assert!(output.contains("40 120"), "{}", output);
+}
+
+#[test]
+fn escapes() {
+    let _shell = one_at_a_time();
+    let (mut master, slave) = pty_pair(80, 24);
+    let tty = slave.try_clone().unwrap();
+    let sess = ::authed_session();
+    sess.set_blocking(false);
+    let shell = thread::spawn(move || {
+        InteractiveShell::new()
+            .term("dumb")
+            .run_on(&sess, &slave, &slave)
+    });
+
+    // A tilde in the middle of a line is just a tilde; at the start of one
+    // it starts an escape. The tildes are replaced on the way out, so that
+    // the echoed command line doesn't match.
+    wait_raw(tty);
+    master.write_all(b"echo a~.b ~~ | tr '~' T\r").unwrap();
+    let _ = read_until(&mut master, "aT.b TT");
+    master.write_all(b"~?~.").unwrap();
+    let output = read_all(&mut master);
+    assert_eq!(shell.join().unwrap().unwrap(), ExitStatus::Unknown);
+    assert!(output.contains("~.   - terminate the shell"), "{}", output);
+}
+
+#[test]
+fn needs_nonblocking_session() {
+    let (_master, slave) = pty_pair(80, 24);
+    let sess = Session::new().unwrap();
+    let err = InteractiveShell::new()
+        .run_on(&sess, &slave, &slave)
+        .unwrap_err();
+    assert_eq!(err.code(), ErrorCode::Session(-39)); // LIBSSH2_ERROR_BAD_USE
+    assert!(canonical(&slave));
+}
@@ -0,0 +1,120 @@
+use ssh2::{X11Cookie, X11Forwarding};
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpListener;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn cookies() {
+    let cookie = X11Cookie::generate().unwrap();
+    assert_eq!(cookie.protocol(), "MIT-MAGIC-COOKIE-1");
+    assert_eq!(cookie.data().len(), 16);
+    assert_eq!(cookie.hex().len(), 32);
+    assert_eq!(
+        X11Cookie::from_hex(cookie.protocol(), &cookie.hex()),
+        Some(cookie.clone())
+    );
+    assert!(X11Cookie::generate().unwrap() != cookie);
+    assert_eq!(
+        X11Cookie::from_hex("XDM-AUTHORIZATION-1", "00ff7A")
+            .unwrap()
+            .data(),
+        &[0, 0xff, 0x7a]
+    );
+    assert_eq!(X11Cookie::from_hex("MIT-MAGIC-COOKIE-1", "abc"), None);
+    assert_eq!(X11Cookie::from_hex("MIT-MAGIC-COOKIE-1", "zz"), None);
+
+    let x11 = X11Forwarding::new("localhost:10.0").unwrap();
+    assert_eq!(x11.display(), "localhost:10.0");
+    assert!(x11.fake_cookie() != X11Forwarding::new(":0").unwrap().fake_cookie());
+    assert!(X11Forwarding::new("localhost").is_err());
+    assert!(X11Forwarding::new(":x").is_err());
+}
+
+#[test]
+fn forwarding() {
+    // sshd refuses X11 forwarding when it can't run xauth to install the
+    // cookie.
+    if Command::new("xauth").arg("version").output().is_err() {
+        eprintln!("skipping X11 forwarding: xauth is not installed");
+        return;
+    }
+
+    // A stand-in X server, on a display of its own.
+    let (listener, display) = (50..100)
+        .filter_map(|n| {
+            TcpListener::bind(("127.0.0.1", 6000 + n))
+                .ok()
+                .map(|l| (l, n))
+        })
+        .next()
+        .unwrap();
+    let real = X11Cookie::new("XDM-AUTHORIZATION-1", &[7; 24]);
+    let server_cookie = real.clone();
+    let server = thread::spawn(move || {
+        let mut conn = listener.accept().unwrap().0;
+        let mut header = [0; 12];
+        conn.read_exact(&mut header).unwrap();
+        assert_eq!(header[0], b'l');
+        assert_eq!(header[6] as usize, server_cookie.protocol().len());
+        assert_eq!(header[8] as usize, server_cookie.data().len());
+        let mut auth = vec![0; 20 + 24];
+        conn.read_exact(&mut auth).unwrap();
+        assert_eq!(&auth[..19], server_cookie.protocol().as_bytes());
+        assert_eq!(&auth[20..], server_cookie.data());
+        let mut ping = [0; 4];
+        conn.read_exact(&mut ping).unwrap();
+        assert_eq!(&ping, b"ping");
+        conn.write_all(b"pong").unwrap();
+    });
+
+    let mut x11 = X11Forwarding::new(&format!("127.0.0.1:{}", display)).unwrap();
+    let _ = x11.real_cookie(real);
+
+    // An X11 client connection setup with the fake cookie, then some data.
+    let fake = x11.fake_cookie();
+    let mut setup = vec![b'l', 0, 11, 0, 0, 0, 18, 0, 16, 0, 0, 0];
+    setup.extend_from_slice(fake.protocol().as_bytes());
+    setup.extend_from_slice(&[0, 0]);
+    setup.extend_from_slice(fake.data());
+    setup.extend_from_slice(b"ping");
+    let escaped: String = setup.iter().map(|b| format!("\\x{:02x}", b)).collect();
+    let command = format!(
+        "bash -c 'n=${{DISPLAY#*:}}; exec 3<>/dev/tcp/127.0.0.1/$((6000 + ${{n%.*}})); \
+         printf \"{}\" >&3; head -c 4 <&3'",
+        escaped
+    );
+
+    let sess = ::authed_session();
+    let mut channel = sess.channel_session().unwrap();
+    x11.request(&mut channel, true).unwrap();
+    channel.exec(&command).unwrap();
+
+    sess.set_blocking(false);
+    let mut relays = Vec::new();
+    let mut output = Vec::new();
+    let mut buf = [0; 64];
+    loop {
+        match channel.read(&mut buf) {
+            Ok(0) if channel.eof() => break,
+            Ok(n) => output.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            Err(e) => panic!("{}", e),
+        }
+        while let Some(conn) = sess.accept_x11() {
+            assert_eq!(conn.originator().0, "127.0.0.1");
+            let (x11, sess) = (x11.clone(), sess.clone());
+            relays.push(thread::spawn(move || x11.relay(&sess, conn)));
+        }
+    }
+    assert_eq!(output, b"pong");
+    server.join().unwrap();
+    assert_eq!(relays.len(), 1);
+    for relay in relays {
+        relay.join().unwrap().unwrap();
+    }
+}
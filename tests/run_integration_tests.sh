@@ -61,4 +61,6 @@ sleep 2
 
 # Run the tests against it
 cargo test --all -- --nocapture
+cargo test --features keys -- --nocapture
+cargo test --features prompters -- --nocapture
 cargo test --features vendored-openssl -- --nocapture